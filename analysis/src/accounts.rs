@@ -1,13 +1,10 @@
+use std::collections::HashSet;
 use std::sync::LazyLock;
 
-use alloy_primitives::{Address, B256, U256};
+use alloy_primitives::{Address, B256, KECCAK256_EMPTY, U256};
 use anyhow::Result;
+use eth_stateless::{iterators::code::CodeItems, StateSource};
 use indicatif::{ProgressBar, ProgressStyle};
-use reth_db::mdbx::tx::Tx;
-use reth_db::mdbx::RO;
-use reth_db::{Bytecodes, PlainAccountState, PlainStorageState};
-use reth_db_api::cursor::DbCursorRO;
-use reth_db_api::transaction::DbTx;
 use serde::{Deserialize, Serialize};
 use std::cmp::min;
 
@@ -20,6 +17,8 @@ static PROGRESS_STYLE: LazyLock<ProgressStyle> = LazyLock::new(|| {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AccountStemStats {
     pub address: Address,
+    pub contract: bool,
+    /// Length of the bytecode, if it's read, i.e: zero if the code is skipped.
     pub bytecode_len: usize,
     pub account_stem: u16,
     pub ss_stems: Vec<u16>,
@@ -27,8 +26,14 @@ pub struct AccountStemStats {
     pub num_storage_slots: usize,
 }
 
-pub fn account_stats(tx: &Tx<RO>, group_size: u16) -> Result<Vec<AccountStemStats>> {
-    let bar = ProgressBar::new(tx.entries::<PlainAccountState>()? as u64)
+/// Returns the stem stats of the accounts of `source`. The bytecode of the contracts is only read for the
+/// `code` items, the code of the other contracts being left out of the stems.
+pub fn account_stats(
+    source: &impl StateSource,
+    group_size: u16,
+    code: CodeItems,
+) -> Result<Vec<AccountStemStats>> {
+    let bar = ProgressBar::new(source.accounts_count()?)
         .with_style(PROGRESS_STYLE.clone())
         .with_message("Analyzing...");
 
@@ -37,61 +42,55 @@ pub fn account_stats(tx: &Tx<RO>, group_size: u16) -> Result<Vec<AccountStemStat
     let ss_header_count = to_b256(code_offset - header_storage_offset);
     let group_size_bits = group_size.trailing_zeros();
 
+    // Code hashes already counted, if deduplicating.
+    let mut seen = HashSet::new();
     let mut accounts = Vec::<AccountStemStats>::new();
-    let mut cur = tx.cursor_read::<PlainAccountState>()?;
-    loop {
-        match cur.next() {
-            Ok(Some((address, _))) => {
-                bar.set_message(address.to_string().to_lowercase());
-                let account = tx.get::<PlainAccountState>(address)?.unwrap();
-                let bytecode = tx
-                    .get::<Bytecodes>(account.get_bytecode_hash())?
-                    .unwrap_or_default();
-                let code_chunks_count = ((bytecode.len() + 30) / 31) as u16;
-                let code_chunks_in_header = min(group_size - code_offset, code_chunks_count);
+    for entry in source.accounts()? {
+        let (address, account) = entry?;
+        bar.set_message(address.to_string().to_lowercase());
+        let code_hash = account.code_hash.filter(|hash| *hash != KECCAK256_EMPTY);
+        let included = match code {
+            CodeItems::Skip => None,
+            CodeItems::All => code_hash,
+            CodeItems::Deduplicated => code_hash.filter(|hash| seen.insert(*hash)),
+        };
+        let bytecode = match included {
+            Some(code_hash) => source.bytecode(code_hash)?.unwrap_or_default(),
+            None => Default::default(),
+        };
+        let code_chunks_count = ((bytecode.len() + 30) / 31) as u16;
+        let code_chunks_in_header = min(group_size - code_offset, code_chunks_count);
 
-                let mut stats = AccountStemStats {
-                    address,
-                    bytecode_len: bytecode.len(),
-                    account_stem: 1 + 1 + code_chunks_in_header, // BASIC_DATA + CODE_HASH + header_code_chunks
-                    ss_stems: vec![],
-                    code_stems: (code_chunks_count - code_chunks_in_header).div_ceil(group_size),
-                    num_storage_slots: 0,
-                };
+        let mut stats = AccountStemStats {
+            address,
+            contract: code_hash.is_some(),
+            bytecode_len: bytecode.len(),
+            account_stem: 1 + 1 + code_chunks_in_header, // BASIC_DATA + CODE_HASH + header_code_chunks
+            ss_stems: vec![],
+            code_stems: (code_chunks_count - code_chunks_in_header).div_ceil(group_size),
+            num_storage_slots: 0,
+        };
 
-                let mut cur = tx.cursor_read::<PlainStorageState>()?;
-                let mut entry = cur.seek_exact(address)?;
-                let mut curr_ss_group = U256::default();
-                while let Some((slot_address, slot)) = entry {
-                    if slot_address != address {
-                        break;
-                    }
-                    stats.num_storage_slots += 1;
-                    if slot.key < ss_header_count {
-                        stats.account_stem += 1;
-                    } else {
-                        let (mut ss_group, _) = U256::from_be_slice(slot.key.as_slice())
-                            .overflowing_shr(group_size_bits as usize);
-                        ss_group = ss_group.checked_add(U256::from(1)).unwrap();
+        let mut curr_ss_group = U256::default();
+        for entry in source.storage(address)? {
+            let (slot, _) = entry?;
+            stats.num_storage_slots += 1;
+            if slot < ss_header_count {
+                stats.account_stem += 1;
+            } else {
+                let (mut ss_group, _) =
+                    U256::from_be_slice(slot.as_slice()).overflowing_shr(group_size_bits as usize);
+                ss_group = ss_group.checked_add(U256::from(1)).unwrap();
 
-                        if ss_group != curr_ss_group {
-                            curr_ss_group = ss_group;
-                            stats.ss_stems.push(1);
-                        } else {
-                            *stats.ss_stems.last_mut().unwrap() += 1;
-                        }
-                    }
-                    entry = cur.next()?;
+                if ss_group != curr_ss_group {
+                    curr_ss_group = ss_group;
+                    stats.ss_stems.push(1);
+                } else {
+                    *stats.ss_stems.last_mut().unwrap() += 1;
                 }
-                accounts.push(stats);
-            }
-            Ok(None) => {
-                break;
-            }
-            Err(e) => {
-                return Err(e.into());
             }
         }
+        accounts.push(stats);
         bar.inc(1);
     }
     bar.finish_and_clear();
@@ -1,15 +1,6 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use clap::Parser;
-use reth_chainspec::ChainSpecBuilder;
-use reth_db::{
-    mdbx::{tx::Tx, DatabaseArguments, MaxReadTransactionDuration, RO},
-    DatabaseEnv,
-};
-use reth_node_ethereum::EthereumNode;
-use reth_node_types::NodeTypesWithDBAdapter;
-use reth_provider::{providers::StaticFileProvider, ProviderFactory, StageCheckpointReader};
-use reth_stages::StageId;
-use std::{path::Path, sync::Arc};
+use eth_stateless::{iterators::code::CodeItems, JsonStateSource, RethStateSource, StateSource};
 use tabled::{settings::Panel, Table, Tabled};
 
 mod accounts;
@@ -17,8 +8,27 @@ mod accounts;
 #[derive(Parser)]
 #[command(name = "report")]
 struct Cli {
-    #[arg(short = 'd', long = "datadir", help = "Reth datadir path")]
-    datadir: String,
+    #[arg(
+        short = 'd',
+        long = "datadir",
+        help = "Reth datadir path",
+        required_unless_present = "state_json"
+    )]
+    datadir: Option<String>,
+
+    #[arg(
+        long = "state-json",
+        help = "JSON state dump path (genesis alloc, geth dump or debug_dumpBlock) to use instead of a datadir",
+        conflicts_with = "datadir"
+    )]
+    state_json: Option<String>,
+
+    #[arg(
+        long = "chain",
+        help = "Chain name (mainnet, sepolia, holesky, dev) or genesis JSON file path",
+        default_value = "mainnet"
+    )]
+    chain: String,
 
     #[command(subcommand)]
     subcmd: SubCommand,
@@ -27,45 +37,63 @@ struct Cli {
 #[derive(Parser)]
 enum SubCommand {
     #[command(name = "accounts-stats", about = "Generate account stats report")]
-    AccountsStats,
+    AccountsStats {
+        #[arg(
+            long = "include-code",
+            help = "Read the bytecode of each contract to include its code length and code chunk stems"
+        )]
+        include_code: bool,
+
+        #[arg(
+            long = "dedup-code",
+            help = "Only include the code of the first contract with each code",
+            requires = "include_code"
+        )]
+        dedup_code: bool,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    let db_path = Path::new(&cli.datadir).join("db");
-    let db = reth_db::open_db_read_only(
-        db_path.as_ref(),
-        DatabaseArguments::default()
-            .with_max_read_transaction_duration(Some(MaxReadTransactionDuration::Unbounded)),
-    )
-    .map_err(|err| anyhow!(err))?;
-
-    let spec = ChainSpecBuilder::mainnet().build();
-    let factory = ProviderFactory::<NodeTypesWithDBAdapter<EthereumNode, Arc<DatabaseEnv>>>::new(
-        db.into(),
-        spec.into(),
-        StaticFileProvider::read_only(db_path.join("static_files"), true)?,
-    );
-    let provider = factory.provider()?;
-
-    let latest_block_number = provider
-        .get_stage_checkpoint(StageId::Finish)?
-        .map(|ch| ch.block_number)
-        .ok_or(anyhow!("No finish checkpoint"))?;
-    println!("Database block number: {:?}", latest_block_number);
+    if let Some(path) = cli.state_json {
+        let source = JsonStateSource::open(&path)?;
+        return run(&source, cli.subcmd);
+    }
 
-    let tx = provider.into_tx();
+    let datadir = cli
+        .datadir
+        .expect("--datadir is required without --state-json");
+    let source = RethStateSource::open(&datadir, &cli.chain)?;
+    println!(
+        "Chain: {} (id {})",
+        source.chain_spec.chain,
+        source.chain_id()
+    );
+    println!("Database block number: {:?}", source.block_number);
+    run(&source, cli.subcmd)
+}
 
-    match cli.subcmd {
-        SubCommand::AccountsStats => account_stats(tx)?,
+fn run(source: &impl StateSource, subcmd: SubCommand) -> Result<()> {
+    match subcmd {
+        SubCommand::AccountsStats {
+            include_code,
+            dedup_code,
+        } => {
+            let code = match (include_code, dedup_code) {
+                (false, _) => CodeItems::Skip,
+                (true, false) => CodeItems::All,
+                (true, true) => CodeItems::Deduplicated,
+            };
+            account_stats(source, code)?
+        }
     }
 
     Ok(())
 }
 
-fn account_stats(tx: Tx<RO>) -> Result<()> {
-    let stats = accounts::account_stats(&tx, 256)?;
+fn account_stats(source: &impl StateSource, code: CodeItems) -> Result<()> {
+    let stats = accounts::account_stats(source, 256, code)?;
     {
         #[derive(Tabled)]
         struct AccountCounts {
@@ -73,7 +101,7 @@ fn account_stats(tx: Tx<RO>) -> Result<()> {
             contracts: usize,
             total: usize,
         }
-        let eoa_count = stats.iter().filter(|a| a.bytecode_len == 0).count();
+        let eoa_count = stats.iter().filter(|a| !a.contract).count();
         let table = Table::new(vec![AccountCounts {
             eoas: eoa_count,
             contracts: stats.len() - eoa_count,
@@ -85,7 +113,7 @@ fn account_stats(tx: Tx<RO>) -> Result<()> {
         println!("{}\n", table);
     }
 
-    {
+    if code != CodeItems::Skip {
         let mut code_lens: Vec<u64> = stats
             .iter()
             .filter(|a| a.bytecode_len > 0)
@@ -101,7 +129,7 @@ fn account_stats(tx: Tx<RO>) -> Result<()> {
     {
         let mut num_storage_slots: Vec<u64> = stats
             .iter()
-            .filter(|a| a.bytecode_len > 0)
+            .filter(|a| a.contract)
             .map(|a| a.num_storage_slots as u64)
             .collect();
         let table = Table::new(vec![calculate_stats(&mut num_storage_slots)])
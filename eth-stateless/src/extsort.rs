@@ -0,0 +1,260 @@
+//! External merge sort of fixed-size records.
+//!
+//! Records are buffered in memory up to a maximum size, then sorted and spilled to disk as a sorted run
+//! in a temporary directory. Once all records are pushed, the runs are k-way merged, in several passes
+//! of at most [`MAX_MERGE_FAN_IN`] runs if there are more, so the number of open files is bounded. If all
+//! the records fit in memory nothing is written to disk.
+//!
+//! Records are compared as byte strings, so keys must be encoded big-endian at the start of the record.
+
+use anyhow::{Context, Result};
+use rayon::slice::ParallelSliceMut;
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+};
+use tempfile::TempDir;
+
+const RUN_READ_BUFFER_SIZE: usize = 1 << 16;
+/// Maximum number of runs merged at once, so the merge doesn't run out of file descriptors.
+const MAX_MERGE_FAN_IN: usize = 256;
+
+pub struct ExternalSorter<const N: usize> {
+    max_records: usize,
+    max_fan_in: usize,
+    buf: Vec<[u8; N]>,
+
+    dir: Option<TempDir>,
+    runs: Vec<Run>,
+    /// Number of run files created so far, to name the next one.
+    created_runs: usize,
+}
+
+struct Run {
+    path: PathBuf,
+    records: u64,
+}
+
+impl<const N: usize> ExternalSorter<N> {
+    /// Creates a sorter buffering at most `max_memory` bytes of records before spilling them to disk.
+    /// The runs are stored in a temporary directory created in `TMPDIR`.
+    pub fn new(max_memory: usize) -> Self {
+        Self {
+            max_records: (max_memory / N).max(1),
+            max_fan_in: MAX_MERGE_FAN_IN,
+            buf: Vec::new(),
+            dir: None,
+            runs: Vec::new(),
+            created_runs: 0,
+        }
+    }
+
+    pub fn push(&mut self, record: [u8; N]) -> Result<()> {
+        if self.buf.len() >= self.max_records {
+            self.spill()?;
+        }
+        self.buf.push(record);
+        Ok(())
+    }
+
+    /// Returns the number of sorted runs spilled to disk so far.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        self.buf.par_sort_unstable();
+        let buf = std::mem::take(&mut self.buf);
+        let run = self.write_run(buf.iter().map(|record| Ok(*record)))?;
+        self.runs.push(run);
+        self.buf = buf;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes the sorted `records` to a new run file.
+    fn write_run(&mut self, records: impl Iterator<Item = Result<[u8; N]>>) -> Result<Run> {
+        if self.dir.is_none() {
+            self.dir = Some(TempDir::new().context("creating sort temporary directory")?);
+        }
+        let path = self
+            .dir
+            .as_ref()
+            .expect("temporary directory created")
+            .path()
+            .join(format!("run-{}", self.created_runs));
+        self.created_runs += 1;
+        let mut w = BufWriter::new(File::create(&path).context("creating sort run file")?);
+        let mut count = 0;
+        for record in records {
+            w.write_all(&record?)?;
+            count += 1;
+        }
+        w.flush()?;
+        Ok(Run {
+            path,
+            records: count,
+        })
+    }
+
+    /// Returns the pushed records in ascending order.
+    pub fn finish(mut self) -> Result<SortedRecords<N>> {
+        if self.runs.is_empty() {
+            self.buf.par_sort_unstable();
+            return Ok(SortedRecords(Records::Memory(self.buf.into_iter())));
+        }
+        if !self.buf.is_empty() {
+            self.spill()?;
+        }
+        // The merge only needs a read buffer per run.
+        self.buf = Vec::new();
+
+        // Runs are merged in passes of at most `max_fan_in` runs until they can all be merged at once.
+        while self.runs.len() > self.max_fan_in {
+            let runs = std::mem::take(&mut self.runs);
+            let mut runs = runs.into_iter().peekable();
+            while runs.peek().is_some() {
+                let group: Vec<_> = runs.by_ref().take(self.max_fan_in).collect();
+                if group.len() == 1 {
+                    self.runs.extend(group);
+                    continue;
+                }
+                let run = self.write_run(Merge::new(&group)?)?;
+                for merged in group {
+                    fs::remove_file(&merged.path).context("removing sort run file")?;
+                }
+                self.runs.push(run);
+            }
+        }
+        Ok(SortedRecords(Records::Merge {
+            merge: Merge::new(&self.runs)?,
+            _dir: self.dir,
+        }))
+    }
+}
+
+struct RunReader {
+    r: BufReader<File>,
+    remaining: u64,
+}
+
+impl RunReader {
+    fn next<const N: usize>(&mut self) -> Result<Option<[u8; N]>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let mut record = [0u8; N];
+        self.r
+            .read_exact(&mut record)
+            .context("reading sort run file")?;
+        self.remaining -= 1;
+        Ok(Some(record))
+    }
+}
+
+/// K-way merge of sorted runs.
+struct Merge<const N: usize> {
+    readers: Vec<RunReader>,
+    heap: BinaryHeap<Reverse<([u8; N], usize)>>,
+}
+
+impl<const N: usize> Merge<N> {
+    fn new(runs: &[Run]) -> Result<Self> {
+        let mut readers = Vec::with_capacity(runs.len());
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (i, run) in runs.iter().enumerate() {
+            let file = File::open(&run.path).context("opening sort run file")?;
+            let mut reader = RunReader {
+                r: BufReader::with_capacity(RUN_READ_BUFFER_SIZE, file),
+                remaining: run.records,
+            };
+            if let Some(record) = reader.next()? {
+                heap.push(Reverse((record, i)));
+            }
+            readers.push(reader);
+        }
+        Ok(Self { readers, heap })
+    }
+}
+
+impl<const N: usize> Iterator for Merge<N> {
+    type Item = Result<[u8; N]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((record, i)) = self.heap.pop()?;
+        match self.readers[i].next() {
+            Ok(Some(next)) => self.heap.push(Reverse((next, i))),
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Ok(record))
+    }
+}
+
+pub struct SortedRecords<const N: usize>(Records<N>);
+
+enum Records<const N: usize> {
+    Memory(std::vec::IntoIter<[u8; N]>),
+    Merge {
+        merge: Merge<N>,
+        // Removed when the records are dropped.
+        _dir: Option<TempDir>,
+    },
+}
+
+impl<const N: usize> Iterator for SortedRecords<N> {
+    type Item = Result<[u8; N]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            Records::Memory(records) => records.next().map(Ok),
+            Records::Merge { merge, .. } => merge.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_in_memory_and_on_disk() {
+        let records: Vec<[u8; 4]> = (0..1000u32)
+            .map(|i| i.wrapping_mul(2654435761).to_be_bytes())
+            .collect();
+        let mut expected = records.clone();
+        expected.sort();
+
+        for max_memory in [1 << 20, 4 * 64, 4 * 8] {
+            let mut sorter = ExternalSorter::new(max_memory);
+            for record in &records {
+                sorter.push(*record).unwrap();
+            }
+            let spilled = sorter.runs() > 0;
+            assert_eq!(spilled, max_memory < 4 * records.len());
+            let sorted: Vec<_> = sorter.finish().unwrap().map(|r| r.unwrap()).collect();
+            assert_eq!(sorted, expected);
+        }
+    }
+
+    #[test]
+    fn merges_runs_in_passes() {
+        let records: Vec<[u8; 4]> = (0..1000u32)
+            .map(|i| i.wrapping_mul(2654435761).to_be_bytes())
+            .collect();
+        let mut expected = records.clone();
+        expected.sort();
+
+        let mut sorter = ExternalSorter::new(4 * 8);
+        sorter.max_fan_in = 4;
+        for record in &records {
+            sorter.push(*record).unwrap();
+        }
+        assert_eq!(sorter.runs(), 124);
+        let sorted: Vec<_> = sorter.finish().unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(sorted, expected);
+    }
+}
@@ -0,0 +1,212 @@
+//! Contract code items of the preimage iterators.
+//!
+//! EIP-7748 converts the code of each contract, in chunks, after its storage slots. The iterators can
+//! emit a code item with the code hash of each contract after its storage slots, see [`CodeItems`]. The
+//! code hash is the one of the account the iterator already read, and the bytecode must be in the state
+//! (e.g: the `Bytecodes` table).
+//!
+//! Contracts sharing their code can be deduplicated, so only the first contract with a given code hash
+//! has a code item. The code hashes seen so far are kept in memory: one per distinct code, so it's bounded
+//! by the number of bytecodes of the state rather than by its number of contracts.
+//!
+//! Sample output: [account1, account1_ss0, account1_ss1, account1_code, account2, account3, account3_ss0, ...]
+
+use alloy_primitives::{Address, B256};
+use alloy_trie::KECCAK_EMPTY;
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+
+use crate::source::StateSource;
+
+/// Contract code items emitted by an iterator.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CodeItems {
+    /// No code item.
+    #[default]
+    Skip,
+    /// The code hash of every contract.
+    All,
+    /// The code hash of the first contract with each code.
+    Deduplicated,
+}
+
+/// Code items of the contracts of an iterator, in its order.
+pub(crate) struct Codes {
+    items: CodeItems,
+    /// Code hashes already emitted, if deduplicating.
+    seen: HashSet<B256>,
+}
+
+impl Codes {
+    pub(crate) fn new(items: CodeItems) -> Self {
+        Self {
+            items,
+            seen: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.items != CodeItems::Skip
+    }
+
+    /// Returns the code hash of the code item of an account with `code_hash`, if it's a contract whose
+    /// code isn't skipped.
+    pub(crate) fn code_hash(&mut self, code_hash: Option<B256>) -> Option<B256> {
+        let code_hash = contract_code_hash(code_hash)?;
+        match self.items {
+            CodeItems::Skip => None,
+            CodeItems::All => Some(code_hash),
+            CodeItems::Deduplicated => self.seen.insert(code_hash).then_some(code_hash),
+        }
+    }
+}
+
+/// Returns `code_hash` if it's the one of a contract, i.e: not the hash of empty code.
+pub(crate) fn contract_code_hash(code_hash: Option<B256>) -> Option<B256> {
+    code_hash.filter(|hash| *hash != KECCAK_EMPTY)
+}
+
+/// Checks the bytecode with `code_hash` of the contract at `address` is in `source`.
+pub(crate) fn check_bytecode<S: StateSource + ?Sized>(
+    source: &S,
+    address: Address,
+    code_hash: B256,
+) -> Result<()> {
+    if source.bytecode(code_hash)?.is_none() {
+        bail!(
+            "Bytecode {} (address: {}) isn't in the state",
+            code_hash,
+            address
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{Bytes, U256};
+    use std::thread;
+
+    use super::*;
+    use crate::iterators::{
+        eip7748::Eip7748Iterator, eip7748_external::Eip7748ExternalIterator, plain::PlainIterator,
+        AccountStorageItem, PreimageIterator,
+    };
+    use crate::source::{memory::MemoryStateSource, Account};
+    use AccountStorageItem::{Account as A, Code as C, StorageSlot as S};
+
+    fn state() -> (MemoryStateSource, B256, B256) {
+        let address = Address::with_last_byte;
+        let mut source = MemoryStateSource::default();
+        let code = source.insert_bytecode(Bytes::from_static(&[0x60, 0x00, 0xff]));
+        let other_code = source.insert_bytecode(Bytes::from_static(&[0x00]));
+        for (i, code_hash) in [
+            (1, Some(code)),
+            (2, None),
+            (3, Some(code)),
+            (4, Some(KECCAK_EMPTY)),
+            (5, Some(other_code)),
+        ] {
+            let account = Account {
+                code_hash,
+                ..Default::default()
+            };
+            source.insert_account(address(i), account);
+        }
+        source.insert_storage_slot(address(1), B256::with_last_byte(1), U256::from(1));
+        source.insert_storage_slot(address(3), B256::with_last_byte(2), U256::from(2));
+        (source, code, other_code)
+    }
+
+    /// Returns the items of every iterator with `code`, checking they're the same ones up to the order
+    /// of the accounts.
+    fn collect(source: &MemoryStateSource, code: CodeItems) -> Result<Vec<AccountStorageItem>> {
+        let collect = |it: &mut dyn PreimageIterator| it.collect::<Result<Vec<_>>>();
+        let plain = collect(&mut PlainIterator::new(source)?.with_code(code))?;
+        let mut eip7748 = thread::scope(|s| {
+            collect(&mut Eip7748Iterator::new(s, source, None::<fn(Address)>)?.with_code(code))
+        })?;
+        let external = collect(&mut Eip7748ExternalIterator::new(
+            source,
+            1 << 20,
+            code,
+            None::<fn(Address)>,
+        )?)?;
+        assert_eq!(eip7748, external);
+
+        // Code items are deduplicated in the order of the iterator.
+        if code != CodeItems::Deduplicated {
+            let key = |item: &AccountStorageItem| match item {
+                A(address) | S(address, _) | C(address, _) => *address,
+            };
+            eip7748.sort_by_key(key);
+            assert_eq!(eip7748, plain);
+        }
+        Ok(plain)
+    }
+
+    #[test]
+    fn adds_code_after_storage_slots() {
+        let (source, code, other_code) = state();
+        let address = Address::with_last_byte;
+        let slot = B256::with_last_byte;
+        assert_eq!(
+            collect(&source, CodeItems::All).unwrap(),
+            vec![
+                A(address(1)),
+                S(address(1), slot(1)),
+                C(address(1), code),
+                A(address(2)),
+                A(address(3)),
+                S(address(3), slot(2)),
+                C(address(3), code),
+                A(address(4)),
+                A(address(5)),
+                C(address(5), other_code),
+            ]
+        );
+        assert!(!collect(&source, CodeItems::Skip)
+            .unwrap()
+            .iter()
+            .any(|item| matches!(item, C(..))));
+    }
+
+    #[test]
+    fn deduplicates_code_hashes() {
+        let (source, code, other_code) = state();
+        let codes: Vec<_> = collect(&source, CodeItems::Deduplicated)
+            .unwrap()
+            .into_iter()
+            .filter(|item| matches!(item, C(..)))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                C(Address::with_last_byte(1), code),
+                C(Address::with_last_byte(5), other_code)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_bytecode() {
+        let (mut source, ..) = state();
+        source.bytecodes.clear();
+        let code = CodeItems::All;
+        let plain: Result<Vec<_>> = PlainIterator::new(&source)
+            .unwrap()
+            .with_code(code)
+            .collect();
+        assert!(plain.is_err());
+        let eip7748: Result<Vec<_>> = thread::scope(|s| {
+            Eip7748Iterator::new(s, &source, None::<fn(Address)>)
+                .unwrap()
+                .with_code(code)
+                .collect()
+        });
+        assert!(eip7748.is_err());
+        // The external iterator reads the code hashes while sorting.
+        let external = Eip7748ExternalIterator::new(&source, 1 << 20, code, None::<fn(Address)>);
+        assert!(external.is_err());
+    }
+}
@@ -0,0 +1,796 @@
+//! Implementation of the EIP-7748 preimage access sequence iterator.
+//!
+//! This module provides an account and storage slot iterator respecting the order defined in EIP-7748.
+//! The ordering can be summarized as:
+//! 1. DFS the state tree, until an account is reached.
+//! 2. For each account, iterate over its state trie also in DFS order.
+//!
+//! In summary, the ordering is based by account and storage slot _hash_ (i.e keccak256).
+//!
+//! Sample output: [hash(account1), hash(account1_ss0), hash(account1_ss1), hash(account2), hash(account3), hash(account3_ss0), ...]
+//!
+//! The position of the iterator is the conversion pointer of EIP-7748, a [`ConversionCursor`], so the
+//! conversion can be driven block by block: the iterator can be resumed from the cursor of a previous
+//! one, even in the middle of the storage slots of an account. A resumed iterator seeks the accounts and
+//! storage slots after the cursor by hash (see [`StateSource::hashed_accounts`]) instead of sorting the
+//! addresses of the state. Resuming only takes constant memory for sources keyed by hash, e.g: a
+//! `RethStateSource` with preimages, the default seeks hash and sort all the accounts otherwise.
+
+use alloy_primitives::{keccak256, Address, B256};
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::{
+    mpsc::{self, Receiver, SyncSender},
+    Mutex,
+};
+use std::thread::Scope;
+
+use super::code::{check_bytecode, contract_code_hash, CodeItems, Codes};
+use super::{AccountStorageItem, PreimageIterator};
+use crate::source::{Account, StateSource};
+
+/// Number of accounts, in hash order, whose storage slots are read and sorted ahead at once.
+const PREFETCH_ACCOUNTS: usize = 1024;
+/// Number of batches of [`PREFETCH_ACCOUNTS`] accounts the prefetch thread can read ahead of the
+/// iterator.
+const PREFETCH_QUEUE_BATCHES: usize = 2;
+
+/// Accounts with their code hash, if code items are emitted, and their storage slots sorted by hash.
+type PrefetchedAccount = (Address, Option<B256>, Vec<B256>);
+
+/// Accounts sent by the prefetch thread, `None` once all were sent.
+type Prefetched = Option<Result<Vec<PrefetchedAccount>>>;
+
+/// Accounts read by the prefetch thread, in hash order.
+enum Accounts<'env> {
+    /// Addresses sorted by hash, read from the state as they're prefetched.
+    Sorted(Cow<'env, [Address]>),
+    /// Accounts from the one of the cursor, seeked by hash in the state.
+    Resumed(ConversionCursor),
+}
+
+/// Position of the conversion between two items of an [`Eip7748Iterator`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConversionCursor {
+    /// Hashed address of the account being converted.
+    pub account_hash: B256,
+    pub phase: ConversionPhase,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConversionPhase {
+    /// The storage slots of the account are being converted, after the one with this hash if any.
+    Storage(Option<B256>),
+    /// The storage slots of the account were converted, its data (and code) is next. The conversion
+    /// continues with the next account.
+    AccountData,
+}
+
+/// Iterator building the address list with a parallel scan of the accounts, split by the first byte of
+/// the address. The sorted storage slots of the next accounts are read in parallel by a prefetch thread,
+/// spawned in the scope the iterator is created with, ahead of the iterator. Each thread reads from its
+/// own [`StateSource::view`].
+pub struct Eip7748Iterator {
+    // Starts the prefetch thread on the first item, telling it whether to read the code hashes.
+    start: Option<SyncSender<bool>>,
+    receiver: Receiver<Prefetched>,
+    batch: std::vec::IntoIter<PrefetchedAccount>,
+    codes: Codes,
+    state: State,
+    // Last account and storage slot returned, and the cursor the iterator was resumed at until the next
+    // item, for the conversion cursor.
+    last_account: Option<Address>,
+    last_storage_slot: Option<B256>,
+    last_code: bool,
+    resumed: Option<ConversionCursor>,
+    done: bool,
+}
+
+enum State {
+    Account,
+    /// Storage slots of the account with the address and code hash.
+    StorageSlot(Address, Option<B256>, std::vec::IntoIter<B256>),
+    End,
+}
+
+impl PreimageIterator for Eip7748Iterator {}
+
+impl Eip7748Iterator {
+    pub fn new<'scope, 'env, S, P>(
+        scope: &'scope Scope<'scope, 'env>,
+        source: &'env S,
+        progress: Option<P>,
+    ) -> Result<Self>
+    where
+        S: StateSource + ?Sized,
+        P: FnMut(Address) + Send,
+    {
+        let ordered_addresses = Self::sorted_addresses(source, progress)?;
+        Ok(Self::with_sorted_addresses(
+            scope,
+            source,
+            ordered_addresses,
+        ))
+    }
+
+    /// Returns the iterator of the accounts of `ordered_addresses`, which must be sorted by hash, e.g: a
+    /// subslice of [`Eip7748Iterator::sorted_addresses`].
+    pub fn with_sorted_addresses<'scope, 'env, S: StateSource + ?Sized>(
+        scope: &'scope Scope<'scope, 'env>,
+        source: &'env S,
+        ordered_addresses: impl Into<Cow<'env, [Address]>>,
+    ) -> Self {
+        let accounts = Accounts::Sorted(ordered_addresses.into());
+        Self::spawn(scope, source, accounts)
+    }
+
+    /// Returns the iterator resumed at `cursor`. The accounts from the one of the cursor are seeked by
+    /// hash, and so are its storage slots after the cursor when resuming in the middle of an account. If
+    /// the account was removed since, e.g: the state is at a later block, the iterator resumes at the
+    /// next account.
+    ///
+    /// Neither the addresses nor the storage slots of the state are sorted, so the memory used doesn't
+    /// grow with the state, only if `source` is keyed by hash, i.e: overrides
+    /// [`StateSource::hashed_accounts`] and [`StateSource::hashed_storage`]. A `RethStateSource` is
+    /// only with `with_preimages`, its seeks hash and sort all the accounts otherwise.
+    pub fn with_cursor<'scope, 'env, S: StateSource + ?Sized>(
+        scope: &'scope Scope<'scope, 'env>,
+        source: &'env S,
+        cursor: ConversionCursor,
+    ) -> Self {
+        let mut it = Self::spawn(scope, source, Accounts::Resumed(cursor));
+        it.resumed = Some(cursor);
+        it
+    }
+
+    /// Spawns the prefetch thread of `accounts`, see [`prefetch_storage_slots`].
+    fn spawn<'scope, 'env, S: StateSource + ?Sized>(
+        scope: &'scope Scope<'scope, 'env>,
+        source: &'env S,
+        accounts: Accounts<'env>,
+    ) -> Self {
+        let (start_sender, start_receiver) = mpsc::sync_channel(1);
+        let (sender, receiver) = mpsc::sync_channel(PREFETCH_QUEUE_BATCHES);
+        scope.spawn(move || {
+            // The iterator was dropped before its first item otherwise.
+            let Ok(code) = start_receiver.recv() else {
+                return;
+            };
+            match accounts {
+                Accounts::Sorted(addresses) => {
+                    let accounts = addresses.iter().map(|address| Ok((*address, None)));
+                    prefetch_storage_slots(source, accounts, None, code, sender)
+                }
+                Accounts::Resumed(cursor) => match source.view() {
+                    Ok(view) => {
+                        let mut accounts = view
+                            .hashed_accounts(cursor.account_hash)
+                            .unwrap_or_else(|e| Box::new(std::iter::once(Err(e))))
+                            .peekable();
+                        // The account of the cursor is the first one seeked, unless it was removed.
+                        let mut after = None;
+                        if let Some(Ok((hashed_address, ..))) = accounts.peek() {
+                            if *hashed_address == cursor.account_hash {
+                                match cursor.phase {
+                                    ConversionPhase::Storage(last_slot_hash) => {
+                                        after = last_slot_hash
+                                    }
+                                    ConversionPhase::AccountData => {
+                                        accounts.next();
+                                    }
+                                }
+                            }
+                        }
+                        let accounts = accounts.map(|entry| {
+                            entry.map(|(_, address, account)| (address, Some(account)))
+                        });
+                        prefetch_storage_slots(source, accounts, after, code, sender)
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Some(Err(e)));
+                    }
+                },
+            }
+        });
+        Eip7748Iterator {
+            start: Some(start_sender),
+            receiver,
+            batch: Vec::new().into_iter(),
+            codes: Codes::new(CodeItems::Skip),
+            state: State::Account,
+            last_account: None,
+            last_storage_slot: None,
+            last_code: false,
+            resumed: None,
+            done: false,
+        }
+    }
+
+    /// Returns the iterator emitting `code` items after the storage slots of each contract, see
+    /// [`super::code`]. The code hashes are read along with the storage slots.
+    pub fn with_code(mut self, code: CodeItems) -> Self {
+        self.codes = Codes::new(code);
+        self
+    }
+
+    /// Returns the conversion cursor after the last item returned, or the one it was resumed at. It's
+    /// `None` before the first account.
+    pub fn cursor(&self) -> Option<ConversionCursor> {
+        let mut cursor = match (self.resumed, self.last_account) {
+            (Some(cursor), _) => cursor,
+            (None, Some(address)) => ConversionCursor {
+                account_hash: keccak256(address),
+                phase: ConversionPhase::Storage(self.last_storage_slot.map(keccak256)),
+            },
+            (None, None) => return None,
+        };
+        if self.done || self.last_code {
+            cursor.phase = ConversionPhase::AccountData;
+        }
+        Some(cursor)
+    }
+
+    /// Returns whether the iterator was resumed in the middle of the storage slots of `address`, so the
+    /// account was returned before the cursor and only its remaining storage slots are.
+    fn resumed_in(&self, address: Address) -> bool {
+        match self.resumed {
+            Some(ConversionCursor {
+                account_hash,
+                phase: ConversionPhase::Storage(_),
+            }) => account_hash == keccak256(address),
+            _ => false,
+        }
+    }
+
+    /// Returns the addresses of `source` sorted by hash. `progress` is called as the address ranges are
+    /// scanned, with the last address of the scanned ranges.
+    pub fn sorted_addresses<S, P>(source: &S, progress: Option<P>) -> Result<Vec<Address>>
+    where
+        S: StateSource + ?Sized,
+        P: FnMut(Address) + Send,
+    {
+        let progress = Mutex::new((0u8, progress));
+        let ranges = (0..=u8::MAX)
+            .into_par_iter()
+            .map(|first_byte| -> Result<Vec<(B256, Address)>> {
+                let view = source.view()?;
+                let end = first_byte.checked_add(1).map(range_start);
+                let mut addresses = Vec::new();
+                for entry in view.accounts_range(range_start(first_byte), end)? {
+                    let (address, _) = entry?;
+                    addresses.push((keccak256(address), address));
+                }
+
+                let (scanned, progress) = &mut *progress.lock().expect("progress lock");
+                if let Some(progress) = progress {
+                    let mut last = [0xff; 20];
+                    last[0] = *scanned;
+                    progress(Address::from(last));
+                }
+                *scanned = scanned.saturating_add(1);
+                Ok(addresses)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Each range is freed as it's moved, so the addresses aren't held twice.
+        let mut addresses = Vec::with_capacity(source.accounts_count()? as usize);
+        for range in ranges {
+            addresses.extend(range);
+        }
+        addresses.par_sort_unstable_by_key(|(hashed_address, _)| *hashed_address);
+        Ok(addresses.into_iter().map(|(_, addr)| addr).collect())
+    }
+}
+
+/// Reads the storage slots sorted by hash of `accounts` by batches of [`PREFETCH_ACCOUNTS`] accounts, each
+/// split in a chunk per thread, and sends them to the iterator. Only the storage slots after `after` are
+/// read for the first account, if set. The code hashes of the contracts are read too if `code` is set,
+/// from the account if it was already read, checking their bytecode is in the state. Stops early if the
+/// iterator was dropped or a read failed.
+fn prefetch_storage_slots<S: StateSource + ?Sized>(
+    source: &S,
+    mut accounts: impl Iterator<Item = Result<(Address, Option<Account>)>>,
+    mut after: Option<B256>,
+    code: bool,
+    sender: SyncSender<Prefetched>,
+) {
+    loop {
+        let batch = match accounts
+            .by_ref()
+            .take(PREFETCH_ACCOUNTS)
+            .collect::<Result<Vec<_>>>()
+        {
+            Ok(batch) if batch.is_empty() => break,
+            Ok(batch) => batch,
+            Err(e) => {
+                let _ = sender.send(Some(Err(e)));
+                return;
+            }
+        };
+        let chunk_size = batch.len().div_ceil(rayon::current_num_threads());
+        let prefetched = batch
+            .par_chunks(chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| -> Result<Vec<PrefetchedAccount>> {
+                let view = source.view()?;
+                chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(j, (address, account))| {
+                        let after = after.filter(|_| i == 0 && j == 0);
+                        let code_hash = match (code, account) {
+                            (false, _) => None,
+                            (true, Some(account)) => {
+                                checked_code_hash(&*view, *address, account.code_hash)?
+                            }
+                            (true, None) => read_code_hash(&*view, *address)?,
+                        };
+                        let storage_slots = view
+                            .hashed_storage(*address, after)?
+                            .map(|entry| entry.map(|(_, slot)| slot))
+                            .collect::<Result<_>>()?;
+                        Ok((*address, code_hash, storage_slots))
+                    })
+                    .collect()
+            })
+            .collect::<Result<Vec<_>>>()
+            .map(|chunks| chunks.into_iter().flatten().collect());
+        after = None;
+        let failed = prefetched.is_err();
+        if sender.send(Some(prefetched)).is_err() || failed {
+            return;
+        }
+    }
+    let _ = sender.send(None);
+}
+
+/// Returns the code hash of the contract at `address`, if it's a contract, checking its bytecode is in
+/// `source`.
+fn read_code_hash(source: &dyn StateSource, address: Address) -> Result<Option<B256>> {
+    let account = source
+        .account(address)?
+        .ok_or(anyhow!("Account {} isn't in the state", address))?;
+    checked_code_hash(source, address, account.code_hash)
+}
+
+/// Returns `code_hash` of the account at `address` if it's the one of a contract, checking its bytecode
+/// is in `source`.
+fn checked_code_hash(
+    source: &dyn StateSource,
+    address: Address,
+    code_hash: Option<B256>,
+) -> Result<Option<B256>> {
+    let code_hash = contract_code_hash(code_hash);
+    if let Some(code_hash) = code_hash {
+        check_bytecode(source, address, code_hash)?;
+    }
+    Ok(code_hash)
+}
+
+fn range_start(first_byte: u8) -> Address {
+    let mut start = [0; 20];
+    start[0] = first_byte;
+    Address::from(start)
+}
+
+impl Iterator for Eip7748Iterator {
+    type Item = Result<AccountStorageItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match &mut self.state {
+                State::Account => {
+                    let Some((address, code_hash, storage_slots)) = self.batch.next() else {
+                        if let Some(start) = self.start.take() {
+                            // The prefetch thread only stops early if the iterator is dropped.
+                            let _ = start.send(self.codes.enabled());
+                        }
+                        match self.receiver.recv() {
+                            Ok(Some(Ok(batch))) => self.batch = batch.into_iter(),
+                            Ok(Some(Err(e))) => {
+                                self.state = State::End;
+                                return Some(Err(e));
+                            }
+                            Ok(None) => {
+                                self.state = State::End;
+                                self.done = true;
+                                return None;
+                            }
+                            Err(_) => {
+                                self.state = State::End;
+                                return Some(Err(anyhow!("Storage slots prefetch thread stopped")));
+                            }
+                        }
+                        continue;
+                    };
+                    self.state = State::StorageSlot(address, code_hash, storage_slots.into_iter());
+                    self.last_account = Some(address);
+                    self.last_storage_slot = None;
+                    self.last_code = false;
+                    if self.resumed_in(address) {
+                        continue;
+                    }
+                    self.resumed = None;
+                    return Some(Ok(AccountStorageItem::Account(address)));
+                }
+                State::StorageSlot(address, code_hash, storage_slots) => {
+                    let address = *address;
+                    if let Some(key) = storage_slots.next() {
+                        self.last_storage_slot = Some(key);
+                        self.resumed = None;
+                        return Some(Ok(AccountStorageItem::StorageSlot(address, key)));
+                    }
+                    let code_hash = self.codes.code_hash(*code_hash);
+                    self.state = State::Account;
+                    if let Some(code_hash) = code_hash {
+                        self.last_code = true;
+                        self.resumed = None;
+                        return Some(Ok(AccountStorageItem::Code(address, code_hash)));
+                    }
+                }
+                State::End => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{Bytes, U256};
+    use anyhow::bail;
+    use std::thread;
+
+    use super::*;
+    use crate::source::{
+        memory::MemoryStateSource, Account, AccountsIter, HashedAccountsIter, HashedStorageIter,
+        StorageIter,
+    };
+    use AccountStorageItem::{Account as A, Code as C, StorageSlot as S};
+
+    fn collect(source: &MemoryStateSource) -> Vec<AccountStorageItem> {
+        thread::scope(|s| {
+            Eip7748Iterator::new(s, source, None::<fn(Address)>)
+                .unwrap()
+                .map(|item| item.unwrap())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn orders_accounts_and_storage_slots_by_hash() {
+        let address = Address::with_last_byte;
+        let slot = B256::with_last_byte;
+        let mut source = MemoryStateSource::default();
+        for i in 1..=5 {
+            source.insert_account(address(i), Account::default());
+        }
+        for i in 1..=4 {
+            source.insert_storage_slot(address(3), slot(i), U256::from(i));
+        }
+        source.insert_storage_slot(address(2), slot(1), U256::from(1));
+        source.insert_storage_slot(address(2), slot(2), U256::ZERO);
+
+        // keccak256 order of the addresses is 1, 5, 3, 4, 2 and the one of the slots is 2, 4, 1, 3.
+        assert_eq!(
+            collect(&source),
+            vec![
+                A(address(1)),
+                A(address(5)),
+                A(address(3)),
+                S(address(3), slot(2)),
+                S(address(3), slot(4)),
+                S(address(3), slot(1)),
+                S(address(3), slot(3)),
+                A(address(4)),
+                A(address(2)),
+                S(address(2), slot(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn hashes_are_strictly_increasing() {
+        let mut source = MemoryStateSource::default();
+        for i in 0..200u64 {
+            let address = Address::left_padding_from(&i.to_be_bytes());
+            source.insert_account(address, Account::default());
+            for j in 0..i % 7 {
+                let slot = B256::left_padding_from(&(i * j).to_be_bytes());
+                source.insert_storage_slot(address, slot, U256::from(1));
+            }
+        }
+
+        let items = collect(&source);
+        let accounts: Vec<_> = items
+            .iter()
+            .filter_map(|item| match item {
+                A(address) => Some(keccak256(address)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(accounts.len(), 200);
+        assert!(accounts.windows(2).all(|w| w[0] < w[1]));
+        for chunk in items.split(|item| matches!(item, A(_))) {
+            let slots: Vec<_> = chunk
+                .iter()
+                .map(|item| match item {
+                    S(_, slot) => keccak256(slot),
+                    _ => unreachable!(),
+                })
+                .collect();
+            assert!(slots.windows(2).all(|w| w[0] < w[1]));
+        }
+    }
+
+    #[test]
+    fn matches_sequential_ordering_across_prefetches() {
+        let mut source = MemoryStateSource::default();
+        for i in 0..3 * PREFETCH_ACCOUNTS as u64 {
+            // Spread the addresses over all the scanned ranges.
+            let address = Address::from_slice(&keccak256(i.to_be_bytes())[..20]);
+            source.insert_account(address, Account::default());
+            for j in 0..i % 3 {
+                let slot = B256::left_padding_from(&(i * 3 + j).to_be_bytes());
+                source.insert_storage_slot(address, slot, U256::from(1));
+            }
+        }
+
+        let mut addresses: Vec<_> = source.accounts.keys().copied().collect();
+        addresses.sort_by_key(|address| keccak256(address));
+        let mut expected = Vec::new();
+        for address in addresses {
+            expected.push(A(address));
+            let mut slots: Vec<_> = source
+                .storage
+                .get(&address)
+                .into_iter()
+                .flat_map(|storage| storage.keys().copied())
+                .collect();
+            slots.sort_by_key(|slot| keccak256(slot));
+            expected.extend(slots.into_iter().map(|slot| S(address, slot)));
+        }
+        assert_eq!(collect(&source), expected);
+    }
+
+    #[test]
+    fn prefetch_thread_stops_when_the_iterator_is_dropped() {
+        let mut source = MemoryStateSource::default();
+        for i in 0..(PREFETCH_QUEUE_BATCHES + 3) * PREFETCH_ACCOUNTS {
+            let address = Address::left_padding_from(&i.to_be_bytes());
+            source.insert_account(address, Account::default());
+        }
+
+        // The scope only returns once the prefetch thread, blocked on the full queue, is done.
+        let first = thread::scope(|s| {
+            let mut it = Eip7748Iterator::new(s, &source, None::<fn(Address)>).unwrap();
+            it.next().unwrap().unwrap()
+        });
+        assert!(matches!(first, A(_)));
+    }
+
+    #[test]
+    fn sorted_address_slices_concatenate_to_the_whole_iteration() {
+        let mut source = MemoryStateSource::default();
+        for i in 0..100u64 {
+            let address = Address::left_padding_from(&i.to_be_bytes());
+            source.insert_account(address, Account::default());
+            for j in 0..i % 4 {
+                let slot = B256::left_padding_from(&(i * 4 + j).to_be_bytes());
+                source.insert_storage_slot(address, slot, U256::from(1));
+            }
+        }
+
+        let addresses = Eip7748Iterator::sorted_addresses(&source, None::<fn(Address)>).unwrap();
+        let mut items = Vec::new();
+        thread::scope(|s| {
+            for range in [0..1, 1..40, 40..40, 40..100] {
+                let it = Eip7748Iterator::with_sorted_addresses(s, &source, &addresses[range]);
+                items.extend(it.map(|item| item.unwrap()));
+            }
+        });
+        assert_eq!(items, collect(&source));
+    }
+
+    #[test]
+    fn resumes_from_the_cursor_of_every_position() {
+        let mut source = MemoryStateSource::default();
+        for i in 0..20u64 {
+            let address = Address::left_padding_from(&i.to_be_bytes());
+            source.insert_account(address, Account::default());
+            for j in 0..i % 4 {
+                let slot = B256::left_padding_from(&(i * 4 + j).to_be_bytes());
+                source.insert_storage_slot(address, slot, U256::from(1));
+            }
+        }
+        let addresses = Eip7748Iterator::sorted_addresses(&source, None::<fn(Address)>).unwrap();
+        let expected = collect(&source);
+
+        thread::scope(|s| {
+            for position in 1..=expected.len() {
+                let mut it = Eip7748Iterator::with_sorted_addresses(s, &source, &addresses[..]);
+                let mut items: Vec<_> = it
+                    .by_ref()
+                    .take(position)
+                    .map(|item| item.unwrap())
+                    .collect();
+                let cursor = it.cursor().unwrap();
+                let json = serde_json::to_string(&cursor).unwrap();
+                let cursor: ConversionCursor = serde_json::from_str(&json).unwrap();
+                let resumed = Eip7748Iterator::with_cursor(s, &source, cursor);
+                assert_eq!(resumed.cursor(), Some(cursor));
+                items.extend(resumed.map(|item| item.unwrap()));
+                assert_eq!(items, expected);
+            }
+
+            let mut it = Eip7748Iterator::with_sorted_addresses(s, &source, &addresses[..]);
+            assert_eq!(it.cursor(), None);
+            it.by_ref().for_each(drop);
+            assert_eq!(it.cursor().unwrap().phase, ConversionPhase::AccountData);
+        });
+    }
+
+    #[test]
+    fn resumes_after_code_items() {
+        let mut source = MemoryStateSource::default();
+        let code_hash = source.insert_bytecode(Bytes::from_static(&[0x00]));
+        for i in 0..10u64 {
+            let address = Address::left_padding_from(&i.to_be_bytes());
+            let account = Account {
+                code_hash: (i % 2 == 0).then_some(code_hash),
+                ..Default::default()
+            };
+            source.insert_account(address, account);
+            for j in 0..i % 3 {
+                let slot = B256::left_padding_from(&(i * 3 + j).to_be_bytes());
+                source.insert_storage_slot(address, slot, U256::from(1));
+            }
+        }
+        let addresses = Eip7748Iterator::sorted_addresses(&source, None::<fn(Address)>).unwrap();
+
+        thread::scope(|s| {
+            let iter = || {
+                Eip7748Iterator::with_sorted_addresses(s, &source, &addresses[..])
+                    .with_code(CodeItems::All)
+            };
+            let expected: Vec<_> = iter().map(|item| item.unwrap()).collect();
+            assert_eq!(
+                expected.iter().filter(|item| matches!(item, C(..))).count(),
+                5
+            );
+            for position in 1..=expected.len() {
+                let mut it = iter();
+                let mut items: Vec<_> = it
+                    .by_ref()
+                    .take(position)
+                    .map(|item| item.unwrap())
+                    .collect();
+                let cursor = it.cursor().unwrap();
+                if matches!(items.last(), Some(C(..))) {
+                    assert_eq!(cursor.phase, ConversionPhase::AccountData);
+                }
+                let resumed =
+                    Eip7748Iterator::with_cursor(s, &source, cursor).with_code(CodeItems::All);
+                items.extend(resumed.map(|item| item.unwrap()));
+                assert_eq!(items, expected);
+            }
+        });
+    }
+
+    #[test]
+    fn resumes_at_the_next_account_if_the_account_was_removed() {
+        let address = Address::with_last_byte;
+        let mut source = MemoryStateSource::default();
+        for i in 1..=5 {
+            source.insert_account(address(i), Account::default());
+            source.insert_storage_slot(address(i), B256::with_last_byte(i), U256::from(1));
+        }
+        // keccak256 order of the addresses is 1, 5, 3, 4, 2.
+        let cursor = ConversionCursor {
+            account_hash: keccak256(address(3)),
+            phase: ConversionPhase::Storage(None),
+        };
+        source.accounts.remove(&address(3));
+        source.storage.remove(&address(3));
+        let items: Vec<_> = thread::scope(|s| {
+            Eip7748Iterator::with_cursor(s, &source, cursor)
+                .map(|item| item.unwrap())
+                .collect()
+        });
+        assert_eq!(
+            items,
+            vec![
+                A(address(4)),
+                S(address(4), B256::with_last_byte(4)),
+                A(address(2)),
+                S(address(2), B256::with_last_byte(2)),
+            ]
+        );
+    }
+
+    /// Source only read by hash, failing to read the accounts or storage slots by address.
+    struct HashedSource(MemoryStateSource);
+
+    impl StateSource for HashedSource {
+        fn accounts_count(&self) -> Result<u64> {
+            self.0.accounts_count()
+        }
+
+        fn accounts(&self) -> Result<AccountsIter<'_>> {
+            bail!("Accounts read by address")
+        }
+
+        fn storage(&self, _address: Address) -> Result<StorageIter<'_>> {
+            bail!("Storage slots read by address")
+        }
+
+        fn hashed_accounts(&self, start: B256) -> Result<HashedAccountsIter<'_>> {
+            self.0.hashed_accounts(start)
+        }
+
+        fn hashed_storage(
+            &self,
+            address: Address,
+            after: Option<B256>,
+        ) -> Result<HashedStorageIter<'_>> {
+            self.0.hashed_storage(address, after)
+        }
+
+        fn bytecode(&self, code_hash: B256) -> Result<Option<Bytes>> {
+            self.0.bytecode(code_hash)
+        }
+    }
+
+    #[test]
+    fn resumes_from_a_source_keyed_by_hash() {
+        let mut source = MemoryStateSource::default();
+        let code_hash = source.insert_bytecode(Bytes::from_static(&[0x00]));
+        for i in 0..50u64 {
+            let address = Address::left_padding_from(&i.to_be_bytes());
+            let account = Account {
+                code_hash: (i % 5 == 0).then_some(code_hash),
+                ..Default::default()
+            };
+            source.insert_account(address, account);
+            let slots = if i == 10 { 100 } else { i % 3 };
+            for j in 0..slots {
+                let slot = B256::left_padding_from(&(i * 100 + j).to_be_bytes());
+                source.insert_storage_slot(address, slot, U256::from(1));
+            }
+        }
+        let contract = Address::left_padding_from(&10u64.to_be_bytes());
+        let hashed = HashedSource(source.clone());
+        assert!(Eip7748Iterator::sorted_addresses(&hashed, None::<fn(Address)>).is_err());
+
+        thread::scope(|s| {
+            let expected: Vec<_> = Eip7748Iterator::new(s, &source, None::<fn(Address)>)
+                .unwrap()
+                .with_code(CodeItems::All)
+                .map(|item| item.unwrap())
+                .collect();
+            // In the middle of the storage slots of the large contract.
+            let position = expected
+                .iter()
+                .position(|item| matches!(item, S(address, _) if *address == contract))
+                .unwrap()
+                + 50;
+            let mut it = Eip7748Iterator::new(s, &source, None::<fn(Address)>)
+                .unwrap()
+                .with_code(CodeItems::All);
+            let mut items: Vec<_> = it
+                .by_ref()
+                .take(position)
+                .map(|item| item.unwrap())
+                .collect();
+            let cursor = it.cursor().unwrap();
+            assert!(matches!(cursor.phase, ConversionPhase::Storage(Some(_))));
+
+            let resumed =
+                Eip7748Iterator::with_cursor(s, &hashed, cursor).with_code(CodeItems::All);
+            items.extend(resumed.map(|item| item.unwrap()));
+            assert_eq!(items, expected);
+        });
+    }
+}
@@ -0,0 +1,275 @@
+//! Implementation of the EIP-7748 preimage access sequence iterator sorting on disk.
+//!
+//! This module provides an account and storage slot iterator with the same ordering as the EIP-7748
+//! iterator, for states that don't fit in memory. Instead of sorting the addresses in memory and looking
+//! up the storage slots of each account in hash order, it does a single sequential scan of the accounts
+//! and another one of the storage slots, sorting each of them by hash with an external merge sort:
+//! - Accounts are sorted as `keccak256(address) | address` records.
+//! - Storage slots are sorted as `keccak256(address) | keccak256(slot) | slot` records, so the storage
+//!   slots of each account come out together and in hash order.
+//! - If code items are emitted, the code hashes of the contracts are sorted as
+//!   `keccak256(address) | code_hash` records, read in the same scan as the accounts.
+//!
+//! The sorted streams are then merged, emitting each account followed by its storage slots (and code).
+
+use alloy_primitives::{keccak256, Address, B256};
+use anyhow::{anyhow, bail, Result};
+use std::iter::Peekable;
+
+use super::code::{check_bytecode, contract_code_hash, CodeItems, Codes};
+use super::{AccountStorageItem, PreimageIterator};
+use crate::extsort::{ExternalSorter, SortedRecords};
+use crate::source::StateSource;
+
+const ACCOUNT_RECORD_SIZE: usize = 32 + 20;
+const STORAGE_SLOT_RECORD_SIZE: usize = 32 + 32 + 32;
+const CODE_RECORD_SIZE: usize = 32 + 32;
+
+pub struct Eip7748ExternalIterator {
+    accounts: SortedRecords<ACCOUNT_RECORD_SIZE>,
+    storage_slots: Peekable<SortedRecords<STORAGE_SLOT_RECORD_SIZE>>,
+    code_hashes: Option<Peekable<SortedRecords<CODE_RECORD_SIZE>>>,
+    codes: Codes,
+
+    current: Option<(B256, Address)>,
+    done: bool,
+}
+
+impl PreimageIterator for Eip7748ExternalIterator {}
+
+impl Eip7748ExternalIterator {
+    /// Sorts the accounts and storage slots of `source` using at most `max_memory` bytes of memory,
+    /// spilling to a temporary directory in `TMPDIR`. The code hashes of the contracts are sorted too if
+    /// `code` items are emitted, see [`super::code`], so they must be known before sorting. `progress` is
+    /// called with the scanned addresses, first while scanning the accounts and then again while
+    /// scanning the storage slots.
+    pub fn new<S, P>(
+        source: &S,
+        max_memory: usize,
+        code: CodeItems,
+        mut progress: Option<P>,
+    ) -> Result<Self>
+    where
+        S: StateSource,
+        P: FnMut(Address),
+    {
+        // The sorted accounts (and code hashes) may stay in memory while the storage slots are sorted.
+        let max_memory = max_memory / 2;
+        let codes = Codes::new(code);
+        let (accounts_memory, mut code_hashes) = match codes.enabled() {
+            true => (
+                max_memory / 2,
+                Some(ExternalSorter::<CODE_RECORD_SIZE>::new(max_memory / 2)),
+            ),
+            false => (max_memory, None),
+        };
+
+        let mut accounts = ExternalSorter::<ACCOUNT_RECORD_SIZE>::new(accounts_memory);
+        for entry in source.accounts()? {
+            let (address, account) = entry?;
+            let hashed_address = keccak256(address);
+            let mut record = [0u8; ACCOUNT_RECORD_SIZE];
+            record[..32].copy_from_slice(hashed_address.as_slice());
+            record[32..].copy_from_slice(address.as_slice());
+            accounts.push(record)?;
+            if let (Some(code_hashes), Some(code_hash)) =
+                (&mut code_hashes, contract_code_hash(account.code_hash))
+            {
+                check_bytecode(source, address, code_hash)?;
+                let mut record = [0u8; CODE_RECORD_SIZE];
+                record[..32].copy_from_slice(hashed_address.as_slice());
+                record[32..].copy_from_slice(code_hash.as_slice());
+                code_hashes.push(record)?;
+            }
+            if let Some(ref mut progress) = progress {
+                progress(address);
+            }
+        }
+        let accounts = accounts.finish()?;
+        let code_hashes = code_hashes
+            .map(|code_hashes| code_hashes.finish().map(Iterator::peekable))
+            .transpose()?;
+
+        let mut storage_slots = ExternalSorter::<STORAGE_SLOT_RECORD_SIZE>::new(max_memory);
+        let mut current: Option<(Address, B256)> = None;
+        for entry in source.storage_slots()? {
+            let (address, slot, _) = entry?;
+            let hashed_address = match current {
+                Some((current_address, hashed_address)) if current_address == address => {
+                    hashed_address
+                }
+                _ => {
+                    if let Some(ref mut progress) = progress {
+                        progress(address);
+                    }
+                    let hashed_address = keccak256(address);
+                    current = Some((address, hashed_address));
+                    hashed_address
+                }
+            };
+            let mut record = [0u8; STORAGE_SLOT_RECORD_SIZE];
+            record[..32].copy_from_slice(hashed_address.as_slice());
+            record[32..64].copy_from_slice(keccak256(slot).as_slice());
+            record[64..].copy_from_slice(slot.as_slice());
+            storage_slots.push(record)?;
+        }
+
+        Ok(Eip7748ExternalIterator {
+            accounts,
+            storage_slots: storage_slots.finish()?.peekable(),
+            code_hashes,
+            codes,
+            current: None,
+            done: false,
+        })
+    }
+
+    /// Returns the code hash of the code item of the account with `hashed_address`, if any.
+    fn code_hash(&mut self, hashed_address: B256) -> Result<Option<B256>> {
+        let Some(code_hashes) = &mut self.code_hashes else {
+            return Ok(None);
+        };
+        let code_hash = match code_hashes.next_if(|entry| {
+            entry
+                .as_ref()
+                .map_or(true, |record| record[..32] == hashed_address[..])
+        }) {
+            Some(record) => Some(B256::from_slice(&record?[32..])),
+            None => None,
+        };
+        if let Some(Ok(record)) = code_hashes.peek() {
+            if record[..32] < hashed_address[..] {
+                bail!(
+                    "Code hash of missing account with hash {}",
+                    B256::from_slice(&record[..32])
+                );
+            }
+        }
+        Ok(self.codes.code_hash(code_hash))
+    }
+
+    fn fail(&mut self, e: anyhow::Error) -> Option<Result<AccountStorageItem>> {
+        self.done = true;
+        Some(Err(e))
+    }
+}
+
+impl Iterator for Eip7748ExternalIterator {
+    type Item = Result<AccountStorageItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if let Some((hashed_address, address)) = self.current {
+            let next = self.storage_slots.next_if(|entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |record| record[..32] == hashed_address[..])
+            });
+            match next {
+                Some(Ok(record)) => {
+                    let slot = B256::from_slice(&record[64..]);
+                    return Some(Ok(AccountStorageItem::StorageSlot(address, slot)));
+                }
+                Some(Err(e)) => return self.fail(e),
+                None => {
+                    self.current = None;
+                    match self.code_hash(hashed_address) {
+                        Ok(Some(code_hash)) => {
+                            return Some(Ok(AccountStorageItem::Code(address, code_hash)))
+                        }
+                        Ok(None) => {}
+                        Err(e) => return self.fail(e),
+                    }
+                }
+            }
+        }
+
+        match self.accounts.next() {
+            Some(Ok(record)) => {
+                let hashed_address = B256::from_slice(&record[..32]);
+                let address = Address::from_slice(&record[32..]);
+                if let Some(Ok(slot_record)) = self.storage_slots.peek() {
+                    if slot_record[..32] < hashed_address[..] {
+                        let orphan = B256::from_slice(&slot_record[..32]);
+                        return self.fail(anyhow!(
+                            "Storage slots of missing account with hash {}",
+                            orphan
+                        ));
+                    }
+                }
+                self.current = Some((hashed_address, address));
+                Some(Ok(AccountStorageItem::Account(address)))
+            }
+            Some(Err(e)) => self.fail(e),
+            None => {
+                self.done = true;
+                match self.storage_slots.next() {
+                    Some(Ok(slot_record)) => Some(Err(anyhow!(
+                        "Storage slots of missing account with hash {}",
+                        B256::from_slice(&slot_record[..32])
+                    ))),
+                    Some(Err(e)) => Some(Err(e)),
+                    None => None,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::U256;
+    use std::thread;
+
+    use super::*;
+    use crate::iterators::eip7748::Eip7748Iterator;
+    use crate::source::{memory::MemoryStateSource, Account};
+
+    #[test]
+    fn matches_in_memory_ordering() {
+        let mut source = MemoryStateSource::default();
+        for i in 0..300u64 {
+            let address = Address::left_padding_from(&i.to_be_bytes());
+            source.insert_account(address, Account::default());
+            for j in 0..i % 5 {
+                let slot = B256::left_padding_from(&(i * 31 + j).to_be_bytes());
+                source.insert_storage_slot(address, slot, U256::from(1));
+            }
+        }
+        let expected: Vec<_> = thread::scope(|s| {
+            Eip7748Iterator::new(s, &source, None::<fn(Address)>)
+                .unwrap()
+                .map(|item| item.unwrap())
+                .collect()
+        });
+
+        // From everything in memory to a few records per sorted run.
+        for max_memory in [1 << 20, 2 * 10 * STORAGE_SLOT_RECORD_SIZE] {
+            let items: Vec<_> = Eip7748ExternalIterator::new(
+                &source,
+                max_memory,
+                CodeItems::Skip,
+                None::<fn(Address)>,
+            )
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+            assert_eq!(items, expected);
+        }
+    }
+
+    #[test]
+    fn fails_on_storage_slots_without_account() {
+        let mut source = MemoryStateSource::default();
+        source.insert_account(Address::with_last_byte(1), Account::default());
+        source.insert_storage_slot(Address::with_last_byte(2), B256::ZERO, U256::from(1));
+
+        let result: Result<Vec<_>> =
+            Eip7748ExternalIterator::new(&source, 1 << 20, CodeItems::Skip, None::<fn(Address)>)
+                .unwrap()
+                .collect();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,29 @@
+//! Multiple iterator implementators to dump the preimages in different orders
+//!
+//! This crate provides different implementations of the preimage iterator:
+//! - EIP-7748: The iterator respects the order defined in EIP-7748.
+//! - EIP-7748 external: Same ordering, sorted on disk with a bounded amount of memory.
+//! - Plain: The iterator respects the plain ordering in the database.
+//!
+//! Any of them can emit the code hash of each contract after its storage slots, see [`code`].
+//!
+//! See each module docs for more information.
+
+use alloy_primitives::{Address, B256};
+use anyhow::Result;
+
+pub mod code;
+pub mod eip7748;
+pub mod eip7748_external;
+pub mod plain;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountStorageItem {
+    Account(Address),
+    StorageSlot(Address, B256),
+    /// Code hash of a contract, after its storage slots. Only emitted with [`code::CodeItems`].
+    Code(Address, B256),
+}
+pub trait PreimageIterator: Iterator<Item = Result<AccountStorageItem>> {}
+
+impl<I: PreimageIterator + ?Sized> PreimageIterator for Box<I> {}
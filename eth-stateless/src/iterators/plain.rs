@@ -0,0 +1,184 @@
+//! Implementation of the plain preimage access sequence iterator.
+//!
+//! This module provides an account and storage slot iterator respecting the plain ordering of the state.
+//! The ordering can be summarized as:
+//! 1. Iterate the account sorted by address.
+//! 2. For each account, iterate over the sorted storage slots.
+//!
+//! No actual sorting is required since the state source returns both addresses and storage slots sorted.
+//!
+//! Sample output: [account1, account1_ss0, account1_ss1, account2, account3, account3_ss0, ...]
+
+use alloy_primitives::{Address, B256};
+use anyhow::Result;
+
+use super::code::{check_bytecode, CodeItems, Codes};
+use super::{AccountStorageItem, PreimageIterator};
+use crate::source::{AccountsIter, StateSource, StorageIter};
+
+pub struct PlainIterator<'a, S: ?Sized> {
+    source: &'a S,
+    accounts: AccountsIter<'a>,
+    codes: Codes,
+
+    state: State,
+    storage_slots: Option<StorageIter<'a>>,
+}
+
+enum State {
+    Account,
+    /// Storage slots of the account with the address and code hash.
+    StorageSlot(Address, Option<B256>),
+    End,
+}
+
+impl<'a, S: StateSource + ?Sized> PlainIterator<'a, S> {
+    pub fn new(source: &'a S) -> Result<Self> {
+        Self::with_accounts(source, source.accounts()?)
+    }
+
+    /// Returns the iterator of the accounts with an address in `[start, end)`, see
+    /// [`StateSource::accounts_range`].
+    pub fn range(source: &'a S, start: Address, end: Option<Address>) -> Result<Self> {
+        Self::with_accounts(source, source.accounts_range(start, end)?)
+    }
+
+    fn with_accounts(source: &'a S, accounts: AccountsIter<'a>) -> Result<Self> {
+        Ok(PlainIterator {
+            source,
+            accounts,
+            codes: Codes::new(CodeItems::Skip),
+            state: State::Account,
+            storage_slots: None,
+        })
+    }
+
+    /// Returns the iterator emitting `code` items after the storage slots of each contract, see
+    /// [`super::code`].
+    pub fn with_code(mut self, code: CodeItems) -> Self {
+        self.codes = Codes::new(code);
+        self
+    }
+}
+
+impl<S: StateSource + ?Sized> PreimageIterator for PlainIterator<'_, S> {}
+
+impl<S: StateSource + ?Sized> Iterator for PlainIterator<'_, S> {
+    type Item = Result<AccountStorageItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.state {
+            State::Account => match self.accounts.next() {
+                Some(Ok((address, account))) => {
+                    self.state = State::StorageSlot(address, account.code_hash);
+                    Some(Ok(AccountStorageItem::Account(address)))
+                }
+                Some(Err(e)) => {
+                    self.state = State::End;
+                    Some(Err(e))
+                }
+                None => {
+                    self.state = State::End;
+                    None
+                }
+            },
+            State::StorageSlot(address, code_hash) => {
+                if self.storage_slots.is_none() {
+                    match self.source.storage(address) {
+                        Ok(storage_slots) => self.storage_slots = Some(storage_slots),
+                        Err(e) => {
+                            self.state = State::End;
+                            return Some(Err(e));
+                        }
+                    }
+                }
+                let storage_slots = self.storage_slots.as_mut()?;
+
+                match storage_slots.next() {
+                    Some(Ok((key, _))) => Some(Ok(AccountStorageItem::StorageSlot(address, key))),
+                    Some(Err(e)) => {
+                        self.state = State::End;
+                        Some(Err(e))
+                    }
+                    None => {
+                        self.storage_slots = None;
+                        self.state = State::Account;
+                        let Some(code_hash) = self.codes.code_hash(code_hash) else {
+                            return self.next();
+                        };
+                        if let Err(e) = check_bytecode(self.source, address, code_hash) {
+                            self.state = State::End;
+                            return Some(Err(e));
+                        }
+                        Some(Ok(AccountStorageItem::Code(address, code_hash)))
+                    }
+                }
+            }
+            State::End => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{B256, U256};
+
+    use super::*;
+    use crate::source::{memory::MemoryStateSource, Account};
+    use AccountStorageItem::{Account as A, StorageSlot as S};
+
+    #[test]
+    fn orders_accounts_and_storage_slots_by_preimage() {
+        let address = Address::with_last_byte;
+        let slot = B256::with_last_byte;
+        let mut source = MemoryStateSource::default();
+        for i in [3, 1, 2] {
+            source.insert_account(address(i), Account::default());
+        }
+        for i in [4, 2, 3] {
+            source.insert_storage_slot(address(2), slot(i), U256::from(i));
+        }
+        source.insert_storage_slot(address(3), slot(1), U256::from(1));
+
+        let items: Vec<_> = PlainIterator::new(&source)
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                A(address(1)),
+                A(address(2)),
+                S(address(2), slot(2)),
+                S(address(2), slot(3)),
+                S(address(2), slot(4)),
+                A(address(3)),
+                S(address(3), slot(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn iterates_address_range() {
+        let address = Address::with_last_byte;
+        let mut source = MemoryStateSource::default();
+        for i in 1..=4 {
+            source.insert_account(address(i), Account::default());
+            source.insert_storage_slot(address(i), B256::with_last_byte(i), U256::from(i));
+        }
+
+        let items: Vec<_> = PlainIterator::range(&source, address(2), Some(address(4)))
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                A(address(2)),
+                S(address(2), B256::with_last_byte(2)),
+                A(address(3)),
+                S(address(3), B256::with_last_byte(3)),
+            ]
+        );
+    }
+}
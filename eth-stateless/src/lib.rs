@@ -0,0 +1,20 @@
+//! Shared building blocks for the Ethereum stateless tooling.
+//!
+//! - [`StateSource`] gives read access to a state, e.g: a Reth datadir opened with [`RethStateSource`].
+//! - [`iterators`] provides the account and storage slot preimage iterators over a state source.
+//! - [`extsort`] provides the external merge sort used to order large states with bounded memory.
+//! - [`progress`] provides the address based progress bar used by the long running commands.
+//! - [`trie`] computes the state root of a state from its preimages.
+
+pub mod extsort;
+pub mod iterators;
+pub mod progress;
+pub mod source;
+pub mod trie;
+
+pub use source::{
+    json::JsonStateSource,
+    memory::MemoryStateSource,
+    reth::{chain_spec, RethStateSource},
+    Account, Preimages, StateSource,
+};
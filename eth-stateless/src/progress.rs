@@ -19,6 +19,14 @@ impl AddressProgressBar {
         }
     }
 
+    /// Creates a progress bar that isn't drawn, e.g: for tasks running alongside other progress output.
+    pub fn hidden() -> Self {
+        Self {
+            inner: ProgressBar::hidden(),
+            hash_on_progress: false,
+        }
+    }
+
     pub fn progress(&mut self, addr: Address) {
         let hashed_addr = keccak256(addr);
         let progress_val = if self.hash_on_progress {
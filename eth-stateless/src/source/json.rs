@@ -0,0 +1,338 @@
+//! JSON state dump source.
+//!
+//! The whole dump is loaded in memory, so this is meant for devnet snapshots and test fixtures. The
+//! supported formats are detected automatically:
+//! - Genesis: a genesis file with an `alloc` field, or the `alloc` object itself.
+//! - `debug_dumpBlock`: an object with the `root` and `accounts` fields, optionally wrapped in a JSON-RPC
+//!   response.
+//! - geth `dump`: JSON lines, each with one account and its `address`, after an optional `root` line.
+//!
+//! Balances and nonces can be hex or decimal, storage values are always hex. Accounts (or storage slots) whose preimage is missing from
+//! the dump, e.g. `pre(0x...)` keys, are rejected since the preimages can't be recovered.
+
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{Map, Value};
+use std::str::FromStr;
+
+use super::{
+    memory::MemoryStateSource, Account, AccountsIter, StateSource, StorageIter, StorageSlotsIter,
+};
+
+pub struct JsonStateSource {
+    /// State root of the dump, if it has one.
+    pub state_root: Option<B256>,
+
+    state: MemoryStateSource,
+}
+
+impl JsonStateSource {
+    pub fn open(path: &str) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("reading state dump {}", path))?;
+        Self::parse(&data).with_context(|| format!("parsing state dump {}", path))
+    }
+
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut source = JsonStateSource {
+            state_root: None,
+            state: MemoryStateSource::default(),
+        };
+        let Ok(value) = serde_json::from_str::<Value>(data) else {
+            // geth `dump` output, one JSON object per line.
+            for (i, line) in data.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let value: Value = serde_json::from_str(line)
+                    .with_context(|| format!("invalid JSON at line {}", i + 1))?;
+                source
+                    .add_dump_line(&value)
+                    .with_context(|| format!("line {}", i + 1))?;
+            }
+            return Ok(source);
+        };
+
+        let object = as_object(&value)?;
+        let object = match object.get("result") {
+            Some(result) => as_object(result)?,
+            None => object,
+        };
+        if let Some(alloc) = object.get("alloc") {
+            source.add_accounts(as_object(alloc)?)?;
+        } else if object.contains_key("accounts") || object.contains_key("root") {
+            source.state_root = object.get("root").map(parse_b256).transpose()?;
+            if let Some(accounts) = object.get("accounts") {
+                source.add_accounts(as_object(accounts)?)?;
+            }
+        } else if object.contains_key("address") {
+            // A single geth `dump` line.
+            source.add_dump_line(&value)?;
+        } else {
+            source.add_accounts(object)?;
+        }
+        Ok(source)
+    }
+
+    fn add_dump_line(&mut self, value: &Value) -> Result<()> {
+        let object = as_object(value)?;
+        match object.get("address") {
+            Some(address) => {
+                let address = address
+                    .as_str()
+                    .ok_or(anyhow!("Invalid address {}", address))?;
+                self.add_account(address, object)
+            }
+            None if object.contains_key("key") => {
+                Err(anyhow!("Account {} has no address preimage", object["key"]))
+            }
+            None => {
+                self.state_root = object.get("root").map(parse_b256).transpose()?;
+                Ok(())
+            }
+        }
+    }
+
+    fn add_accounts(&mut self, accounts: &Map<String, Value>) -> Result<()> {
+        for (address, account) in accounts {
+            self.add_account(address, as_object(account)?)?;
+        }
+        Ok(())
+    }
+
+    fn add_account(&mut self, address: &str, account: &Map<String, Value>) -> Result<()> {
+        let address = Address::from_str(address)
+            .map_err(|_| anyhow!("Account {} has no address preimage", address))?;
+        let code = account
+            .get("code")
+            .map(|code| {
+                let code = code.as_str().ok_or(anyhow!("Invalid code {}", code))?;
+                Bytes::from_str(code).map_err(|err| anyhow!("Invalid code: {}", err))
+            })
+            .transpose()?
+            .unwrap_or_default();
+        let code_hash = if !code.is_empty() {
+            Some(self.state.insert_bytecode(code))
+        } else {
+            account
+                .get("codeHash")
+                .map(parse_b256)
+                .transpose()?
+                .filter(|code_hash| *code_hash != keccak256([]))
+        };
+        self.state.insert_account(
+            address,
+            Account {
+                nonce: account
+                    .get("nonce")
+                    .map(parse_u256)
+                    .transpose()?
+                    .unwrap_or_default()
+                    .try_into()
+                    .map_err(|_| anyhow!("Account {} nonce overflow", address))?,
+                balance: account
+                    .get("balance")
+                    .map(parse_u256)
+                    .transpose()?
+                    .unwrap_or_default(),
+                code_hash,
+            },
+        );
+
+        if let Some(storage) = account.get("storage") {
+            for (slot, value) in as_object(storage)? {
+                let slot = B256::from(
+                    U256::from_str(slot)
+                        .map_err(|_| anyhow!("Storage slot {} has no preimage", slot))?,
+                );
+                self.state
+                    .insert_storage_slot(address, slot, parse_storage_value(value)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StateSource for JsonStateSource {
+    fn accounts_count(&self) -> Result<u64> {
+        self.state.accounts_count()
+    }
+
+    fn accounts(&self) -> Result<AccountsIter<'_>> {
+        self.state.accounts()
+    }
+
+    fn accounts_range(&self, start: Address, end: Option<Address>) -> Result<AccountsIter<'_>> {
+        self.state.accounts_range(start, end)
+    }
+
+    fn account(&self, address: Address) -> Result<Option<Account>> {
+        self.state.account(address)
+    }
+
+    fn storage(&self, address: Address) -> Result<StorageIter<'_>> {
+        self.state.storage(address)
+    }
+
+    fn storage_value(&self, address: Address, slot: B256) -> Result<Option<U256>> {
+        self.state.storage_value(address, slot)
+    }
+
+    fn storage_slots(&self) -> Result<StorageSlotsIter<'_>> {
+        self.state.storage_slots()
+    }
+
+    fn bytecode(&self, code_hash: B256) -> Result<Option<Bytes>> {
+        self.state.bytecode(code_hash)
+    }
+}
+
+fn as_object(value: &Value) -> Result<&Map<String, Value>> {
+    value
+        .as_object()
+        .ok_or(anyhow!("Expected a JSON object, got {}", value))
+}
+
+fn parse_u256(value: &Value) -> Result<U256> {
+    match value {
+        Value::String(s) => U256::from_str(s).map_err(|_| anyhow!("Invalid number {}", s)),
+        Value::Number(n) => n
+            .as_u64()
+            .map(U256::from)
+            .ok_or(anyhow!("Invalid number {}", n)),
+        _ => bail!("Invalid number {}", value),
+    }
+}
+
+/// Parses a storage value, which is always hex but without `0x` prefix in geth dumps.
+fn parse_storage_value(value: &Value) -> Result<U256> {
+    let s = value
+        .as_str()
+        .ok_or(anyhow!("Invalid storage value {}", value))?;
+    U256::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16)
+        .map_err(|_| anyhow!("Invalid storage value {}", s))
+}
+
+fn parse_b256(value: &Value) -> Result<B256> {
+    value
+        .as_str()
+        .and_then(|s| B256::from_str(s).ok())
+        .ok_or(anyhow!("Invalid hash {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(source: &JsonStateSource, address: Address) -> Vec<(B256, U256)> {
+        source
+            .storage(address)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parses_genesis_alloc() {
+        let source = JsonStateSource::parse(
+            r#"{
+                "config": {},
+                "alloc": {
+                    "0x0000000000000000000000000000000000000002": {"balance": "0x10"},
+                    "0x0000000000000000000000000000000000000001": {
+                        "balance": "1000",
+                        "nonce": "0x2",
+                        "code": "0x6000",
+                        "storage": {
+                            "0x01": "0x0000000000000000000000000000000000000000000000000000000000000005",
+                            "0x02": "0x00"
+                        }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let accounts: Vec<_> = source.accounts().unwrap().map(|a| a.unwrap()).collect();
+        assert_eq!(accounts.len(), 2);
+        let (address, account) = accounts[0];
+        assert_eq!(address, Address::with_last_byte(1));
+        assert_eq!(account.nonce, 2);
+        assert_eq!(account.balance, U256::from(1000));
+        let code_hash = account.code_hash.unwrap();
+        assert_eq!(
+            source.bytecode(code_hash).unwrap().unwrap(),
+            Bytes::from_static(&[0x60, 0x00])
+        );
+        assert_eq!(
+            storage(&source, address),
+            vec![(B256::with_last_byte(1), U256::from(5))]
+        );
+        assert_eq!(accounts[1].1.balance, U256::from(16));
+        assert_eq!(accounts[1].1.code_hash, None);
+        assert_eq!(source.state_root, None);
+    }
+
+    #[test]
+    fn parses_dump_block() {
+        let source = JsonStateSource::parse(
+            r#"{"jsonrpc": "2.0", "id": 1, "result": {
+                "root": "0x1111111111111111111111111111111111111111111111111111111111111111",
+                "accounts": {
+                    "0x0000000000000000000000000000000000000003": {
+                        "balance": "7",
+                        "nonce": 1,
+                        "root": "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+                        "codeHash": "0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+                        "storage": {
+                            "0x0000000000000000000000000000000000000000000000000000000000000009": "ff"
+                        }
+                    }
+                }
+            }}"#,
+        )
+        .unwrap();
+
+        assert_eq!(source.state_root, Some(B256::repeat_byte(0x11)));
+        let address = Address::with_last_byte(3);
+        let accounts: Vec<_> = source.accounts().unwrap().map(|a| a.unwrap()).collect();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].0, address);
+        assert_eq!(accounts[0].1.code_hash, None);
+        assert_eq!(
+            storage(&source, address),
+            vec![(B256::with_last_byte(9), U256::from(255))]
+        );
+    }
+
+    #[test]
+    fn parses_geth_dump_lines() {
+        let source = JsonStateSource::parse(concat!(
+            r#"{"root": "0x2222222222222222222222222222222222222222222222222222222222222222"}"#,
+            "\n",
+            r#"{"balance": "1", "nonce": 0, "address": "0x0000000000000000000000000000000000000005", "key": "0x00"}"#,
+            "\n",
+            r#"{"balance": "2", "nonce": 3, "address": "0x0000000000000000000000000000000000000004", "key": "0x00"}"#,
+            "\n",
+        ))
+        .unwrap();
+
+        assert_eq!(source.state_root, Some(B256::repeat_byte(0x22)));
+        let addresses: Vec<_> = source.accounts().unwrap().map(|a| a.unwrap().0).collect();
+        assert_eq!(
+            addresses,
+            vec![Address::with_last_byte(4), Address::with_last_byte(5)]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_preimages() {
+        let err = JsonStateSource::parse(
+            r#"{"root": "0x2222222222222222222222222222222222222222222222222222222222222222",
+                "accounts": {"pre(0x1234)": {"balance": "1", "nonce": 0}}}"#,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("no address preimage"));
+    }
+}
@@ -0,0 +1,97 @@
+//! In-memory state source.
+
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+use super::{Account, AccountsIter, StateSource, StorageIter, StorageSlotsIter};
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStateSource {
+    pub accounts: BTreeMap<Address, Account>,
+    pub storage: BTreeMap<Address, BTreeMap<B256, U256>>,
+    pub bytecodes: HashMap<B256, Bytes>,
+}
+
+impl MemoryStateSource {
+    pub fn insert_account(&mut self, address: Address, account: Account) {
+        self.accounts.insert(address, account);
+    }
+
+    /// Sets the value of a storage slot, removing it if `value` is zero.
+    pub fn insert_storage_slot(&mut self, address: Address, slot: B256, value: U256) {
+        let storage = self.storage.entry(address).or_default();
+        if value.is_zero() {
+            storage.remove(&slot);
+        } else {
+            storage.insert(slot, value);
+        }
+    }
+
+    /// Stores `code` and returns its hash.
+    pub fn insert_bytecode(&mut self, code: Bytes) -> B256 {
+        let code_hash = keccak256(&code);
+        self.bytecodes.insert(code_hash, code);
+        code_hash
+    }
+}
+
+impl StateSource for MemoryStateSource {
+    fn accounts_count(&self) -> Result<u64> {
+        Ok(self.accounts.len() as u64)
+    }
+
+    fn accounts(&self) -> Result<AccountsIter<'_>> {
+        Ok(Box::new(
+            self.accounts
+                .iter()
+                .map(|(address, account)| Ok((*address, *account))),
+        ))
+    }
+
+    fn accounts_range(&self, start: Address, end: Option<Address>) -> Result<AccountsIter<'_>> {
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(Box::new(
+            self.accounts
+                .range((Bound::Included(start), end))
+                .map(|(address, account)| Ok((*address, *account))),
+        ))
+    }
+
+    fn account(&self, address: Address) -> Result<Option<Account>> {
+        Ok(self.accounts.get(&address).copied())
+    }
+
+    fn storage(&self, address: Address) -> Result<StorageIter<'_>> {
+        Ok(Box::new(
+            self.storage
+                .get(&address)
+                .into_iter()
+                .flatten()
+                .map(|(slot, value)| Ok((*slot, *value))),
+        ))
+    }
+
+    fn storage_value(&self, address: Address, slot: B256) -> Result<Option<U256>> {
+        Ok(self
+            .storage
+            .get(&address)
+            .and_then(|storage| storage.get(&slot))
+            .copied())
+    }
+
+    fn storage_slots(&self) -> Result<StorageSlotsIter<'_>> {
+        Ok(Box::new(self.storage.iter().flat_map(
+            |(address, storage)| {
+                storage
+                    .iter()
+                    .map(|(slot, value)| Ok((*address, *slot, *value)))
+            },
+        )))
+    }
+
+    fn bytecode(&self, code_hash: B256) -> Result<Option<Bytes>> {
+        Ok(self.bytecodes.get(&code_hash).cloned())
+    }
+}
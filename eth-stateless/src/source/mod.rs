@@ -0,0 +1,217 @@
+//! State sources the preimage iterators and the analysis tools read from.
+//!
+//! This module provides the [`StateSource`] trait and its implementations:
+//! - Reth: The state of a Reth datadir, read from the MDBX plain state tables, and from the hashed state
+//!   ones for the [`StateSource::hashed_accounts`] seeks if it has [`Preimages`].
+//! - Memory: A `BTreeMap` backed state, mostly useful to build synthetic states in tests.
+//! - JSON: A genesis `alloc`, geth `dump` or `debug_dumpBlock` state dump, loaded in memory.
+
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
+use anyhow::Result;
+use rayon::slice::ParallelSliceMut;
+
+pub mod json;
+pub mod memory;
+pub mod reth;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Account {
+    pub nonce: u64,
+    pub balance: U256,
+    /// Bytecode hash, or `None` for accounts without code.
+    pub code_hash: Option<B256>,
+}
+
+pub type AccountsIter<'a> = Box<dyn Iterator<Item = Result<(Address, Account)>> + 'a>;
+pub type StorageIter<'a> = Box<dyn Iterator<Item = Result<(B256, U256)>> + 'a>;
+pub type StorageSlotsIter<'a> = Box<dyn Iterator<Item = Result<(Address, B256, U256)>> + 'a>;
+pub type HashedAccountsIter<'a> = Box<dyn Iterator<Item = Result<(B256, Address, Account)>> + 'a>;
+pub type HashedStorageIter<'a> = Box<dyn Iterator<Item = Result<(B256, B256)>> + 'a>;
+
+/// Preimages of the hashed addresses and storage slots, for sources keyed by hash, e.g: an indexed
+/// preimage file.
+pub trait Preimages: Send + Sync {
+    /// Returns the address whose keccak256 is `hash`, if known.
+    fn address(&self, hash: B256) -> Result<Option<Address>>;
+
+    /// Returns the storage slot whose keccak256 is `hash`, if known.
+    fn storage_slot(&self, hash: B256) -> Result<Option<B256>>;
+}
+
+/// Read access to an Ethereum state.
+///
+/// Sources are `Sync` so iterators can read them from several threads, see [`StateSource::view`].
+pub trait StateSource: Sync {
+    /// Returns the number of accounts in the state.
+    fn accounts_count(&self) -> Result<u64>;
+
+    /// Returns the accounts sorted by address.
+    fn accounts(&self) -> Result<AccountsIter<'_>>;
+
+    /// Returns the accounts with an address in `[start, end)`, sorted by address. A missing `end` means
+    /// up to the last account.
+    fn accounts_range(&self, start: Address, end: Option<Address>) -> Result<AccountsIter<'_>> {
+        let accounts = self.accounts()?;
+        Ok(Box::new(
+            accounts
+                .skip_while(move |entry| matches!(entry, Ok((address, _)) if *address < start))
+                .take_while(move |entry| match (entry, end) {
+                    (Ok((address, _)), Some(end)) => *address < end,
+                    _ => true,
+                }),
+        ))
+    }
+
+    /// Returns the account at `address`, if any.
+    fn account(&self, address: Address) -> Result<Option<Account>> {
+        let entry = self.accounts_range(address, None)?.next().transpose()?;
+        Ok(entry
+            .filter(|(found, _)| *found == address)
+            .map(|(_, account)| account))
+    }
+
+    /// Returns the non-zero storage slots of `address` and their values, sorted by slot.
+    fn storage(&self, address: Address) -> Result<StorageIter<'_>>;
+
+    /// Returns the value of the storage slot `slot` of `address`, or `None` if it's zero.
+    fn storage_value(&self, address: Address, slot: B256) -> Result<Option<U256>> {
+        for entry in self.storage(address)? {
+            let (found, value) = entry?;
+            if found >= slot {
+                return Ok(Some(value).filter(|_| found == slot));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the non-zero storage slots of all the accounts and their values, sorted by address and
+    /// slot. Sources should override it when a single sequential scan is cheaper than an account by
+    /// account lookup.
+    fn storage_slots(&self) -> Result<StorageSlotsIter<'_>> {
+        let accounts = self.accounts()?;
+        Ok(Box::new(accounts.flat_map(
+            move |entry| -> StorageSlotsIter<'_> {
+                let storage = entry.and_then(|(address, _)| Ok((address, self.storage(address)?)));
+                match storage {
+                    Ok((address, storage)) => Box::new(
+                        storage.map(move |entry| entry.map(|(slot, value)| (address, slot, value))),
+                    ),
+                    Err(e) => Box::new(std::iter::once(Err(e))),
+                }
+            },
+        )))
+    }
+
+    /// Returns the accounts with a hashed address from `start`, sorted by hashed address, along with it.
+    /// Sources keyed by hash should override it, since the default implementation hashes and sorts all
+    /// the accounts, see [`sorted_hashed_accounts`].
+    fn hashed_accounts(&self, start: B256) -> Result<HashedAccountsIter<'_>> {
+        sorted_hashed_accounts(self, start)
+    }
+
+    /// Returns the hashes of the non-zero storage slots of `address` after `after` (all of them if
+    /// `None`), sorted, along with the storage slots. Sources keyed by hash should override it, see
+    /// [`sorted_hashed_storage`].
+    fn hashed_storage(
+        &self,
+        address: Address,
+        after: Option<B256>,
+    ) -> Result<HashedStorageIter<'_>> {
+        sorted_hashed_storage(self, address, after)
+    }
+
+    /// Returns the bytecode with hash `code_hash`, if any.
+    fn bytecode(&self, code_hash: B256) -> Result<Option<Bytes>>;
+
+    /// Returns a view of the same state to read it concurrently from another thread, e.g: with its own
+    /// database transaction.
+    fn view(&self) -> Result<Box<dyn StateSource + '_>> {
+        Ok(Box::new(self))
+    }
+}
+
+impl<T: StateSource + ?Sized> StateSource for &T {
+    fn accounts_count(&self) -> Result<u64> {
+        (**self).accounts_count()
+    }
+
+    fn accounts(&self) -> Result<AccountsIter<'_>> {
+        (**self).accounts()
+    }
+
+    fn accounts_range(&self, start: Address, end: Option<Address>) -> Result<AccountsIter<'_>> {
+        (**self).accounts_range(start, end)
+    }
+
+    fn account(&self, address: Address) -> Result<Option<Account>> {
+        (**self).account(address)
+    }
+
+    fn storage(&self, address: Address) -> Result<StorageIter<'_>> {
+        (**self).storage(address)
+    }
+
+    fn storage_value(&self, address: Address, slot: B256) -> Result<Option<U256>> {
+        (**self).storage_value(address, slot)
+    }
+
+    fn storage_slots(&self) -> Result<StorageSlotsIter<'_>> {
+        (**self).storage_slots()
+    }
+
+    fn hashed_accounts(&self, start: B256) -> Result<HashedAccountsIter<'_>> {
+        (**self).hashed_accounts(start)
+    }
+
+    fn hashed_storage(
+        &self,
+        address: Address,
+        after: Option<B256>,
+    ) -> Result<HashedStorageIter<'_>> {
+        (**self).hashed_storage(address, after)
+    }
+
+    fn bytecode(&self, code_hash: B256) -> Result<Option<Bytes>> {
+        (**self).bytecode(code_hash)
+    }
+
+    fn view(&self) -> Result<Box<dyn StateSource + '_>> {
+        (**self).view()
+    }
+}
+
+/// Returns the accounts of `source` with a hashed address from `start`, hashing and sorting all of them in
+/// memory.
+pub fn sorted_hashed_accounts<S: StateSource + ?Sized>(
+    source: &S,
+    start: B256,
+) -> Result<HashedAccountsIter<'static>> {
+    let mut accounts = Vec::new();
+    for entry in source.accounts()? {
+        let (address, account) = entry?;
+        let hashed_address = keccak256(address);
+        if hashed_address >= start {
+            accounts.push((hashed_address, address, account));
+        }
+    }
+    accounts.par_sort_unstable_by_key(|(hashed_address, ..)| *hashed_address);
+    Ok(Box::new(accounts.into_iter().map(Ok)))
+}
+
+/// Returns the storage slots of `address` after `after`, hashing and sorting all of them in memory.
+pub fn sorted_hashed_storage<S: StateSource + ?Sized>(
+    source: &S,
+    address: Address,
+    after: Option<B256>,
+) -> Result<HashedStorageIter<'static>> {
+    let mut storage_slots = Vec::new();
+    for entry in source.storage(address)? {
+        let (slot, _) = entry?;
+        let hashed_slot = keccak256(slot);
+        if after.is_none_or(|after| hashed_slot > after) {
+            storage_slots.push((hashed_slot, slot));
+        }
+    }
+    storage_slots.par_sort_unstable_by_key(|(hashed_slot, _)| *hashed_slot);
+    Ok(Box::new(storage_slots.into_iter().map(Ok)))
+}
@@ -0,0 +1,308 @@
+//! Reth datadir state source.
+
+use alloy_genesis::Genesis;
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
+use anyhow::{anyhow, bail, Context, Result};
+use reth_chainspec::{ChainSpec, DEV, HOLESKY, MAINNET, SEPOLIA};
+use reth_db::{
+    mdbx::{tx::Tx, DatabaseArguments, MaxReadTransactionDuration, RO},
+    Bytecodes, DatabaseEnv, HashedAccounts, HashedStorages, PlainAccountState, PlainStorageState,
+};
+use reth_db_api::cursor::{DbCursorRO, DbDupCursorRO};
+use reth_db_api::transaction::DbTx;
+use reth_node_ethereum::EthereumNode;
+use reth_node_types::NodeTypesWithDBAdapter;
+use reth_provider::{
+    providers::StaticFileProvider, DatabaseProviderRO, ProviderFactory, StageCheckpointReader,
+};
+use reth_stages::StageId;
+use std::{path::Path, sync::Arc};
+
+use super::{
+    sorted_hashed_accounts, sorted_hashed_storage, Account, AccountsIter, HashedAccountsIter,
+    HashedStorageIter, Preimages, StateSource, StorageIter, StorageSlotsIter,
+};
+
+type RethNode = NodeTypesWithDBAdapter<EthereumNode, Arc<DatabaseEnv>>;
+
+/// Read-only access to the state of a Reth datadir, through a single database transaction. Views open
+/// their own transaction, which must read the same snapshot of the database: they fail if it was written
+/// to since the source was opened, e.g: by a running node.
+///
+/// Reth doesn't keep the preimages of its hashed state, so the hashed seeks only read the hashed state
+/// tables with [`RethStateSource::with_preimages`], and hash and sort the plain state otherwise.
+pub struct RethStateSource {
+    pub chain_spec: Arc<ChainSpec>,
+    /// Block number of the `Finish` stage checkpoint, i.e: the block of the plain state.
+    pub block_number: u64,
+
+    factory: ProviderFactory<RethNode>,
+    provider: DatabaseProviderRO<Arc<DatabaseEnv>, RethNode>,
+    preimages: Option<Arc<dyn Preimages>>,
+}
+
+impl RethStateSource {
+    /// Opens the database of the Reth `datadir` of `chain`, see [`chain_spec`].
+    pub fn open(datadir: &str, chain: &str) -> Result<Self> {
+        let db_path = Path::new(datadir).join("db");
+        let db = reth_db::open_db_read_only(
+            db_path.as_ref(),
+            DatabaseArguments::default()
+                .with_max_read_transaction_duration(Some(MaxReadTransactionDuration::Unbounded)),
+        )
+        .map_err(|err| anyhow!(err))?;
+        let chain_spec = chain_spec(chain)?;
+        let factory = ProviderFactory::<RethNode>::new(
+            db.into(),
+            chain_spec.clone(),
+            StaticFileProvider::read_only(db_path.join("static_files"), true)?,
+        );
+        let provider = factory.provider()?;
+        let block_number = finish_checkpoint(&provider)?;
+
+        Ok(Self {
+            chain_spec,
+            block_number,
+            factory,
+            provider,
+            preimages: None,
+        })
+    }
+
+    /// Returns the source reading the hashed accounts and storage slots from the `HashedAccounts` and
+    /// `HashedStorages` tables, looking up their addresses and storage slots in `preimages`, which must
+    /// have all of them.
+    pub fn with_preimages(mut self, preimages: Arc<dyn Preimages>) -> Self {
+        self.preimages = Some(preimages);
+        self
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        self.chain_spec.chain.id()
+    }
+
+    /// Returns the provider, e.g: to read headers.
+    pub fn provider(&self) -> &DatabaseProviderRO<Arc<DatabaseEnv>, RethNode> {
+        &self.provider
+    }
+
+    /// Returns the database transaction, e.g: to read tables not exposed by [`StateSource`].
+    pub fn tx(&self) -> &Tx<RO> {
+        self.provider.tx_ref()
+    }
+}
+
+impl StateSource for RethStateSource {
+    fn accounts_count(&self) -> Result<u64> {
+        Ok(self.tx().entries::<PlainAccountState>()? as u64)
+    }
+
+    fn accounts(&self) -> Result<AccountsIter<'_>> {
+        self.accounts_range(Address::ZERO, None)
+    }
+
+    fn accounts_range(&self, start: Address, end: Option<Address>) -> Result<AccountsIter<'_>> {
+        let mut cursor = self.tx().cursor_read::<PlainAccountState>()?;
+        let mut first = Some(cursor.seek(start));
+        Ok(Box::new(std::iter::from_fn(move || {
+            let entry = match first.take() {
+                Some(entry) => entry,
+                None => cursor.next(),
+            };
+            match entry {
+                Ok(Some((address, _))) if end.is_some_and(|end| address >= end) => None,
+                entry => entry.transpose().map(|entry| -> Result<_> {
+                    let (address, account) = entry?;
+                    let account = Account {
+                        nonce: account.nonce,
+                        balance: account.balance,
+                        code_hash: account.bytecode_hash,
+                    };
+                    Ok((address, account))
+                }),
+            }
+        })))
+    }
+
+    fn account(&self, address: Address) -> Result<Option<Account>> {
+        Ok(self
+            .tx()
+            .get::<PlainAccountState>(address)?
+            .map(|account| Account {
+                nonce: account.nonce,
+                balance: account.balance,
+                code_hash: account.bytecode_hash,
+            }))
+    }
+
+    fn storage(&self, address: Address) -> Result<StorageIter<'_>> {
+        let mut cursor = self.tx().cursor_dup_read::<PlainStorageState>()?;
+        let mut first = Some(cursor.seek_by_key_subkey(address, B256::ZERO));
+        Ok(Box::new(std::iter::from_fn(move || {
+            let entry = match first.take() {
+                Some(entry) => entry,
+                None => cursor.next_dup_val(),
+            };
+            entry
+                .transpose()
+                .map(|entry| entry.map(|e| (e.key, e.value)).map_err(anyhow::Error::from))
+        })))
+    }
+
+    fn storage_value(&self, address: Address, slot: B256) -> Result<Option<U256>> {
+        let mut cursor = self.tx().cursor_dup_read::<PlainStorageState>()?;
+        Ok(cursor
+            .seek_by_key_subkey(address, slot)?
+            .filter(|entry| entry.key == slot)
+            .map(|entry| entry.value))
+    }
+
+    fn storage_slots(&self) -> Result<StorageSlotsIter<'_>> {
+        let mut cursor = self.tx().cursor_read::<PlainStorageState>()?;
+        Ok(Box::new(std::iter::from_fn(move || {
+            cursor.next().transpose().map(|entry| {
+                entry
+                    .map(|(address, e)| (address, e.key, e.value))
+                    .map_err(anyhow::Error::from)
+            })
+        })))
+    }
+
+    fn hashed_accounts(&self, start: B256) -> Result<HashedAccountsIter<'_>> {
+        let Some(preimages) = &self.preimages else {
+            return sorted_hashed_accounts(self, start);
+        };
+        let mut cursor = self.tx().cursor_read::<HashedAccounts>()?;
+        let mut first = Some(cursor.seek(start));
+        Ok(Box::new(std::iter::from_fn(move || {
+            let entry = match first.take() {
+                Some(entry) => entry,
+                None => cursor.next(),
+            };
+            entry.transpose().map(|entry| -> Result<_> {
+                let (hashed_address, account) = entry?;
+                let address = preimages.address(hashed_address)?.ok_or(anyhow!(
+                    "Missing preimage of hashed address {}",
+                    hashed_address
+                ))?;
+                let account = Account {
+                    nonce: account.nonce,
+                    balance: account.balance,
+                    code_hash: account.bytecode_hash,
+                };
+                Ok((hashed_address, address, account))
+            })
+        })))
+    }
+
+    fn hashed_storage(
+        &self,
+        address: Address,
+        after: Option<B256>,
+    ) -> Result<HashedStorageIter<'_>> {
+        let Some(preimages) = &self.preimages else {
+            return sorted_hashed_storage(self, address, after);
+        };
+        let mut cursor = self.tx().cursor_dup_read::<HashedStorages>()?;
+        let mut first =
+            Some(cursor.seek_by_key_subkey(keccak256(address), after.unwrap_or_default()));
+        Ok(Box::new(
+            std::iter::from_fn(move || {
+                let entry = match first.take() {
+                    Some(entry) => entry,
+                    None => cursor.next_dup_val(),
+                };
+                entry.transpose()
+            })
+            // The seek lands on the `after` storage slot itself if it's still there.
+            .filter(move |entry| !matches!((entry, after), (Ok(e), Some(after)) if e.key == after))
+            .map(|entry| -> Result<_> {
+                let hashed_slot = entry?.key;
+                let slot = preimages.storage_slot(hashed_slot)?.ok_or(anyhow!(
+                    "Missing preimage of hashed storage slot {} (address: {})",
+                    hashed_slot,
+                    address
+                ))?;
+                Ok((hashed_slot, slot))
+            }),
+        ))
+    }
+
+    fn bytecode(&self, code_hash: B256) -> Result<Option<Bytes>> {
+        Ok(self
+            .tx()
+            .get::<Bytecodes>(code_hash)?
+            .map(|bytecode| bytecode.original_bytes()))
+    }
+
+    fn view(&self) -> Result<Box<dyn StateSource + '_>> {
+        // A read transaction reads the snapshot of the last write transaction committed when it was
+        // opened, so a view with the same transaction id reads the same state.
+        let provider = self.factory.provider()?;
+        if provider.tx_ref().id()? != self.tx().id()? {
+            bail!("Database was written to while reading it, stop the node writing to it first");
+        }
+        Ok(Box::new(RethStateSource {
+            chain_spec: self.chain_spec.clone(),
+            block_number: self.block_number,
+            factory: self.factory.clone(),
+            provider,
+            preimages: self.preimages.clone(),
+        }))
+    }
+}
+
+fn finish_checkpoint(provider: &DatabaseProviderRO<Arc<DatabaseEnv>, RethNode>) -> Result<u64> {
+    provider
+        .get_stage_checkpoint(StageId::Finish)?
+        .map(|ch| ch.block_number)
+        .ok_or(anyhow!("No finish checkpoint"))
+}
+
+/// Returns the chain spec of a built-in chain name (mainnet, sepolia, holesky, dev) or a genesis JSON
+/// file path. Built-in names are matched first, so a file named like a chain needs a path prefix (e.g:
+/// `./dev`).
+pub fn chain_spec(chain: &str) -> Result<Arc<ChainSpec>> {
+    Ok(match chain {
+        "mainnet" => MAINNET.clone(),
+        "sepolia" => SEPOLIA.clone(),
+        "holesky" => HOLESKY.clone(),
+        "dev" => DEV.clone(),
+        path if Path::new(path).is_file() => {
+            let genesis: Genesis = serde_json::from_str(&std::fs::read_to_string(path)?)
+                .with_context(|| format!("parsing genesis file {}", path))?;
+            Arc::new(genesis.into())
+        }
+        _ => bail!(
+            "Unknown chain or genesis file {} (chains: mainnet, sepolia, holesky, dev)",
+            chain
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_chain_names_and_genesis_files() {
+        let chain_ids = ["mainnet", "sepolia", "holesky", "dev"]
+            .map(|chain| chain_spec(chain).unwrap().chain.id());
+        assert_eq!(chain_ids, [1, 11155111, 17000, 1337]);
+
+        let err = chain_spec("mainet").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown chain or genesis file mainet (chains: mainnet, sepolia, holesky, dev)"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("genesis.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, r#"{"config":{"chainId":42},"alloc":{}}"#).unwrap();
+        assert_eq!(chain_spec(path).unwrap().chain.id(), 42);
+        std::fs::write(path, "{").unwrap();
+        let err = chain_spec(path).unwrap_err();
+        assert_eq!(err.to_string(), format!("parsing genesis file {}", path));
+    }
+}
@@ -0,0 +1,214 @@
+//! State root computation from preimages.
+//!
+//! The leaves of the state trie are sorted by `keccak256(address)` and the ones of each storage trie by
+//! `keccak256(slot)`, which is the EIP-7748 ordering. The tries are built with a streaming hash builder
+//! from preimages in that order, looking up the account and storage slot values in a state source, so
+//! only the nodes on the path of the last leaf of each trie are kept in memory.
+
+use alloy_primitives::{keccak256, Address, B256};
+use alloy_trie::{HashBuilder, Nibbles, TrieAccount, KECCAK_EMPTY};
+use anyhow::{anyhow, bail, Result};
+
+use crate::iterators::AccountStorageItem;
+use crate::progress::AddressProgressBar;
+use crate::StateSource;
+
+/// Computes the state root of the accounts and storage slots of `it`, which must be in the EIP-7748
+/// ordering, with their values in `source`. The result is the state root of `source` only if `it` has
+/// exactly its accounts and storage slots.
+pub fn state_root(
+    source: &impl StateSource,
+    it: impl Iterator<Item = Result<AccountStorageItem>>,
+    pb: &mut AddressProgressBar,
+) -> Result<B256> {
+    let mut state = HashBuilder::default();
+    let mut account: Option<AccountTrie> = None;
+    let mut last_key = None;
+    for item in it {
+        match item? {
+            AccountStorageItem::Account(address) => {
+                if let Some(account) = account.take() {
+                    account.finish(source, &mut state)?;
+                }
+                let key = keccak256(address);
+                if last_key.is_some_and(|last_key| last_key >= key) {
+                    bail!("Account {} is out of the EIP-7748 order", address);
+                }
+                last_key = Some(key);
+                account = Some(AccountTrie::new(address, key));
+                pb.progress(address);
+            }
+            AccountStorageItem::StorageSlot(address, slot) => {
+                account
+                    .as_mut()
+                    .filter(|account| account.address == address)
+                    .ok_or(anyhow!(
+                        "Storage slot {} (address: {}) without account",
+                        slot,
+                        address
+                    ))?
+                    .add_storage_slot(source, slot)?;
+            }
+            // The code hash of the account is read from the state.
+            AccountStorageItem::Code(..) => {}
+        }
+    }
+    if let Some(account) = account {
+        account.finish(source, &mut state)?;
+    }
+    Ok(state.root())
+}
+
+/// Storage trie of the account being built.
+struct AccountTrie {
+    address: Address,
+    key: B256,
+    storage: HashBuilder,
+    last_slot_key: Option<B256>,
+}
+
+impl AccountTrie {
+    fn new(address: Address, key: B256) -> Self {
+        Self {
+            address,
+            key,
+            storage: HashBuilder::default(),
+            last_slot_key: None,
+        }
+    }
+
+    fn add_storage_slot(&mut self, source: &impl StateSource, slot: B256) -> Result<()> {
+        let key = keccak256(slot);
+        if self.last_slot_key.is_some_and(|last_key| last_key >= key) {
+            bail!(
+                "Storage slot {} (address: {}) is out of the EIP-7748 order",
+                slot,
+                self.address
+            );
+        }
+        self.last_slot_key = Some(key);
+        let value = source.storage_value(self.address, slot)?.ok_or(anyhow!(
+            "Storage slot {} (address: {}) isn't in the state",
+            slot,
+            self.address
+        ))?;
+        self.storage
+            .add_leaf(Nibbles::unpack(key), &alloy_rlp::encode_fixed_size(&value));
+        Ok(())
+    }
+
+    /// Adds the account, with the root of its storage trie, to the state trie.
+    fn finish(mut self, source: &impl StateSource, state: &mut HashBuilder) -> Result<()> {
+        let account = source
+            .account(self.address)?
+            .ok_or(anyhow!("Account {} isn't in the state", self.address))?;
+        let account = TrieAccount {
+            nonce: account.nonce,
+            balance: account.balance,
+            storage_root: self.storage.root(),
+            code_hash: account.code_hash.unwrap_or(KECCAK_EMPTY),
+        };
+        state.add_leaf(Nibbles::unpack(self.key), &alloy_rlp::encode(account));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::{Bytes, U256};
+    use alloy_trie::{root::state_root_unhashed, EMPTY_ROOT_HASH};
+    use std::thread;
+
+    use super::*;
+    use crate::iterators::eip7748::Eip7748Iterator;
+    use crate::source::{memory::MemoryStateSource, Account};
+
+    fn root(source: &MemoryStateSource) -> Result<B256> {
+        thread::scope(|s| {
+            let it = Eip7748Iterator::new(s, source, None::<fn(Address)>)?;
+            state_root(source, it, &mut AddressProgressBar::hidden())
+        })
+    }
+
+    fn expected_root(source: &MemoryStateSource) -> B256 {
+        state_root_unhashed(source.accounts.iter().map(|(address, account)| {
+            let storage = source.storage.get(address).cloned().unwrap_or_default();
+            let account = TrieAccount {
+                nonce: account.nonce,
+                balance: account.balance,
+                storage_root: alloy_trie::root::storage_root_unhashed(storage),
+                code_hash: account.code_hash.unwrap_or(KECCAK_EMPTY),
+            };
+            (*address, account)
+        }))
+    }
+
+    fn state() -> MemoryStateSource {
+        let mut source = MemoryStateSource::default();
+        let code_hash = source.insert_bytecode(Bytes::from_static(&[0x60, 0x00, 0xff]));
+        for i in 0..100u64 {
+            let address = Address::left_padding_from(&i.to_be_bytes());
+            let account = Account {
+                nonce: i,
+                balance: U256::from(i * 1000),
+                code_hash: Some(code_hash).filter(|_| i % 5 == 0),
+            };
+            source.insert_account(address, account);
+            for j in 0..i % 4 {
+                let slot = B256::left_padding_from(&(i * 4 + j).to_be_bytes());
+                source.insert_storage_slot(address, slot, U256::from(j + 1));
+            }
+        }
+        source
+    }
+
+    #[test]
+    fn empty_state_has_the_empty_root() {
+        assert_eq!(
+            root(&MemoryStateSource::default()).unwrap(),
+            EMPTY_ROOT_HASH
+        );
+    }
+
+    #[test]
+    fn matches_the_state_root() {
+        let source = state();
+        assert_eq!(root(&source).unwrap(), expected_root(&source));
+    }
+
+    #[test]
+    fn missing_preimages_change_the_root() {
+        let source = state();
+        let root = thread::scope(|s| {
+            let it = Eip7748Iterator::new(s, &source, None::<fn(Address)>)
+                .unwrap()
+                .filter(|item| !matches!(item, Ok(AccountStorageItem::StorageSlot(..))));
+            state_root(&source, it, &mut AddressProgressBar::hidden()).unwrap()
+        });
+        assert_ne!(root, expected_root(&source));
+    }
+
+    #[test]
+    fn rejects_preimages_out_of_the_state_or_order() {
+        let source = state();
+        let address = Address::with_last_byte(1);
+        let items = [
+            AccountStorageItem::Account(address),
+            AccountStorageItem::StorageSlot(address, B256::with_last_byte(1)),
+        ];
+        let result = state_root(
+            &source,
+            items.into_iter().map(Ok),
+            &mut AddressProgressBar::hidden(),
+        );
+        assert!(result.is_err());
+
+        let items = [address, address].map(AccountStorageItem::Account);
+        let result = state_root(
+            &source,
+            items.into_iter().map(Ok),
+            &mut AddressProgressBar::hidden(),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,412 @@
+//! Checkpoints of `generate` and `verify` runs, so a run that died can be resumed.
+//!
+//! A checkpoint is recorded periodically at an account boundary, in a text file next to the preimage
+//! file with one `key: value` line per field:
+//! - The run: file format, header fields (ordering, chain id, block number and state root) and the
+//!   block number of the state source when the run started (i.e: the `Finish` stage checkpoint).
+//! - The last completed account and the position after it (file offset, accounts and storage slots
+//!   totals).
+//!
+//! A run is only resumed if it's the same run and the state source didn't move, and the checkpoint is
+//! removed once the run completes. The preimage file is synced to disk before each checkpoint is
+//! recorded, and the checkpoint itself once written, so after a crash the checkpoint never points past
+//! data that didn't reach the disk.
+
+use alloy_primitives::{Address, B256};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::format::{FileFormat, Header, Order, Position};
+use crate::iterators::{AccountStorageItem, PreimageIterator};
+
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub format: FileFormat,
+    pub header: Header,
+    /// Block number of the state source when the run started.
+    pub source_block_number: u64,
+
+    pub last_account: Address,
+    pub position: Position,
+}
+
+impl Checkpoint {
+    pub fn read(path: &str) -> Result<Self> {
+        let data =
+            fs::read_to_string(path).with_context(|| format!("reading checkpoint {}", path))?;
+        Self::parse(&data).with_context(|| format!("parsing checkpoint {}", path))
+    }
+
+    fn parse(data: &str) -> Result<Self> {
+        let fields: HashMap<_, _> = data
+            .lines()
+            .filter_map(|line| line.split_once(": "))
+            .collect();
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .copied()
+                .ok_or(anyhow!("Missing field {}", name))
+        };
+        let number = |name: &str| -> Result<u64> {
+            field(name)?
+                .parse()
+                .map_err(|_| anyhow!("Invalid {} {}", name, field(name).unwrap_or_default()))
+        };
+
+        Ok(Checkpoint {
+            format: match field("format")? {
+                "raw" => FileFormat::Raw,
+                "delimited" => FileFormat::Delimited,
+                "geth-rlp" => FileFormat::GethRlp,
+                format => bail!("Unknown format {}", format),
+            },
+            header: Header {
+                order: match field("order")? {
+                    "plain" => Order::Plain,
+                    "eip7748" => Order::Eip7748,
+                    order => bail!("Unknown ordering {}", order),
+                },
+                chain_id: number("chain_id")?,
+                block_number: number("block_number")?,
+                state_root: B256::from_str(field("state_root")?)
+                    .map_err(|_| anyhow!("Invalid state root"))?,
+            },
+            source_block_number: number("source_block_number")?,
+            last_account: Address::from_str(field("last_account")?)
+                .map_err(|_| anyhow!("Invalid last account"))?,
+            position: Position {
+                offset: number("offset")?,
+                accounts: number("accounts")?,
+                storage_slots: number("storage_slots")?,
+            },
+        })
+    }
+
+    /// Writes the checkpoint to a temporary file and then renames it, so an interrupted write doesn't
+    /// leave a corrupted checkpoint behind. Both the file and the rename are synced to disk before
+    /// returning.
+    pub fn write(&self, path: &str) -> Result<()> {
+        let data = format!(
+            "format: {}\norder: {}\nchain_id: {}\nblock_number: {}\nstate_root: {}\n\
+             source_block_number: {}\nlast_account: {}\noffset: {}\naccounts: {}\nstorage_slots: {}\n",
+            self.format,
+            self.header.order,
+            self.header.chain_id,
+            self.header.block_number,
+            self.header.state_root,
+            self.source_block_number,
+            self.last_account,
+            self.position.offset,
+            self.position.accounts,
+            self.position.storage_slots
+        );
+        let tmp_path = format!("{}.tmp", path);
+        let mut file =
+            File::create(&tmp_path).with_context(|| format!("writing checkpoint {}", path))?;
+        file.write_all(data.as_bytes())
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("writing checkpoint {}", path))?;
+        fs::rename(&tmp_path, path)?;
+        // The rename is only durable once the directory is synced.
+        let dir = Path::new(path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Records the checkpoints of a run every [`CHECKPOINT_INTERVAL`].
+pub struct Checkpointer {
+    path: String,
+    header: Header,
+    source_block_number: u64,
+
+    last: Instant,
+}
+
+impl Checkpointer {
+    /// Creates the checkpointer of a run, removing any checkpoint left at `path` by a previous run
+    /// unless it's the one being resumed.
+    pub fn new(
+        path: String,
+        header: Header,
+        source_block_number: u64,
+        resumed: bool,
+    ) -> Result<Self> {
+        if !resumed {
+            remove(&path)?;
+        }
+        Ok(Self {
+            path,
+            header,
+            source_block_number,
+            last: Instant::now(),
+        })
+    }
+
+    /// Returns the checkpoint to resume the run from, failing if it's from another run or the state
+    /// source moved since it was recorded.
+    pub fn resume(&self, format: Option<FileFormat>) -> Result<Checkpoint> {
+        let checkpoint = Checkpoint::read(&self.path)?;
+        if checkpoint.header != self.header || format.is_some_and(|f| f != checkpoint.format) {
+            bail!(
+                "Checkpoint {} is from a run with another format, ordering or state",
+                self.path
+            );
+        }
+        if checkpoint.source_block_number != self.source_block_number {
+            bail!(
+                "Database moved from block {} to {} since the run started, it can't be resumed",
+                checkpoint.source_block_number,
+                self.source_block_number
+            );
+        }
+        Ok(checkpoint)
+    }
+
+    /// Returns whether a checkpoint is due.
+    pub fn due(&self) -> bool {
+        self.last.elapsed() >= CHECKPOINT_INTERVAL
+    }
+
+    pub fn record(
+        &mut self,
+        format: FileFormat,
+        last_account: Address,
+        position: Position,
+    ) -> Result<()> {
+        Checkpoint {
+            format,
+            header: self.header.clone(),
+            source_block_number: self.source_block_number,
+            last_account,
+            position,
+        }
+        .write(&self.path)?;
+        self.last = Instant::now();
+        Ok(())
+    }
+
+    /// Removes the checkpoint once the run completed.
+    pub fn finish(self) -> Result<()> {
+        remove(&self.path)
+    }
+}
+
+fn remove(path: &str) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Iterator skipping the accounts of another one up to the last account of a checkpoint, included. Used
+/// on top of the iterators that can't seek, or as a safety net on top of the ones that did.
+pub struct ResumedIterator<I> {
+    it: I,
+    order: Order,
+    last_key: Option<B256>,
+}
+
+impl<I: PreimageIterator> ResumedIterator<I> {
+    pub fn new(it: I, order: Order, last_account: Address) -> Self {
+        Self {
+            it,
+            order,
+            last_key: Some(order.account_key(last_account)),
+        }
+    }
+}
+
+impl<I: PreimageIterator> PreimageIterator for ResumedIterator<I> {}
+
+impl<I: PreimageIterator> Iterator for ResumedIterator<I> {
+    type Item = Result<AccountStorageItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some(last_key) = self.last_key else {
+            return self.it.next();
+        };
+        loop {
+            match self.it.next()? {
+                Ok(AccountStorageItem::Account(address))
+                    if self.order.account_key(address) > last_key =>
+                {
+                    self.last_key = None;
+                    return Some(Ok(AccountStorageItem::Account(address)));
+                }
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy_primitives::U256;
+    use eth_stateless::{iterators::plain::PlainIterator, Account, MemoryStateSource};
+    use std::{fs::OpenOptions, io::BufWriter};
+
+    use super::*;
+    use crate::format::{Encoding, PreimageWriter};
+
+    fn header() -> Header {
+        Header {
+            order: Order::Plain,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::repeat_byte(0xaa),
+        }
+    }
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint {
+            format: FileFormat::Delimited,
+            header: header(),
+            source_block_number: 110,
+            last_account: Address::with_last_byte(2),
+            position: Position {
+                offset: 1000,
+                accounts: 2,
+                storage_slots: 10,
+            },
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_checkpoints() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin.checkpoint");
+        let path = path.to_str().unwrap();
+        checkpoint().write(path).unwrap();
+        assert_eq!(Checkpoint::read(path).unwrap(), checkpoint());
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+        let data = fs::read_to_string(path).unwrap();
+        fs::write(path, data.replace("order: plain", "order: other")).unwrap();
+        assert!(Checkpoint::read(path).is_err());
+    }
+
+    #[test]
+    fn resumes_only_the_same_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin.checkpoint");
+        let path = path.to_str().unwrap().to_string();
+        let position = checkpoint().position;
+
+        let mut checkpointer = Checkpointer::new(path.clone(), header(), 110, false).unwrap();
+        checkpointer
+            .record(FileFormat::Delimited, Address::with_last_byte(2), position)
+            .unwrap();
+        assert_eq!(
+            checkpointer.resume(Some(FileFormat::Delimited)).unwrap(),
+            checkpoint()
+        );
+        assert_eq!(checkpointer.resume(None).unwrap(), checkpoint());
+        assert!(checkpointer.resume(Some(FileFormat::Raw)).is_err());
+
+        let other_header = Header {
+            block_number: 101,
+            ..header()
+        };
+        let other = Checkpointer::new(path.clone(), other_header, 110, true).unwrap();
+        assert!(other.resume(None).is_err());
+        let moved = Checkpointer::new(path.clone(), header(), 111, true).unwrap();
+        let err = moved.resume(None).unwrap_err();
+        assert!(err.to_string().contains("Database moved"));
+
+        checkpointer.finish().unwrap();
+        assert!(!Path::new(&path).exists());
+
+        // A new run removes the checkpoint of a previous one.
+        checkpoint().write(&path).unwrap();
+        Checkpointer::new(path.clone(), header(), 110, false).unwrap();
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn resumed_iterator_skips_the_checkpointed_accounts() {
+        let mut source = MemoryStateSource::default();
+        for i in 1..=4 {
+            source.insert_account(Address::with_last_byte(i), Account::default());
+            source.insert_storage_slot(
+                Address::with_last_byte(i),
+                B256::with_last_byte(i),
+                U256::from(1),
+            );
+        }
+        let it = PlainIterator::new(&source).unwrap();
+        let items: Vec<_> = ResumedIterator::new(it, Order::Plain, Address::with_last_byte(2))
+            .map(|item| item.unwrap())
+            .collect();
+        let expected: Vec<_> = PlainIterator::range(&source, Address::with_last_byte(3), None)
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(items, expected);
+    }
+
+    #[test]
+    fn resumed_file_matches_a_complete_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |w: &mut PreimageWriter<BufWriter<File>>, accounts: &[u8]| {
+            for i in accounts {
+                w.write_account(Address::with_last_byte(*i)).unwrap();
+                w.write_storage_slot(B256::with_last_byte(*i)).unwrap();
+            }
+        };
+        let create = |path: &Path| {
+            let file = BufWriter::new(File::create(path).unwrap());
+            PreimageWriter::with_encoding(
+                file,
+                FileFormat::Delimited,
+                &header(),
+                Encoding::FrontCoding,
+            )
+            .unwrap()
+        };
+
+        let complete = dir.path().join("complete.bin");
+        let mut w = create(&complete);
+        write(&mut w, &[1, 2, 3, 4]);
+        w.finish().unwrap();
+
+        // The run died after the checkpoint at account 2, with part of account 3 written.
+        let resumed = dir.path().join("resumed.bin");
+        let mut w = create(&resumed);
+        write(&mut w, &[1, 2]);
+        let position = w.flush().unwrap();
+        write(&mut w, &[3]);
+        w.flush().unwrap();
+        drop(w);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&resumed)
+            .unwrap();
+        let mut w = PreimageWriter::resume(
+            file,
+            FileFormat::Delimited,
+            &position,
+            Address::with_last_byte(2),
+        )
+        .unwrap();
+        write(&mut w, &[3, 4]);
+        w.finish().unwrap();
+        assert_eq!(fs::read(resumed).unwrap(), fs::read(complete).unwrap());
+    }
+}
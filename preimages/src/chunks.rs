@@ -0,0 +1,559 @@
+//! Chunked preimage files.
+//!
+//! With `generate --chunk-size`, the preimage file is split at account boundaries in numbered chunk files
+//! `<output-path>.chunk-<I>`, each of them a complete delimited preimage file with the header of the
+//! whole file. A JSON manifest, `<output-path>.manifest.json`, lists them in order:
+//!
+//! ```text
+//! {
+//!   "version": 1, "chain_id": 1, "block_number": 21547467, "state_root": "0x..", "ordering": "eip7748",
+//!   "accounts": 280000000, "storage_slots": 1200000000,
+//!   "chunks": [
+//!     {"path": "preimages.bin.chunk-00000", "first_key": "0x..", "last_key": "0x..",
+//!      "accounts": 1500000, "storage_slots": 6000000, "bytes": 268435456, "sha256": "0x.."},
+//!     ...
+//!   ]
+//! }
+//! ```
+//!
+//! The keys are the account keys of the ordering (address hashes in the EIP-7748 ordering), so consumers
+//! can fetch only the chunks of the key ranges they need. Chunk paths are relative to the manifest, and
+//! `bytes` and `sha256` are those of the chunk file as distributed, i.e: compressed if it is.
+
+use alloy_primitives::{Address, B256};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io,
+    iter::Peekable,
+    path::Path,
+    str::FromStr,
+};
+
+use crate::cmds::PreimageSink;
+use crate::compress::{self, Compressor, Output};
+use crate::format::{Encoding, FileFormat, Header, Order, Position, PreimageWriter, Summary};
+use crate::iterators::{AccountStorageItem, PreimageIterator};
+
+pub const MANIFEST_VERSION: u64 = 1;
+
+/// Returns the manifest path of the chunked preimage file at `path`.
+pub fn manifest_path(path: &str) -> String {
+    format!("{}.manifest.json", path)
+}
+
+/// Returns whether `path` is the manifest of a chunked preimage file.
+pub fn is_manifest(path: &str) -> bool {
+    path.ends_with(".json")
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// Path of the chunk file, relative to the manifest.
+    pub path: String,
+    /// Keys of the first and last accounts, `None` for the empty chunk of an empty state.
+    pub first_key: Option<B256>,
+    pub last_key: Option<B256>,
+    pub accounts: u64,
+    pub storage_slots: u64,
+    pub bytes: u64,
+    pub sha256: B256,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub header: Header,
+    pub chunks: Vec<Chunk>,
+    /// Directory of the manifest, which the chunk paths are relative to.
+    dir: String,
+}
+
+impl Manifest {
+    pub fn read(path: &str) -> Result<Self> {
+        let data = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        let value: Value =
+            serde_json::from_str(&data).with_context(|| format!("parsing {}", path))?;
+        let version = u64_field(&value, "version")?;
+        if version != MANIFEST_VERSION {
+            bail!("Unsupported manifest version {}", version);
+        }
+        let header = Header {
+            order: match str_field(&value, "ordering")? {
+                "plain" => Order::Plain,
+                "eip7748" => Order::Eip7748,
+                order => bail!("Unknown ordering {}", order),
+            },
+            chain_id: u64_field(&value, "chain_id")?,
+            block_number: u64_field(&value, "block_number")?,
+            state_root: hash_field(&value, "state_root")?,
+        };
+        let chunks = value
+            .get("chunks")
+            .and_then(Value::as_array)
+            .ok_or(anyhow!("Missing chunks in manifest {}", path))?
+            .iter()
+            .map(|chunk| {
+                Ok(Chunk {
+                    path: str_field(chunk, "path")?.to_string(),
+                    first_key: optional_hash_field(chunk, "first_key")?,
+                    last_key: optional_hash_field(chunk, "last_key")?,
+                    accounts: u64_field(chunk, "accounts")?,
+                    storage_slots: u64_field(chunk, "storage_slots")?,
+                    bytes: u64_field(chunk, "bytes")?,
+                    sha256: hash_field(chunk, "sha256")?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("reading manifest {}", path))?;
+        Ok(Self {
+            header,
+            chunks,
+            dir: parent_dir(path),
+        })
+    }
+
+    fn write(&self, path: &str) -> Result<()> {
+        let chunks: Vec<_> = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                json!({
+                    "path": chunk.path,
+                    "first_key": chunk.first_key.map(|key| key.to_string()),
+                    "last_key": chunk.last_key.map(|key| key.to_string()),
+                    "accounts": chunk.accounts,
+                    "storage_slots": chunk.storage_slots,
+                    "bytes": chunk.bytes,
+                    "sha256": chunk.sha256.to_string(),
+                })
+            })
+            .collect();
+        let manifest = json!({
+            "version": MANIFEST_VERSION,
+            "chain_id": self.header.chain_id,
+            "block_number": self.header.block_number,
+            "state_root": self.header.state_root.to_string(),
+            "ordering": self.header.order.to_string(),
+            "accounts": self.chunks.iter().map(|chunk| chunk.accounts).sum::<u64>(),
+            "storage_slots": self.chunks.iter().map(|chunk| chunk.storage_slots).sum::<u64>(),
+            "chunks": chunks,
+        });
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, serde_json::to_string_pretty(&manifest)? + "\n")?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Returns the path of the chunk file of `chunk`.
+    pub fn chunk_path(&self, chunk: &Chunk) -> String {
+        Path::new(&self.dir)
+            .join(&chunk.path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Checks the length and SHA-256 of the file of `chunk` match the manifest.
+    pub fn check_chunk_file(&self, chunk: &Chunk) -> Result<()> {
+        let path = self.chunk_path(chunk);
+        let (bytes, sha256) = file_sha256(&path)?;
+        if bytes != chunk.bytes {
+            bail!(
+                "Chunk {} has {} bytes, the manifest has {}",
+                path,
+                bytes,
+                chunk.bytes
+            );
+        }
+        if sha256 != chunk.sha256 {
+            bail!(
+                "Chunk {} has SHA-256 {}, the manifest has {}",
+                path,
+                sha256,
+                chunk.sha256
+            );
+        }
+        Ok(())
+    }
+}
+
+fn parent_dir(path: &str) -> String {
+    Path::new(path)
+        .parent()
+        .and_then(Path::to_str)
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value> {
+    value
+        .get(name)
+        .ok_or(anyhow!("Missing manifest field {}", name))
+}
+
+fn u64_field(value: &Value, name: &str) -> Result<u64> {
+    field(value, name)?
+        .as_u64()
+        .ok_or(anyhow!("Manifest field {} isn't an integer", name))
+}
+
+fn str_field<'a>(value: &'a Value, name: &str) -> Result<&'a str> {
+    field(value, name)?
+        .as_str()
+        .ok_or(anyhow!("Manifest field {} isn't a string", name))
+}
+
+fn hash_field(value: &Value, name: &str) -> Result<B256> {
+    B256::from_str(str_field(value, name)?)
+        .with_context(|| format!("parsing manifest field {}", name))
+}
+
+fn optional_hash_field(value: &Value, name: &str) -> Result<Option<B256>> {
+    match field(value, name)? {
+        Value::Null => Ok(None),
+        _ => hash_field(value, name).map(Some),
+    }
+}
+
+pub(crate) fn file_sha256(path: &str) -> Result<(u64, B256)> {
+    let mut file = File::open(path).with_context(|| format!("opening {}", path))?;
+    let mut hasher = Sha256::new();
+    let bytes = io::copy(&mut file, &mut hasher)?;
+    Ok((bytes, B256::from_slice(&hasher.finalize())))
+}
+
+/// Writes the preimages in chunk files of about `chunk_size` bytes (before compression), and their
+/// manifest once finished.
+pub struct ChunkedWriter {
+    path: String,
+    header: Header,
+    encoding: Encoding,
+    compressor: Option<Compressor>,
+    chunk_size: u64,
+
+    current: Option<(PreimageWriter<Output>, Chunk)>,
+    chunks: Vec<Chunk>,
+    summary: Summary,
+}
+
+impl ChunkedWriter {
+    /// Creates a writer of the chunks of the delimited preimage file at `path`.
+    pub fn new(
+        path: &str,
+        header: &Header,
+        encoding: Encoding,
+        compressor: Option<Compressor>,
+        chunk_size: u64,
+    ) -> Result<Self> {
+        if chunk_size == 0 {
+            bail!("The chunk size must be at least 1 byte");
+        }
+        Ok(Self {
+            path: path.to_string(),
+            header: header.clone(),
+            encoding,
+            compressor,
+            chunk_size,
+            current: None,
+            chunks: Vec::new(),
+            summary: Summary {
+                compressed_bytes: compressor.map(|_| 0),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Returns the path of the chunk being written.
+    fn chunk_path(&self) -> String {
+        format!("{}.chunk-{:05}", self.path, self.chunks.len())
+    }
+
+    fn start_chunk(&mut self) -> Result<()> {
+        let path = self.chunk_path();
+        let w = PreimageWriter::with_encoding(
+            compress::create(&path, self.compressor)?,
+            FileFormat::Delimited,
+            &self.header,
+            self.encoding.clone(),
+        )?;
+        let chunk = Chunk {
+            path: Path::new(&path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or(anyhow!("Invalid chunk path {}", path))?
+                .to_string(),
+            first_key: None,
+            last_key: None,
+            accounts: 0,
+            storage_slots: 0,
+            bytes: 0,
+            sha256: B256::ZERO,
+        };
+        self.current = Some((w, chunk));
+        Ok(())
+    }
+
+    fn finish_chunk(&mut self) -> Result<()> {
+        let Some((w, mut chunk)) = self.current.take() else {
+            return Ok(());
+        };
+        let (output, summary) = w.finish()?;
+        let compressed_bytes = output.finish()?;
+        (chunk.bytes, chunk.sha256) = file_sha256(&self.chunk_path())?;
+        self.summary.bytes += summary.bytes;
+        self.summary.accounts += summary.accounts;
+        self.summary.storage_slots += summary.storage_slots;
+        self.summary.dictionary_storage_slots += summary.dictionary_storage_slots;
+        self.summary.codes += summary.codes;
+        if let (Some(total), Some(bytes)) =
+            (self.summary.compressed_bytes.as_mut(), compressed_bytes)
+        {
+            *total += bytes;
+        }
+        self.chunks.push(chunk);
+        Ok(())
+    }
+}
+
+impl PreimageSink for ChunkedWriter {
+    type Output = Manifest;
+
+    fn write_account(&mut self, address: Address) -> Result<()> {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(w, _)| w.offset() >= self.chunk_size)
+        {
+            self.finish_chunk()?;
+        }
+        if self.current.is_none() {
+            self.start_chunk()?;
+        }
+        let key = self.header.order.account_key(address);
+        let (w, chunk) = self.current.as_mut().expect("chunk was started");
+        chunk.first_key.get_or_insert(key);
+        chunk.last_key = Some(key);
+        chunk.accounts += 1;
+        w.write_account(address)
+    }
+
+    fn write_storage_slot(&mut self, slot: B256) -> Result<()> {
+        let (w, chunk) = self
+            .current
+            .as_mut()
+            .ok_or(anyhow!("Storage slot {} without account", slot))?;
+        chunk.storage_slots += 1;
+        w.write_storage_slot(slot)
+    }
+
+    fn write_code(&mut self, code_hash: B256) -> Result<()> {
+        let (w, _) = self
+            .current
+            .as_mut()
+            .ok_or(anyhow!("Code hash {} without account", code_hash))?;
+        w.write_code(code_hash)
+    }
+
+    fn flush(&mut self) -> Result<Position> {
+        bail!("Chunked preimage files can't be checkpointed")
+    }
+
+    /// Finishes the last chunk, or writes an empty one if there were no accounts, and then the manifest.
+    fn finish(mut self) -> Result<(Manifest, Summary)> {
+        if self.chunks.is_empty() && self.current.is_none() {
+            self.start_chunk()?;
+        }
+        self.finish_chunk()?;
+        let manifest = Manifest {
+            header: self.header,
+            chunks: self.chunks,
+            dir: parent_dir(&self.path),
+        };
+        manifest.write(&manifest_path(&self.path))?;
+        Ok((manifest, self.summary))
+    }
+}
+
+/// Iterator over the accounts of another one whose key is lower than `end`, i.e: within a chunk. The
+/// following accounts are left in the other iterator, for the next chunks.
+pub struct ChunkIterator<'a, I: PreimageIterator> {
+    it: &'a mut Peekable<I>,
+    order: Order,
+    end: Option<B256>,
+}
+
+impl<'a, I: PreimageIterator> ChunkIterator<'a, I> {
+    pub fn new(it: &'a mut Peekable<I>, order: Order, end: Option<B256>) -> Self {
+        Self { it, order, end }
+    }
+}
+
+impl<I: PreimageIterator> PreimageIterator for ChunkIterator<'_, I> {}
+
+impl<I: PreimageIterator> Iterator for ChunkIterator<'_, I> {
+    type Item = Result<AccountStorageItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let (Some(Ok(AccountStorageItem::Account(address))), Some(end)) =
+            (self.it.peek(), self.end)
+        {
+            if self.order.account_key(*address) >= end {
+                return None;
+            }
+        }
+        self.it.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iterators::file::PreimageFileReader;
+    use AccountStorageItem::{Account as A, StorageSlot as S};
+
+    const HEADER_LEN: u64 = 55;
+    const RECORD_LEN: u64 = 1 + 20 + 4 + 32;
+
+    fn header() -> Header {
+        Header {
+            order: Order::Plain,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::repeat_byte(0xaa),
+        }
+    }
+
+    /// Writes the accounts `1..=count`, each with a storage slot, in chunks of 3 accounts.
+    fn write_chunks(path: &str, count: u8) -> (Manifest, Summary) {
+        let chunk_size = HEADER_LEN + 2 * RECORD_LEN;
+        let mut w = ChunkedWriter::new(path, &header(), Encoding::None, None, chunk_size).unwrap();
+        for i in 1..=count {
+            w.write_account(Address::with_last_byte(i)).unwrap();
+            w.write_storage_slot(B256::with_last_byte(i)).unwrap();
+        }
+        w.finish().unwrap()
+    }
+
+    fn items(accounts: std::ops::RangeInclusive<u8>) -> Vec<AccountStorageItem> {
+        accounts
+            .flat_map(|i| {
+                [
+                    A(Address::with_last_byte(i)),
+                    S(Address::with_last_byte(i), B256::with_last_byte(i)),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_at_account_boundaries_and_lists_the_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        let (manifest, summary) = write_chunks(path, 7);
+
+        assert_eq!(Manifest::read(&manifest_path(path)).unwrap(), manifest);
+        assert_eq!(manifest.header, header());
+        let chunks: Vec<_> = manifest
+            .chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    chunk.path.as_str(),
+                    chunk.first_key,
+                    chunk.last_key,
+                    chunk.accounts,
+                )
+            })
+            .collect();
+        let key = |i| Some(Address::with_last_byte(i).into_word());
+        assert_eq!(
+            chunks,
+            vec![
+                ("preimages.bin.chunk-00000", key(1), key(3), 3),
+                ("preimages.bin.chunk-00001", key(4), key(6), 3),
+                ("preimages.bin.chunk-00002", key(7), key(7), 1),
+            ]
+        );
+        for chunk in &manifest.chunks {
+            manifest.check_chunk_file(chunk).unwrap();
+            assert_eq!(chunk.storage_slots, chunk.accounts);
+            assert_eq!(
+                chunk.bytes,
+                HEADER_LEN + chunk.accounts * RECORD_LEN + 1 + 16 + 32
+            );
+        }
+        assert_eq!((summary.accounts, summary.storage_slots), (7, 7));
+        assert_eq!(
+            summary.bytes,
+            manifest.chunks.iter().map(|chunk| chunk.bytes).sum::<u64>()
+        );
+
+        let read: Vec<_> = PreimageFileReader::open(&manifest_path(path))
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(read, items(1..=7));
+
+        let mut it = PreimageFileReader::open(&manifest_path(path))
+            .unwrap()
+            .peekable();
+        let first: Vec<_> = ChunkIterator::new(&mut it, Order::Plain, key(4))
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(first, items(1..=3));
+        let rest: Vec<_> = ChunkIterator::new(&mut it, Order::Plain, None)
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(rest, items(4..=7));
+    }
+
+    #[test]
+    fn writes_an_empty_chunk_for_an_empty_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        let (manifest, summary) = write_chunks(path, 0);
+
+        assert_eq!(Manifest::read(&manifest_path(path)).unwrap(), manifest);
+        assert_eq!(manifest.chunks.len(), 1);
+        assert_eq!(manifest.chunks[0].first_key, None);
+        assert_eq!(manifest.chunks[0].accounts, 0);
+        assert_eq!(summary.accounts, 0);
+        manifest.check_chunk_file(&manifest.chunks[0]).unwrap();
+    }
+
+    #[test]
+    fn rejects_chunks_not_matching_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        let (mut manifest, _) = write_chunks(path, 7);
+
+        // Same length, different content.
+        let chunk_path = manifest.chunk_path(&manifest.chunks[0]);
+        let mut data = fs::read(&chunk_path).unwrap();
+        data[HEADER_LEN as usize + 1] ^= 0xff;
+        fs::write(&chunk_path, &data).unwrap();
+        let err = manifest.check_chunk_file(&manifest.chunks[0]).unwrap_err();
+        assert!(err.to_string().contains("SHA-256"));
+
+        manifest.chunks[1].sha256 = B256::ZERO;
+        let err = manifest.check_chunk_file(&manifest.chunks[1]).unwrap_err();
+        assert!(err.to_string().contains("SHA-256"));
+
+        manifest.chunks[2].bytes += 1;
+        let err = manifest.check_chunk_file(&manifest.chunks[2]).unwrap_err();
+        assert!(err.to_string().contains("bytes"));
+
+        let data = fs::read_to_string(manifest_path(path)).unwrap();
+        fs::write(
+            manifest_path(path),
+            data.replace("\"version\": 1", "\"version\": 2"),
+        )
+        .unwrap();
+        let err = Manifest::read(&manifest_path(path)).unwrap_err();
+        assert!(err.to_string().contains("Unsupported manifest version"));
+    }
+}
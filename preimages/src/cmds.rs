@@ -1,57 +1,512 @@
+use crate::checkpoint::{Checkpoint, Checkpointer};
+use crate::chunks::{self, ChunkIterator, ChunkedWriter, Manifest};
+use crate::compress::{self, Compressor, SyncWrite, STDIO_PATH};
+use crate::conversion::{self, Timeline, Unit, CODE_CHUNK_SIZE};
+use crate::delta::StateDelta;
+use crate::format::{
+    Encoding, FileFormat, Header, Order, Position, PreimageReader, PreimageWriter, SlotDictionary,
+    Summary, SLOT_PREFIX_LEN,
+};
+use crate::geth::GethPreimageReader;
+use crate::hashed::HashedAccount;
+use crate::index::{self, PreimageStore};
+use crate::iterators::delta::DeltaIterator;
+use crate::iterators::file::PreimageFileReader;
 use crate::iterators::plain::PlainIterator;
 use crate::iterators::{AccountStorageItem, PreimageIterator};
-use crate::progress::AddressProgressBar;
-use alloy_primitives::{Address, FixedBytes};
-use anyhow::{anyhow, Context, Result};
+use crate::report::{Problem, Report};
+use alloy_primitives::{keccak256, Address, B256};
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use eth_stateless::extsort::ExternalSorter;
+use eth_stateless::progress::AddressProgressBar;
+use eth_stateless::{trie, StateSource};
 use reth_db::mdbx::tx::Tx;
 use reth_db::mdbx::RO;
 use std::collections::HashMap;
+use std::iter::Peekable;
 use std::{
-    fs::File,
-    io::{BufReader, BufWriter, Read, Write},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    sync::mpsc::{self, SyncSender},
+    thread,
 };
 
-pub fn generate(path: &str, it: impl PreimageIterator, mut pb: AddressProgressBar) -> Result<()> {
-    let mut f = BufWriter::new(File::create(path)?);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    Jsonl,
+    Csv,
+}
+
+/// Number of items sent at once to the writer thread.
+const WRITE_BATCH_SIZE: usize = 4096;
+/// Number of batches the iterator can get ahead of the writer thread.
+const WRITE_QUEUE_BATCHES: usize = 64;
+
+/// Destination of the preimages written by [`generate`]: a preimage file or the chunks of one.
+pub trait PreimageSink: Send {
+    /// What's left once finished, e.g: the underlying writer.
+    type Output: Send;
+
+    fn write_account(&mut self, address: Address) -> Result<()>;
+    fn write_storage_slot(&mut self, slot: B256) -> Result<()>;
+    fn write_code(&mut self, code_hash: B256) -> Result<()>;
+    /// Flushes what was written and syncs it to disk, returning the position for a checkpoint.
+    fn flush(&mut self) -> Result<Position>;
+    fn finish(self) -> Result<(Self::Output, Summary)>;
+}
+
+impl<W: SyncWrite + Send> PreimageSink for PreimageWriter<W> {
+    type Output = W;
+
+    fn write_account(&mut self, address: Address) -> Result<()> {
+        PreimageWriter::write_account(self, address)
+    }
+
+    fn write_storage_slot(&mut self, slot: B256) -> Result<()> {
+        PreimageWriter::write_storage_slot(self, slot)
+    }
+
+    fn write_code(&mut self, code_hash: B256) -> Result<()> {
+        PreimageWriter::write_code(self, code_hash)
+    }
+
+    fn flush(&mut self) -> Result<Position> {
+        let position = PreimageWriter::flush(self)?;
+        self.get_mut().sync()?;
+        Ok(position)
+    }
+
+    fn finish(self) -> Result<(W, Summary)> {
+        PreimageWriter::finish(self)
+    }
+}
+
+/// Writes the preimages of `it` to `path` (`-` for the standard output) with `encoding`, compressed with
+/// `compressor` if set, recording checkpoints with `checkpointer` if set. If `resume` is set, the file is
+/// resumed at its position with its encoding, and `it` must start after its last account.
+#[allow(clippy::too_many_arguments)]
+pub fn generate(
+    path: &str,
+    format: FileFormat,
+    header: &Header,
+    encoding: Encoding,
+    compressor: Option<Compressor>,
+    it: impl PreimageIterator,
+    pb: AddressProgressBar,
+    checkpointer: Option<Checkpointer>,
+    resume: Option<&Checkpoint>,
+) -> Result<Summary> {
+    let Some(checkpoint) = resume else {
+        let w = PreimageWriter::with_encoding(
+            compress::create(path, compressor)?,
+            format,
+            header,
+            encoding,
+        )?;
+        let (output, mut summary) = write_preimages(w, format, it, pb, checkpointer, None)?;
+        summary.compressed_bytes = output.finish()?;
+        return Ok(summary);
+    };
+    if compressor.is_some() || path == STDIO_PATH {
+        bail!("Compressed files and the standard output can't be resumed");
+    }
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let w = PreimageWriter::resume(file, format, &checkpoint.position, checkpoint.last_account)?;
+    let (_, summary) = write_preimages(
+        w,
+        format,
+        it,
+        pb,
+        checkpointer,
+        Some(checkpoint.last_account),
+    )?;
+    Ok(summary)
+}
+
+/// Writes the preimages of `it` to the chunks of the delimited preimage file at `path`, see
+/// [`crate::chunks`].
+pub fn generate_chunks(
+    path: &str,
+    header: &Header,
+    encoding: Encoding,
+    compressor: Option<Compressor>,
+    chunk_size: u64,
+    it: impl PreimageIterator,
+    pb: AddressProgressBar,
+) -> Result<(Manifest, Summary)> {
+    let w = ChunkedWriter::new(path, header, encoding, compressor, chunk_size)?;
+    write_preimages(w, FileFormat::Delimited, it, pb, None, None)
+}
+
+fn write_preimages<S: PreimageSink>(
+    mut w: S,
+    format: FileFormat,
+    it: impl PreimageIterator,
+    mut pb: AddressProgressBar,
+    mut checkpointer: Option<Checkpointer>,
+    mut last_account: Option<Address>,
+) -> Result<(S::Output, Summary)> {
+    // The preimages are written from another thread, so the iterator can read the next accounts while
+    // the previous ones are written.
+    let (sender, receiver) = mpsc::sync_channel(WRITE_QUEUE_BATCHES);
+    thread::scope(|s| {
+        let writer = s.spawn(move || -> Result<(S::Output, Summary)> {
+            // The channel is closed without the final `None` if the iterator failed, leaving the file
+            // unfinished.
+            while let Some(batch) = receiver.recv()? {
+                for item in batch {
+                    match item {
+                        AccountStorageItem::Account(address) => {
+                            if let (Some(checkpointer), Some(last_account)) =
+                                (&mut checkpointer, last_account)
+                            {
+                                if checkpointer.due() {
+                                    let position = w.flush()?;
+                                    checkpointer.record(format, last_account, position)?;
+                                }
+                            }
+                            last_account = Some(address);
+                            pb.progress(address);
+                            w.write_account(address)?;
+                        }
+                        AccountStorageItem::StorageSlot(_, ss) => {
+                            w.write_storage_slot(ss)?;
+                        }
+                        AccountStorageItem::Code(_, code_hash) => {
+                            w.write_code(code_hash)?;
+                        }
+                    }
+                }
+            }
+            let finished = w.finish()?;
+            if let Some(checkpointer) = checkpointer {
+                checkpointer.finish()?;
+            }
+            Ok(finished)
+        });
+        let read = send_batches(it, sender);
+        let written = writer.join().expect("writer thread panicked");
+        read.and(written)
+    })
+}
+
+/// Sends the items of `it` in batches, followed by `None` once all of them were sent. Stops early if the
+/// receiver is gone, i.e: the writer failed.
+fn send_batches(
+    it: impl PreimageIterator,
+    sender: SyncSender<Option<Vec<AccountStorageItem>>>,
+) -> Result<()> {
+    let mut batch = Vec::with_capacity(WRITE_BATCH_SIZE);
     for entry in it {
-        match entry {
-            Ok(AccountStorageItem::Account(address)) => {
-                pb.progress(address);
-                f.write_all(address.as_slice())
-                    .context("writing address preimage")?;
+        batch.push(entry?);
+        if batch.len() == WRITE_BATCH_SIZE {
+            let full = std::mem::replace(&mut batch, Vec::with_capacity(WRITE_BATCH_SIZE));
+            if sender.send(Some(full)).is_err() {
+                return Ok(());
             }
-            Ok(AccountStorageItem::StorageSlot(_, ss)) => {
-                f.write_all(ss.as_slice())
-                    .context("writing storage slot preimage")?;
+        }
+    }
+    let _ = sender.send(Some(batch)).and_then(|_| sender.send(None));
+    Ok(())
+}
+
+/// Verifies the preimages of the file at `path` against `it`, recording checkpoints with `checkpointer`
+/// if set. If `resume` is set, the file is resumed at its position, and `it` must start after its last
+/// account. If `path` is a manifest, every chunk is verified, and if `chunk` is set, the file is a single
+/// chunk and only the accounts of `it` within its key range are expected.
+#[allow(clippy::too_many_arguments)]
+pub fn verify(
+    path: &str,
+    order: Order,
+    chain_id: u64,
+    it: impl PreimageIterator,
+    full_scan: bool,
+    max_errors: Option<usize>,
+    mut pb: AddressProgressBar,
+    mut checkpointer: Option<Checkpointer>,
+    resume: Option<&Checkpoint>,
+    chunk: bool,
+) -> Result<()> {
+    if chunks::is_manifest(path) {
+        return verify_chunks(path, order, chain_id, it, full_scan, max_errors, pb);
+    }
+    let mut reader = PreimageReader::new(compress::open(path)?)?;
+    if let Some(checkpoint) = resume {
+        if checkpoint.format != reader.format() {
+            bail!("The checkpoint is from a run on a file with another format");
+        }
+        reader.resume(&checkpoint.position, checkpoint.last_account)?;
+    }
+    let last_account = resume.map(|checkpoint| checkpoint.last_account);
+    let Some(header) = reader.header() else {
+        if full_scan {
+            bail!("--full-scan requires a delimited preimage file, raw files stop at the first mismatch");
+        }
+        verify_raw(&mut reader, it, pb, checkpointer.as_mut(), last_account)?;
+        return finish_checkpoints(checkpointer);
+    };
+    check_header(header, order, chain_id)?;
+
+    let mut report = Report::new(if full_scan { max_errors } else { Some(1) });
+    if let Some(checkpoint) = resume {
+        report.accounts = checkpoint.position.accounts;
+        report.storage_slots = checkpoint.position.storage_slots;
+    }
+    verify_delimited(
+        reader,
+        order,
+        it,
+        &mut report,
+        &mut pb,
+        checkpointer.as_mut(),
+        last_account,
+        chunk,
+    )?;
+    report.print();
+    if !report.is_ok() {
+        return Err(anyhow!("The preimage file is invalid"));
+    }
+    finish_checkpoints(checkpointer)
+}
+
+fn check_header(header: &Header, order: Order, chain_id: u64) -> Result<()> {
+    if header.order != order {
+        return Err(anyhow!(
+            "Preimage file has {} ordering, expected {}",
+            header.order,
+            order
+        ));
+    }
+    if header.chain_id != chain_id {
+        return Err(anyhow!(
+            "Preimage file is for chain id {}, expected {}",
+            header.chain_id,
+            chain_id
+        ));
+    }
+    Ok(())
+}
+
+/// Verifies the chunks of the manifest at `path`, each of them against the accounts of `it` up to the
+/// first key of the next chunk.
+fn verify_chunks(
+    path: &str,
+    order: Order,
+    chain_id: u64,
+    it: impl PreimageIterator,
+    full_scan: bool,
+    max_errors: Option<usize>,
+    mut pb: AddressProgressBar,
+) -> Result<()> {
+    let manifest = Manifest::read(path)?;
+    check_header(&manifest.header, order, chain_id)?;
+    let mut report = Report::new(if full_scan { max_errors } else { Some(1) });
+    let mut it = it.peekable();
+    for (i, chunk) in manifest.chunks.iter().enumerate() {
+        let chunk_path = manifest.chunk_path(chunk);
+        manifest.check_chunk_file(chunk)?;
+        let reader = PreimageReader::new(compress::open(&chunk_path)?)?;
+        if reader.header() != Some(&manifest.header) {
+            bail!("Chunk {} has another header than the manifest", chunk_path);
+        }
+        report.file = Some(chunk.path.clone());
+        let end = manifest.chunks.get(i + 1).and_then(|next| next.first_key);
+        verify_delimited(
+            reader,
+            order,
+            ChunkIterator::new(&mut it, order, end),
+            &mut report,
+            &mut pb,
+            None,
+            None,
+            false,
+        )?;
+        if report.is_full() {
+            break;
+        }
+    }
+    report.print();
+    if !report.is_ok() {
+        return Err(anyhow!("The preimage file is invalid"));
+    }
+    Ok(())
+}
+
+/// Verifies the preimages of the EIP-7748 file (or manifest) at `path` against `hashed`, e.g: the
+/// hashed state tables of the database. The preimages of the file are hashed and looked up in it, and
+/// every hashed account and storage slot must have a preimage in the file.
+pub fn verify_hashed(
+    path: &str,
+    chain_id: u64,
+    hashed: impl Iterator<Item = Result<HashedAccount>>,
+    full_scan: bool,
+    max_errors: Option<usize>,
+    mut pb: AddressProgressBar,
+) -> Result<()> {
+    let mut report = Report::new(if full_scan { max_errors } else { Some(1) });
+    let mut hashed = hashed.peekable();
+    if chunks::is_manifest(path) {
+        let manifest = Manifest::read(path)?;
+        check_header(&manifest.header, Order::Eip7748, chain_id)?;
+        for (i, chunk) in manifest.chunks.iter().enumerate() {
+            let chunk_path = manifest.chunk_path(chunk);
+            manifest.check_chunk_file(chunk)?;
+            let reader = PreimageReader::new(compress::open(&chunk_path)?)?;
+            if reader.header() != Some(&manifest.header) {
+                bail!("Chunk {} has another header than the manifest", chunk_path);
+            }
+            report.file = Some(chunk.path.clone());
+            let end = manifest.chunks.get(i + 1).and_then(|next| next.first_key);
+            verify_hashed_delimited(reader, &mut hashed, end, &mut report, &mut pb)?;
+            if report.is_full() {
+                break;
+            }
+        }
+    } else {
+        let reader = PreimageReader::new(compress::open(path)?)?;
+        let Some(header) = reader.header() else {
+            bail!("Hashed verification requires a delimited preimage file");
+        };
+        check_header(header, Order::Eip7748, chain_id)?;
+        verify_hashed_delimited(reader, &mut hashed, None, &mut report, &mut pb)?;
+    }
+    report.print();
+    if !report.is_ok() {
+        return Err(anyhow!("The preimage file is invalid"));
+    }
+    Ok(())
+}
+
+/// Merge-joins the hashed accounts before `end` with the accounts of the file, keyed by the hashes of
+/// their preimages.
+fn verify_hashed_delimited<R: BufRead>(
+    mut reader: PreimageReader<R>,
+    hashed: &mut Peekable<impl Iterator<Item = Result<HashedAccount>>>,
+    end: Option<B256>,
+    report: &mut Report,
+    pb: &mut AddressProgressBar,
+) -> Result<()> {
+    let mut next_hashed = || match hashed.peek() {
+        Some(Ok((key, _))) if end.is_some_and(|end| *key >= end) => Ok(None),
+        _ => hashed.next().transpose(),
+    };
+    let mut hashed_account = next_hashed()?;
+    let mut file = FileAccounts::new(&mut reader, Order::Eip7748);
+    let mut file_account = file.next_account(report);
+
+    while !report.is_full() {
+        let ordering = match (&hashed_account, &file_account) {
+            (None, None) => break,
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (Some((key, _)), Some(f)) => key.cmp(&f.key),
+        };
+        match ordering {
+            std::cmp::Ordering::Less => {
+                let (key, _) = hashed_account.expect("hashed account is present");
+                let offset = file_account.as_ref().map_or(file.offset(), |f| f.offset);
+                report.add(offset, Problem::MissingAccountPreimage(key));
+                hashed_account = next_hashed()?;
+            }
+            std::cmp::Ordering::Greater => {
+                let f = file_account.expect("file account is present");
+                report.add(f.offset, Problem::ExtraAccount(f.address));
+                file_account = file.next_account(report);
+            }
+            std::cmp::Ordering::Equal => {
+                let (_, hashed_slots) = hashed_account.expect("hashed account is present");
+                let f = file_account.expect("file account is present");
+                pb.progress(f.address);
+                verify_hashed_storage_slots(&f, &hashed_slots, report);
+                hashed_account = next_hashed()?;
+                file_account = file.next_account(report);
             }
-            Err(e) => return Err(e),
         }
     }
+
+    if !report.is_full() && !file.malformed && !reader.is_eof()? {
+        report.add(reader.offset(), Problem::TrailingBytes);
+    }
     Ok(())
 }
 
-pub fn verify(path: &str, it: impl PreimageIterator, mut pb: AddressProgressBar) -> Result<()> {
-    let mut reader = BufReader::new(File::open(path)?);
+fn verify_hashed_storage_slots(file_account: &FileAccount, hashed: &[B256], report: &mut Report) {
+    let address = file_account.address;
+    let file = &file_account.storage_slots;
+    let (mut i, mut j) = (0, 0);
+    while (i < hashed.len() || j < file.len()) && !report.is_full() {
+        let ordering = match (hashed.get(i), file.get(j)) {
+            (Some(key), Some(f)) => key.cmp(&f.key),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            _ => std::cmp::Ordering::Greater,
+        };
+        match ordering {
+            std::cmp::Ordering::Less => {
+                let offset = file.get(j).map_or(file_account.end_offset, |f| f.offset);
+                report.add(
+                    offset,
+                    Problem::MissingStorageSlotPreimage(address, hashed[i]),
+                );
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                report.add(
+                    file[j].offset,
+                    Problem::ExtraStorageSlot(address, file[j].slot),
+                );
+                j += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+}
+
+fn finish_checkpoints(checkpointer: Option<Checkpointer>) -> Result<()> {
+    match checkpointer {
+        Some(checkpointer) => checkpointer.finish(),
+        None => Ok(()),
+    }
+}
 
+fn verify_raw<R: BufRead>(
+    reader: &mut PreimageReader<R>,
+    it: impl PreimageIterator,
+    mut pb: AddressProgressBar,
+    mut checkpointer: Option<&mut Checkpointer>,
+    mut last_account: Option<Address>,
+) -> Result<()> {
     for entry in it {
         match entry {
             Ok(AccountStorageItem::Account(addr)) => {
+                if let (Some(checkpointer), Some(last_account)) =
+                    (checkpointer.as_deref_mut(), last_account)
+                {
+                    if checkpointer.due() {
+                        checkpointer.record(FileFormat::Raw, last_account, reader.position())?;
+                    }
+                }
+                last_account = Some(addr);
                 pb.progress(addr);
-                let mut file_addr: Address = Default::default();
-                reader
-                    .read_exact(file_addr.as_mut_slice())
-                    .context("reading address preimage")?;
-
-                if addr != file_addr.as_slice() {
+                let file_addr = reader
+                    .read_account()?
+                    .ok_or_else(|| anyhow!("Address {} preimage missing", addr))?;
+                if addr != file_addr {
                     return Err(anyhow!("Address {} preimage mismatch", file_addr));
                 }
             }
             Ok(AccountStorageItem::StorageSlot(address, ss)) => {
-                let mut file_ss: FixedBytes<32> = Default::default();
-                reader
-                    .read_exact(file_ss.as_mut_slice())
-                    .context("reading storage slot preimage")?;
-                if ss != file_ss.as_slice() {
+                let file_ss = reader.read_storage_slot()?.ok_or_else(|| {
+                    anyhow!(
+                        "Storage slot {} preimage (address: {}) missing",
+                        ss,
+                        address
+                    )
+                })?;
+                if ss != file_ss {
                     return Err(anyhow!(
                         "Storage slot {} preimage (address: {}) mistmatch",
                         ss,
@@ -59,16 +514,816 @@ pub fn verify(path: &str, it: impl PreimageIterator, mut pb: AddressProgressBar)
                     ));
                 }
             }
+            Ok(AccountStorageItem::Code(..)) => {
+                return Err(anyhow!("Raw preimage files don't have code hashes"))
+            }
             Err(e) => return Err(e),
         }
     }
+    if !reader.is_eof()? {
+        return Err(anyhow!("Trailing bytes at offset {}", reader.offset()));
+    }
     Ok(())
 }
 
-pub fn storage_slot_freq<const N: usize>(tx: &Tx<RO>, top_n_detail: usize) -> Result<()> {
+/// Merge-joins the expected accounts and storage slots with the ones in the file, reporting every
+/// difference instead of stopping at the first one. Checkpoints are only recorded while no problem was
+/// found. If `chunk` is set, the expected accounts before the first account of the file and after its
+/// last one are skipped, since they are in other chunks.
+#[allow(clippy::too_many_arguments)]
+fn verify_delimited<R: BufRead>(
+    mut reader: PreimageReader<R>,
+    order: Order,
+    it: impl PreimageIterator,
+    report: &mut Report,
+    pb: &mut AddressProgressBar,
+    mut checkpointer: Option<&mut Checkpointer>,
+    last_account: Option<Address>,
+    chunk: bool,
+) -> Result<()> {
+    let mut expected = AccountGroups::new(it);
+    let mut expected_account = expected.next_account()?;
+    let mut file = FileAccounts::new(&mut reader, order);
+    file.last_key = last_account.map(|address| order.account_key(address));
+    let mut file_account = file.next_account(report);
+    let first_key = file_account.as_ref().map(|f| f.key).filter(|_| chunk);
+
+    while !report.is_full() {
+        let ordering = match (&expected_account, &file_account) {
+            (None, None) => break,
+            (Some(_), None) if chunk => break,
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (Some((address, ..)), Some(f)) => order.account_key(*address).cmp(&f.key),
+        };
+        match ordering {
+            std::cmp::Ordering::Less => {
+                let (address, ..) = expected_account.expect("expected account is present");
+                pb.progress(address);
+                if first_key.is_some_and(|first_key| order.account_key(address) < first_key) {
+                    expected_account = expected.next_account()?;
+                    continue;
+                }
+                let offset = file_account.as_ref().map_or(file.offset(), |f| f.offset);
+                report.add(offset, Problem::MissingAccount(address));
+                expected_account = expected.next_account()?;
+            }
+            std::cmp::Ordering::Greater => {
+                let f = file_account.expect("file account is present");
+                report.add(f.offset, Problem::ExtraAccount(f.address));
+                file_account = file.next_account(report);
+            }
+            std::cmp::Ordering::Equal => {
+                let (address, storage_slots, code_hash) =
+                    expected_account.expect("expected account is present");
+                let f = file_account.expect("file account is present");
+                pb.progress(address);
+                verify_storage_slots(order, &f, &storage_slots, report);
+                verify_code(&f, code_hash, report);
+                if let Some(checkpointer) = checkpointer.as_deref_mut() {
+                    if report.is_ok() && checkpointer.due() {
+                        checkpointer.record(
+                            FileFormat::Delimited,
+                            address,
+                            file.reader.position(),
+                        )?;
+                    }
+                }
+                expected_account = expected.next_account()?;
+                file_account = file.next_account(report);
+            }
+        }
+    }
+
+    if !report.is_full() && !file.malformed && !reader.is_eof()? {
+        report.add(reader.offset(), Problem::TrailingBytes);
+    }
+    Ok(())
+}
+
+fn verify_storage_slots(
+    order: Order,
+    file_account: &FileAccount,
+    expected: &[B256],
+    report: &mut Report,
+) {
+    let address = file_account.address;
+    let expected: Vec<_> = expected
+        .iter()
+        .map(|ss| (*ss, order.storage_slot_key(*ss)))
+        .collect();
+    let file = &file_account.storage_slots;
+
+    let (mut i, mut j) = (0, 0);
+    while (i < expected.len() || j < file.len()) && !report.is_full() {
+        let ordering = match (expected.get(i), file.get(j)) {
+            (Some((_, expected_key)), Some(f)) => expected_key.cmp(&f.key),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            _ => std::cmp::Ordering::Greater,
+        };
+        match ordering {
+            std::cmp::Ordering::Less => {
+                let offset = file.get(j).map_or(file_account.end_offset, |f| f.offset);
+                report.add(offset, Problem::MissingStorageSlot(address, expected[i].0));
+                i += 1;
+            }
+            std::cmp::Ordering::Greater => {
+                report.add(
+                    file[j].offset,
+                    Problem::ExtraStorageSlot(address, file[j].slot),
+                );
+                j += 1;
+            }
+            std::cmp::Ordering::Equal => {
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+}
+
+fn verify_code(file_account: &FileAccount, expected: Option<B256>, report: &mut Report) {
+    if file_account.code_hash == expected {
+        return;
+    }
+    let address = file_account.address;
+    if let Some(code_hash) = expected {
+        report.add(
+            file_account.offset,
+            Problem::MissingCode(address, code_hash),
+        );
+    }
+    if let Some(code_hash) = file_account.code_hash {
+        report.add(file_account.offset, Problem::ExtraCode(address, code_hash));
+    }
+}
+
+/// Address of an account with its storage slots and code hash, if any.
+type ExpectedAccount = (Address, Vec<B256>, Option<B256>);
+
+/// Groups the items of a preimage iterator by account.
+struct AccountGroups<I> {
+    it: I,
+    next_address: Option<Address>,
+}
+
+impl<I: PreimageIterator> AccountGroups<I> {
+    fn new(it: I) -> Self {
+        Self {
+            it,
+            next_address: None,
+        }
+    }
+
+    fn next_account(&mut self) -> Result<Option<ExpectedAccount>> {
+        let address = match self.next_address.take() {
+            Some(address) => address,
+            None => match self.it.next().transpose()? {
+                Some(AccountStorageItem::Account(address)) => address,
+                Some(AccountStorageItem::StorageSlot(address, ss)) => {
+                    return Err(anyhow!(
+                        "Storage slot {} (address: {}) without account",
+                        ss,
+                        address
+                    ))
+                }
+                Some(AccountStorageItem::Code(address, code_hash)) => {
+                    return Err(anyhow!(
+                        "Code hash {} (address: {}) without account",
+                        code_hash,
+                        address
+                    ))
+                }
+                None => return Ok(None),
+            },
+        };
+        let mut storage_slots = Vec::new();
+        let mut code_hash = None;
+        while let Some(entry) = self.it.next().transpose()? {
+            match entry {
+                AccountStorageItem::Account(next_address) => {
+                    self.next_address = Some(next_address);
+                    break;
+                }
+                AccountStorageItem::StorageSlot(_, ss) => storage_slots.push(ss),
+                AccountStorageItem::Code(_, hash) => code_hash = Some(hash),
+            }
+        }
+        Ok(Some((address, storage_slots, code_hash)))
+    }
+}
+
+struct FileStorageSlot {
+    offset: u64,
+    slot: B256,
+    key: B256,
+}
+
+struct FileAccount {
+    offset: u64,
+    end_offset: u64,
+    address: Address,
+    key: B256,
+    storage_slots: Vec<FileStorageSlot>,
+    code_hash: Option<B256>,
+}
+
+/// Reads the accounts of a delimited preimage file, reporting out of order and malformed records.
+/// The returned accounts and storage slots are strictly increasing in the file ordering.
+struct FileAccounts<'a, R: BufRead> {
+    reader: &'a mut PreimageReader<R>,
+    order: Order,
+    last_key: Option<B256>,
+    malformed: bool,
+}
+
+impl<'a, R: BufRead> FileAccounts<'a, R> {
+    fn new(reader: &'a mut PreimageReader<R>, order: Order) -> Self {
+        Self {
+            reader,
+            order,
+            last_key: None,
+            malformed: false,
+        }
+    }
+
+    fn offset(&self) -> u64 {
+        self.reader.offset()
+    }
+
+    fn next_account(&mut self, report: &mut Report) -> Option<FileAccount> {
+        while !self.malformed && !report.is_full() {
+            let account = match self.read_account(report) {
+                Ok(Some(account)) => account,
+                Ok(None) => return None,
+                Err(e) => {
+                    report.add(self.reader.offset(), Problem::Malformed(e.to_string()));
+                    self.malformed = true;
+                    return None;
+                }
+            };
+            match self.last_key.map(|last_key| last_key.cmp(&account.key)) {
+                Some(std::cmp::Ordering::Equal) => {
+                    report.add(account.offset, Problem::DuplicateAccount(account.address))
+                }
+                Some(std::cmp::Ordering::Greater) => {
+                    report.add(account.offset, Problem::UnorderedAccount(account.address))
+                }
+                _ => {
+                    self.last_key = Some(account.key);
+                    return Some(account);
+                }
+            }
+        }
+        None
+    }
+
+    fn read_account(&mut self, report: &mut Report) -> Result<Option<FileAccount>> {
+        let offset = self.reader.offset();
+        let Some(address) = self.reader.read_account()? else {
+            return Ok(None);
+        };
+        report.accounts += 1;
+
+        let mut storage_slots: Vec<FileStorageSlot> = Vec::new();
+        while let Some(slot) = self.reader.read_storage_slot()? {
+            let slot_offset = self.reader.storage_slot_offset();
+            report.storage_slots += 1;
+            let key = self.order.storage_slot_key(slot);
+            match storage_slots.last().map(|last| last.key.cmp(&key)) {
+                Some(std::cmp::Ordering::Equal) => {
+                    report.add(slot_offset, Problem::DuplicateStorageSlot(address, slot))
+                }
+                Some(std::cmp::Ordering::Greater) => {
+                    report.add(slot_offset, Problem::UnorderedStorageSlot(address, slot))
+                }
+                _ => storage_slots.push(FileStorageSlot {
+                    offset: slot_offset,
+                    slot,
+                    key,
+                }),
+            }
+        }
+        // Known once all the storage slots were read.
+        let code_hash = self.reader.code_hash();
+        report.codes += code_hash.is_some() as u64;
+
+        Ok(Some(FileAccount {
+            offset,
+            end_offset: self.reader.offset(),
+            address,
+            key: self.order.account_key(address),
+            storage_slots,
+            code_hash,
+        }))
+    }
+}
+
+/// Recomputes the state root from the preimages of the EIP-7748 file at `path` and the account and
+/// storage slot values of `source`, and checks it's the state root of `expected`, i.e: that the file has
+/// exactly the preimages of the state.
+pub fn state_root(source: &impl StateSource, path: &str, expected: &Header) -> Result<()> {
+    let it = PreimageFileReader::open(path)?;
+    let header = it.header().clone();
+    check_header(&header, Order::Eip7748, expected.chain_id)?;
+    if header.block_number != expected.block_number {
+        bail!(
+            "Preimage file is for block {}, the state is at block {}",
+            header.block_number,
+            expected.block_number
+        );
+    }
+    println!("[1/2] Computing the state root from the preimage file...");
+    let mut pb = AddressProgressBar::new(true);
+    let state_root = trie::state_root(source, it, &mut pb)?;
+    println!("Computed state root: {}", state_root);
+    println!("Expected state root: {}", expected.state_root);
+    if state_root != expected.state_root {
+        bail!(
+            "The state root doesn't match, the preimage file is incomplete or has extra preimages"
+        );
+    }
+    println!(
+        "[2/2] The preimage file is complete for block {}!",
+        header.block_number
+    );
+    Ok(())
+}
+
+/// Simulates the EIP-7748 conversion of the state of `source`, whose preimages with the code of the
+/// contracts are `it` in the EIP-7748 ordering, from `start_block` with each of `strides`. The timeline of
+/// each stride is printed in `format`, a row per block, and the number of blocks of the conversion once
+/// it's done.
+pub fn simulate_conversion(
+    source: &impl StateSource,
+    it: impl PreimageIterator,
+    strides: &[u64],
+    start_block: u64,
+    format: DumpFormat,
+    mut pb: AddressProgressBar,
+) -> Result<()> {
+    let accounts = source.accounts_count()?;
+    let mut timelines: Vec<_> = strides
+        .iter()
+        .map(|stride| Timeline::new(*stride, start_block))
+        .collect();
+    let mut out = BufWriter::new(io::stdout().lock());
+    conversion::write_header(&mut out, format)?;
+    let mut push = |unit: Unit, out: &mut BufWriter<_>| -> Result<()> {
+        for timeline in &mut timelines {
+            if let Some(block) = timeline.push(unit) {
+                conversion::write_block(out, format, timeline.stride, &block, accounts)?;
+            }
+        }
+        Ok(())
+    };
+
+    // Each account is converted along with its code, after its storage slots, i.e: once the code item
+    // following them (if any) is read.
+    let mut pending_account = None;
+    for item in it {
+        match item? {
+            AccountStorageItem::Account(address) => {
+                pb.progress(address);
+                if let Some(unit) = pending_account.replace(Unit::Account { code_chunks: None }) {
+                    push(unit, &mut out)?;
+                }
+            }
+            AccountStorageItem::StorageSlot(..) => push(Unit::StorageSlot, &mut out)?,
+            AccountStorageItem::Code(address, code_hash) => {
+                let code = source.bytecode(code_hash)?.ok_or(anyhow!(
+                    "Bytecode {} (address: {}) isn't in the state",
+                    code_hash,
+                    address
+                ))?;
+                let Some(Unit::Account { code_chunks }) = &mut pending_account else {
+                    bail!("Code of {} before its account", address);
+                };
+                *code_chunks = Some((code.len() as u64).div_ceil(CODE_CHUNK_SIZE));
+            }
+        }
+    }
+    if let Some(unit) = pending_account {
+        push(unit, &mut out)?;
+    }
+
+    for timeline in &mut timelines {
+        if let Some(block) = timeline.finish() {
+            conversion::write_block(&mut out, format, timeline.stride, &block, accounts)?;
+        }
+    }
+    out.flush()?;
+    for timeline in &timelines {
+        let total = timeline.total();
+        eprintln!(
+            "Stride {}: {} conversion units ({} accounts, with {} codes of {} chunks, and {} storage slots) in {} blocks, until block {}",
+            timeline.stride,
+            total.units(),
+            total.accounts,
+            total.codes,
+            total.code_chunks,
+            total.storage_slots,
+            timeline.blocks(),
+            (timeline.start_block + timeline.blocks()).saturating_sub(1)
+        );
+    }
+    Ok(())
+}
+
+/// Sorted record of a state preimage: hash (32) | storage slot flag (1) | address (20) | storage slot (32).
+const GETH_STATE_RECORD_SIZE: usize = 32 + 1 + 20 + 32;
+
+/// Checks the geth preimage file at `path` has the preimages of every account and storage slot of `it`,
+/// in any order. Both are sorted by hash with at most `max_memory` bytes of memory and then merged.
+/// Preimages of the file that aren't in the state are ignored, since geth keeps the preimages of removed
+/// accounts and storage slots.
+pub fn verify_geth(
+    path: &str,
+    it: impl PreimageIterator,
+    max_memory: usize,
+    max_errors: Option<usize>,
+) -> Result<()> {
+    println!("[1/3] Reading geth preimage file...");
+    let mut file_hashes = ExternalSorter::<32>::new(max_memory / 2);
+    let mut preimages = 0u64;
+    for preimage in GethPreimageReader::open(path)? {
+        file_hashes.push(keccak256(preimage?.as_slice()).0)?;
+        preimages += 1;
+    }
+    println!("Geth preimage file has {} preimages", preimages);
+
+    println!("[2/3] Reading state preimages...");
+    let mut state_hashes = ExternalSorter::<GETH_STATE_RECORD_SIZE>::new(max_memory / 2);
+    let mut pb = AddressProgressBar::new(false);
+    for item in it {
+        let mut record = [0u8; GETH_STATE_RECORD_SIZE];
+        match item? {
+            AccountStorageItem::Account(address) => {
+                record[..32].copy_from_slice(keccak256(address).as_slice());
+                record[33..53].copy_from_slice(address.as_slice());
+                pb.progress(address);
+            }
+            AccountStorageItem::StorageSlot(address, slot) => {
+                record[..32].copy_from_slice(keccak256(slot).as_slice());
+                record[32] = 1;
+                record[33..53].copy_from_slice(address.as_slice());
+                record[53..].copy_from_slice(slot.as_slice());
+            }
+            // Geth doesn't keep code hash preimages.
+            AccountStorageItem::Code(..) => continue,
+        }
+        state_hashes.push(record)?;
+    }
+
+    println!("[3/3] Looking up state preimages...");
+    let mut file_hashes = file_hashes.finish()?.peekable();
+    let mut report = Report::new(max_errors);
+    let mut last_hash = None;
+    for record in state_hashes.finish()? {
+        let record = record?;
+        let hash: [u8; 32] = record[..32].try_into().expect("32 bytes hash");
+        if last_hash == Some(hash) {
+            // Storage slot of several accounts.
+            continue;
+        }
+        last_hash = Some(hash);
+
+        let address = Address::from_slice(&record[33..53]);
+        if record[32] == 0 {
+            report.accounts += 1;
+        } else {
+            report.storage_slots += 1;
+        }
+        while file_hashes
+            .next_if(|file_hash| file_hash.as_ref().is_ok_and(|file_hash| *file_hash < hash))
+            .is_some()
+        {}
+        match file_hashes.peek() {
+            Some(Ok(file_hash)) if *file_hash == hash => continue,
+            Some(Err(_)) => return Err(file_hashes.next().expect("peeked").unwrap_err()),
+            _ => {}
+        }
+        if record[32] == 0 {
+            report.add_unlocated(Problem::MissingAccount(address));
+        } else {
+            let slot = B256::from_slice(&record[53..]);
+            report.add_unlocated(Problem::MissingStorageSlot(address, slot));
+        }
+        if report.is_full() {
+            break;
+        }
+    }
+
+    report.print();
+    if !report.is_ok() {
+        return Err(anyhow!("The geth preimage file is missing state preimages"));
+    }
+    Ok(())
+}
+
+pub fn check(path: &str, max_errors: Option<usize>) -> Result<()> {
+    let report = match chunks::is_manifest(path) {
+        true => check_chunks(path, max_errors)?,
+        false => check_file(path, max_errors)?,
+    };
+    report.print();
+    if !report.is_ok() {
+        return Err(anyhow!("The preimage file is invalid"));
+    }
+    Ok(())
+}
+
+/// Checks the single preimage file at `path`, returning the report of its problems.
+fn check_file(path: &str, max_errors: Option<usize>) -> Result<Report> {
+    let mut reader = PreimageReader::new(compress::open(path)?)?;
+    let header = reader.header().cloned().ok_or(anyhow!(
+        "Raw preimage files can't be checked without the database"
+    ))?;
+    println!(
+        "Preimage file block number: {} ({} ordering, chain id {})",
+        header.block_number, header.order, header.chain_id
+    );
+
+    let mut report = Report::new(max_errors);
+    let mut pb = AddressProgressBar::new(header.order == Order::Eip7748);
+    let mut file = FileAccounts::new(&mut reader, header.order);
+    while let Some(account) = file.next_account(&mut report) {
+        pb.progress(account.address);
+    }
+    if !report.is_full() && !file.malformed && !reader.is_eof()? {
+        report.add(reader.offset(), Problem::TrailingBytes);
+    }
+    Ok(report)
+}
+
+/// Checks the chunks of the manifest at `path`, and that they match the manifest: file length and SHA-256,
+/// key range and counts. The accounts must be in order across the chunks.
+fn check_chunks(path: &str, max_errors: Option<usize>) -> Result<Report> {
+    let manifest = Manifest::read(path)?;
+    let header = &manifest.header;
+    println!(
+        "Preimage file block number: {} ({} ordering, chain id {}, {} chunks)",
+        header.block_number,
+        header.order,
+        header.chain_id,
+        manifest.chunks.len()
+    );
+
+    let mut report = Report::new(max_errors);
+    let mut pb = AddressProgressBar::new(header.order == Order::Eip7748);
+    let mut last_key = None;
+    for chunk in &manifest.chunks {
+        report.file = Some(chunk.path.clone());
+        if let Err(e) = manifest.check_chunk_file(chunk) {
+            report.add_unlocated(Problem::ManifestMismatch(e.to_string()));
+            continue;
+        }
+        let mut reader = PreimageReader::new(compress::open(&manifest.chunk_path(chunk))?)?;
+        if reader.header() != Some(header) {
+            report.add_unlocated(Problem::ManifestMismatch(format!(
+                "chunk {} has another header",
+                chunk.path
+            )));
+            continue;
+        }
+        let (accounts, storage_slots) = (report.accounts, report.storage_slots);
+        let mut file = FileAccounts::new(&mut reader, header.order);
+        file.last_key = last_key;
+        let mut first_key = None;
+        while let Some(account) = file.next_account(&mut report) {
+            first_key.get_or_insert(account.key);
+            pb.progress(account.address);
+        }
+        let (malformed, chunk_last_key) = (file.malformed, first_key.and(file.last_key));
+        if !report.is_full() && !malformed && !reader.is_eof()? {
+            report.add(reader.offset(), Problem::TrailingBytes);
+        }
+        if first_key != chunk.first_key
+            || chunk_last_key != chunk.last_key
+            || report.accounts - accounts != chunk.accounts
+            || report.storage_slots - storage_slots != chunk.storage_slots
+        {
+            report.add_unlocated(Problem::ManifestMismatch(format!(
+                "chunk {} key range or counts differ from the manifest",
+                chunk.path
+            )));
+        }
+        last_key = chunk_last_key.or(last_key);
+        if report.is_full() {
+            break;
+        }
+    }
+    Ok(report)
+}
+
+pub fn delta(
+    tx: &Tx<RO>,
+    chain_id: u64,
+    from: &str,
+    to_block: u64,
+    state_root: B256,
+    path: &str,
+    include_removed: bool,
+) -> Result<()> {
+    let from_block = preimage_file_block(from)?;
+    println!(
+        "[1/2] Reading changesets from block {} to {}...",
+        from_block + 1,
+        to_block
+    );
+    let delta = StateDelta::from_changesets(tx, chain_id, from_block, to_block, state_root)?;
+    let counts = delta.counts();
+    println!(
+        "Added {} accounts and {} storage slots, removed {} accounts and {} storage slots",
+        counts.added_accounts,
+        counts.added_storage_slots,
+        counts.removed_accounts,
+        counts.removed_storage_slots
+    );
+    println!("[2/2] Writing delta file...");
+    delta.write(BufWriter::new(File::create(path)?), include_removed)
+}
+
+pub fn apply_delta(base_path: &str, delta_path: &str, path: &str) -> Result<()> {
+    if base_path == path {
+        bail!("The output path must be different from the base preimages file");
+    }
+    let base = PreimageFileReader::open(base_path)?;
+    let delta = StateDelta::read(BufReader::new(File::open(delta_path)?))?;
+    if !delta.removals {
+        bail!("The delta doesn't include removed keys, generate it with --include-removed");
+    }
+    if base.header().block_number != delta.from_block {
+        bail!(
+            "The preimages file is at block {} but the delta starts at block {}",
+            base.header().block_number,
+            delta.from_block
+        );
+    }
+    if base.header().chain_id != delta.chain_id {
+        bail!(
+            "The preimages file is for chain id {} but the delta for chain id {}",
+            base.header().chain_id,
+            delta.chain_id
+        );
+    }
+
+    let header = Header {
+        order: base.header().order,
+        chain_id: delta.chain_id,
+        block_number: delta.to_block,
+        state_root: delta.state_root,
+    };
+    println!(
+        "Applying delta from block {} to {}...",
+        delta.from_block, delta.to_block
+    );
+    let encoding = base.encoding().clone();
+    let summary = generate(
+        path,
+        FileFormat::Delimited,
+        &header,
+        encoding,
+        None,
+        DeltaIterator::new(base, delta, header.order),
+        AddressProgressBar::new(header.order == Order::Eip7748),
+        None,
+        None,
+    )?;
+    summary.print();
+    Ok(())
+}
+
+/// Returns the block number of a `<path>[@<block>]` preimage file argument. The block is read from
+/// the file header, so it's only required for raw preimage files.
+fn preimage_file_block(arg: &str) -> Result<u64> {
+    let (path, block) = match arg.rsplit_once('@') {
+        Some((path, block)) if block.parse::<u64>().is_ok() => (path, block.parse::<u64>().ok()),
+        _ => (arg, None),
+    };
+    let reader = PreimageReader::new(compress::open(path)?)?;
+    match (reader.header().map(|h| h.block_number), block) {
+        (Some(file_block), Some(block)) if file_block != block => bail!(
+            "Preimage file {} is at block {}, not {}",
+            path,
+            file_block,
+            block
+        ),
+        (Some(block), _) | (None, Some(block)) => Ok(block),
+        (None, None) => bail!(
+            "Raw preimage file {} has no block number, use {}@<block>",
+            path,
+            path
+        ),
+    }
+}
+
+pub fn index(path: &str, index_path: &str, max_memory: usize) -> Result<()> {
+    println!("Indexing {} to {}...", path, index_path);
+    let stats = index::build(path, index_path, max_memory)?;
+    println!(
+        "Indexed {} preimages ({} repeated storage slots skipped)",
+        stats.entries, stats.duplicates
+    );
+    Ok(())
+}
+
+pub fn lookup(path: &str, index_path: &str, hashes: &[B256]) -> Result<()> {
+    let store = PreimageStore::open(path, index_path)?;
+    let mut missing = 0;
+    for hash in hashes {
+        match store.get(*hash) {
+            Some(preimage) => println!("{}: {}", hash, preimage),
+            None => {
+                println!("{}: not found", hash);
+                missing += 1;
+            }
+        }
+    }
+    if missing > 0 {
+        bail!("{} of {} hashes not found", missing, hashes.len());
+    }
+    Ok(())
+}
+
+pub fn dump(path: &str, format: DumpFormat) -> Result<()> {
+    let it = PreimageFileReader::open(path)?;
+    eprintln!(
+        "Preimage file block number: {} ({} ordering, chain id {})",
+        it.header().block_number,
+        it.header().order,
+        it.header().chain_id
+    );
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    write_dump(it, format, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes the items of `it` to `out` in `format`, one line per item.
+fn write_dump(
+    it: impl Iterator<Item = Result<AccountStorageItem>>,
+    format: DumpFormat,
+    out: &mut impl Write,
+) -> Result<()> {
+    if format == DumpFormat::Csv {
+        writeln!(out, "address,hashed_address,slot,hashed_slot,code_hash")?;
+    }
+    for entry in it {
+        match (entry?, format) {
+            (AccountStorageItem::Account(address), DumpFormat::Jsonl) => writeln!(
+                out,
+                r#"{{"address":"{:#x}","hashed_address":"{:#x}"}}"#,
+                address,
+                keccak256(address)
+            )?,
+            (AccountStorageItem::Account(address), DumpFormat::Csv) => {
+                writeln!(out, "{:#x},{:#x},,,", address, keccak256(address))?
+            }
+            (AccountStorageItem::StorageSlot(address, ss), DumpFormat::Jsonl) => writeln!(
+                out,
+                r#"{{"address":"{:#x}","hashed_address":"{:#x}","slot":"{:#x}","hashed_slot":"{:#x}"}}"#,
+                address,
+                keccak256(address),
+                ss,
+                keccak256(ss)
+            )?,
+            (AccountStorageItem::StorageSlot(address, ss), DumpFormat::Csv) => writeln!(
+                out,
+                "{:#x},{:#x},{:#x},{:#x},",
+                address,
+                keccak256(address),
+                ss,
+                keccak256(ss)
+            )?,
+            (AccountStorageItem::Code(address, code_hash), DumpFormat::Jsonl) => writeln!(
+                out,
+                r#"{{"address":"{:#x}","hashed_address":"{:#x}","code_hash":"{:#x}"}}"#,
+                address,
+                keccak256(address),
+                code_hash
+            )?,
+            (AccountStorageItem::Code(address, code_hash), DumpFormat::Csv) => writeln!(
+                out,
+                "{:#x},{:#x},,,{:#x}",
+                address,
+                keccak256(address),
+                code_hash
+            )?,
+        }
+    }
+    Ok(())
+}
+
+/// Counts the storage slots of `source` by their `N`-byte prefix, returning the prefixes seen more than
+/// once and the total number of storage slots.
+fn storage_slot_prefix_counts<const N: usize>(
+    source: &impl StateSource,
+) -> Result<(HashMap<[u8; N], u32>, u64)> {
     let mut counts: HashMap<[u8; N], u32> = HashMap::new();
     let mut pb = AddressProgressBar::new(false);
-    let it = PlainIterator::new(tx)?;
+    let it = PlainIterator::new(source)?;
     let mut total_storage_slots = 0;
     for entry in it {
         match entry {
@@ -85,11 +1340,20 @@ pub fn storage_slot_freq<const N: usize>(tx: &Tx<RO>, top_n_detail: usize) -> Re
                     counts.retain(|_, count| *count > 1);
                 }
             }
+            Ok(AccountStorageItem::Code(..)) => {}
             Err(e) => return Err(e),
         }
     }
     // Only keep storage slots that are _potentially_ worth deduping.
     counts.retain(|_, count| *count > 1);
+    Ok((counts, total_storage_slots))
+}
+
+pub fn storage_slot_freq<const N: usize>(
+    source: &impl StateSource,
+    top_n_detail: usize,
+) -> Result<()> {
+    let (counts, total_storage_slots) = storage_slot_prefix_counts::<N>(source)?;
 
     let mut counts_vec = counts.iter().collect::<Vec<_>>();
     counts_vec.sort_unstable_by_key(|(_, v)| std::cmp::Reverse(*v));
@@ -112,3 +1376,479 @@ pub fn storage_slot_freq<const N: usize>(tx: &Tx<RO>, top_n_detail: usize) -> Re
 
     Ok(())
 }
+
+/// Returns the slot dictionary with the `size` most frequent storage slot prefixes of `source`, using the
+/// same frequency analysis as [`storage_slot_freq`].
+pub fn slot_dictionary(source: &impl StateSource, size: usize) -> Result<SlotDictionary> {
+    let (counts, total_storage_slots) = storage_slot_prefix_counts::<SLOT_PREFIX_LEN>(source)?;
+
+    let mut counts_vec = counts.into_iter().collect::<Vec<_>>();
+    // Ties are broken by prefix, so the dictionary doesn't depend on the hash map iteration order.
+    counts_vec.sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    counts_vec.truncate(size);
+    let covered: u64 = counts_vec.iter().map(|(_, count)| *count as u64).sum();
+    eprintln!(
+        "Slot dictionary of {} prefixes covers {} of {} storage slots ({:.2}%)",
+        counts_vec.len(),
+        covered,
+        total_storage_slots,
+        covered as f64 / total_storage_slots.max(1) as f64 * 100.0
+    );
+    SlotDictionary::new(counts_vec.into_iter().map(|(prefix, _)| prefix).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::U256;
+    use eth_stateless::{Account, MemoryStateSource};
+    use AccountStorageItem::{Account as A, Code as C, StorageSlot as S};
+
+    fn address(i: u8) -> Address {
+        Address::with_last_byte(i)
+    }
+
+    fn slot(i: u8) -> B256 {
+        B256::with_last_byte(i)
+    }
+
+    /// Accounts 1 to 4, with the storage slots 1, 1 to 3, none and 4.
+    fn source() -> MemoryStateSource {
+        let mut source = MemoryStateSource::default();
+        let accounts: [(u8, &[u8]); 4] = [(1, &[1]), (2, &[1, 2, 3]), (3, &[]), (4, &[4])];
+        for (i, slots) in accounts {
+            source.insert_account(address(i), Account::default());
+            for j in slots {
+                source.insert_storage_slot(address(i), slot(*j), U256::from(1));
+            }
+        }
+        source
+    }
+
+    /// Writes a plain delimited file of `accounts` with their storage slots, in the given order.
+    fn file(accounts: &[(u8, &[u8])]) -> Vec<u8> {
+        let header = Header {
+            order: Order::Plain,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::ZERO,
+        };
+        let mut w = PreimageWriter::new(Vec::new(), FileFormat::Delimited, &header).unwrap();
+        for (i, slots) in accounts {
+            w.write_account(address(*i)).unwrap();
+            for j in *slots {
+                w.write_storage_slot(slot(*j)).unwrap();
+            }
+        }
+        w.finish().unwrap().0
+    }
+
+    fn verify_file(data: &[u8], max_errors: Option<usize>) -> Report {
+        let source = source();
+        let mut report = Report::new(max_errors);
+        verify_delimited(
+            PreimageReader::new(data).unwrap(),
+            Order::Plain,
+            PlainIterator::new(&source).unwrap(),
+            &mut report,
+            &mut AddressProgressBar::hidden(),
+            None,
+            None,
+            false,
+        )
+        .unwrap();
+        report
+    }
+
+    fn issues(report: &Report) -> Vec<(u64, &Problem)> {
+        report
+            .issues()
+            .iter()
+            .map(|issue| (issue.offset.expect("located issue"), &issue.problem))
+            .collect()
+    }
+
+    #[test]
+    fn verifies_a_matching_file() {
+        let data = file(&[(1, &[1]), (2, &[1, 2, 3]), (3, &[]), (4, &[4])]);
+        let report = verify_file(&data, None);
+        assert!(report.is_ok());
+        assert_eq!((report.accounts, report.storage_slots), (4, 5));
+    }
+
+    #[test]
+    fn reports_missing_and_extra_accounts_and_storage_slots() {
+        // Offsets: the header is 55 bytes, account records 25 and storage slots 32.
+        let data = file(&[(1, &[1]), (2, &[1, 3, 4]), (4, &[]), (5, &[])]);
+        let report = verify_file(&data, None);
+        assert_eq!(
+            issues(&report),
+            vec![
+                (169, &Problem::MissingStorageSlot(address(2), slot(2))),
+                (201, &Problem::ExtraStorageSlot(address(2), slot(4))),
+                (233, &Problem::MissingAccount(address(3))),
+                // At the end of the storage slots of the account.
+                (258, &Problem::MissingStorageSlot(address(4), slot(4))),
+                (258, &Problem::ExtraAccount(address(5))),
+            ]
+        );
+        assert_eq!((report.accounts, report.storage_slots), (4, 4));
+
+        // The full scan stops at the maximum number of errors, and the default one at the first.
+        for max_errors in [1, 2] {
+            let report = verify_file(&data, Some(max_errors));
+            assert!(report.is_full());
+            assert_eq!(
+                issues(&report),
+                issues(&verify_file(&data, None))[..max_errors]
+            );
+        }
+    }
+
+    #[test]
+    fn reports_duplicate_and_unordered_records() {
+        let data = file(&[
+            (1, &[1]),
+            (2, &[1, 1, 3, 2]),
+            (2, &[]),
+            (1, &[]),
+            (3, &[]),
+            (4, &[4]),
+        ]);
+        let report = verify_file(&data, None);
+        assert_eq!(
+            issues(&report),
+            vec![
+                (169, &Problem::DuplicateStorageSlot(address(2), slot(1))),
+                (233, &Problem::UnorderedStorageSlot(address(2), slot(2))),
+                // The storage slot out of order is skipped, so it's missing before the next one.
+                (201, &Problem::MissingStorageSlot(address(2), slot(2))),
+                (265, &Problem::DuplicateAccount(address(2))),
+                (290, &Problem::UnorderedAccount(address(1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_trailing_bytes_and_malformed_records() {
+        let mut data = file(&[(1, &[1]), (2, &[1, 2, 3]), (3, &[]), (4, &[4])]);
+        let len = data.len() as u64;
+        data.extend_from_slice(&[0xde, 0xad]);
+        let report = verify_file(&data, None);
+        assert_eq!(issues(&report), vec![(len, &Problem::TrailingBytes)]);
+
+        // Truncated in the storage slot of the last account, which is then missing.
+        let truncated = &data[..len as usize - 49 - 16];
+        let report = verify_file(truncated, None);
+        let offset = truncated.len() as u64;
+        assert_eq!(
+            issues(&report),
+            vec![
+                (
+                    offset,
+                    &Problem::Malformed("reading storage slot preimage".to_string())
+                ),
+                (offset, &Problem::MissingAccount(address(4))),
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_files_and_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        let data = file(&[(1, &[1]), (2, &[1, 2, 3]), (3, &[]), (4, &[4])]);
+        std::fs::write(path, &data).unwrap();
+        let report = check_file(path, None).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.accounts, report.storage_slots), (4, 5));
+
+        // The last byte of the checksum, reported once the footer was read.
+        std::fs::write(path, flipped_byte(&data, data.len() - 1)).unwrap();
+        let report = check_file(path, None).unwrap();
+        assert_eq!(report.issues().len(), 1);
+        assert_eq!(report.issues()[0].offset, Some(data.len() as u64));
+        assert!(matches!(
+            &report.issues()[0].problem,
+            Problem::Malformed(e) if e.starts_with("Checksum mismatch")
+        ));
+        assert!(check(path, None).is_err());
+
+        // Two chunks, the first one ending with the account past the chunk size.
+        let header = PreimageReader::new(&data[..]).unwrap().header().cloned();
+        let mut w =
+            ChunkedWriter::new(path, &header.unwrap(), Encoding::None, None, 55 + 2 * 57).unwrap();
+        for i in 1..=4 {
+            w.write_account(address(i)).unwrap();
+            w.write_storage_slot(slot(i)).unwrap();
+        }
+        let (manifest, _) = w.finish().unwrap();
+        let manifest_path = chunks::manifest_path(path);
+        assert_eq!(manifest.chunks.len(), 2);
+        let report = check_chunks(&manifest_path, None).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.accounts, report.storage_slots), (4, 4));
+        check(&manifest_path, None).unwrap();
+
+        let chunk_path = manifest.chunk_path(&manifest.chunks[0]);
+        let chunk = std::fs::read(&chunk_path).unwrap();
+        let checksum_offset = chunk.len() - 32;
+        std::fs::write(&chunk_path, flipped_byte(&chunk, checksum_offset)).unwrap();
+        let report = check_chunks(&manifest_path, None).unwrap();
+        assert_eq!(
+            report.issues()[0].problem,
+            Problem::ManifestMismatch(format!(
+                "Chunk {} has SHA-256 {}, the manifest has {}",
+                chunk_path,
+                chunks::file_sha256(&chunk_path).unwrap().1,
+                manifest.chunks[0].sha256
+            ))
+        );
+        assert_eq!(report.issues().len(), 1);
+
+        std::fs::remove_file(&chunk_path).unwrap();
+        let report = check_chunks(&manifest_path, None).unwrap();
+        assert_eq!(
+            report.issues()[0].problem,
+            Problem::ManifestMismatch(format!("opening {}", chunk_path))
+        );
+        assert_eq!(
+            report.issues()[0].file.as_deref(),
+            Some("preimages.bin.chunk-00000")
+        );
+        // The second chunk is still checked.
+        assert_eq!(report.issues().len(), 1);
+        assert_eq!(report.accounts, manifest.chunks[1].accounts);
+    }
+
+    /// Returns `data` with the byte at `offset` flipped.
+    fn flipped_byte(data: &[u8], offset: usize) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data[offset] ^= 0xff;
+        data
+    }
+
+    #[test]
+    fn verifies_files_against_the_hashed_state() {
+        let source = source();
+        let hashed: Vec<HashedAccount> = source
+            .hashed_accounts(B256::ZERO)
+            .unwrap()
+            .map(|entry| {
+                let (key, address, _) = entry.unwrap();
+                let slots = source.hashed_storage(address, None).unwrap();
+                (key, slots.map(|entry| entry.unwrap().0).collect())
+            })
+            .collect();
+
+        // The third account and a storage slot of the second one are missing from the file, which has
+        // another account and storage slot instead.
+        let mut accounts = vec![
+            (address(1), vec![slot(1)]),
+            (address(2), vec![slot(1), slot(3), slot(9)]),
+            (address(4), vec![slot(4)]),
+            (address(5), vec![]),
+        ];
+        accounts.sort_by_key(|(address, _)| keccak256(address));
+        let header = Header {
+            order: Order::Eip7748,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::ZERO,
+        };
+        let mut w = PreimageWriter::new(Vec::new(), FileFormat::Delimited, &header).unwrap();
+        // Offsets of the accounts, their end and their storage slots.
+        let mut offsets = HashMap::new();
+        let mut offset = 55;
+        for (address, slots) in &mut accounts {
+            slots.sort_by_key(|slot| keccak256(slot));
+            w.write_account(*address).unwrap();
+            let account_offset = offset;
+            offset += 25;
+            let mut slot_offsets = Vec::new();
+            for slot in slots.iter() {
+                w.write_storage_slot(*slot).unwrap();
+                slot_offsets.push((keccak256(slot), offset));
+                offset += 32;
+            }
+            offsets.insert(*address, (account_offset, offset, slot_offsets));
+        }
+        let data = w.finish().unwrap().0;
+
+        let mut report = Report::new(None);
+        verify_hashed_delimited(
+            PreimageReader::new(&data[..]).unwrap(),
+            &mut hashed.into_iter().map(Ok).peekable(),
+            None,
+            &mut report,
+            &mut AddressProgressBar::hidden(),
+        )
+        .unwrap();
+
+        // Missing preimages are reported at the offset of the next account or storage slot.
+        let missing_account = keccak256(address(3));
+        let next_account = accounts
+            .iter()
+            .find(|(address, _)| keccak256(address) > missing_account)
+            .map_or(offset, |(address, _)| offsets[address].0);
+        let (_, end_offset, slot_offsets) = &offsets[&address(2)];
+        let missing_slot = keccak256(slot(2));
+        let next_slot = slot_offsets
+            .iter()
+            .find(|(key, _)| *key > missing_slot)
+            .map_or(*end_offset, |(_, offset)| *offset);
+        let extra_slot = slot_offsets
+            .iter()
+            .find(|(key, _)| *key == keccak256(slot(9)))
+            .unwrap()
+            .1;
+        let mut expected = vec![
+            (
+                next_account,
+                Problem::MissingAccountPreimage(missing_account),
+            ),
+            (offsets[&address(5)].0, Problem::ExtraAccount(address(5))),
+            (
+                next_slot,
+                Problem::MissingStorageSlotPreimage(address(2), missing_slot),
+            ),
+            (extra_slot, Problem::ExtraStorageSlot(address(2), slot(9))),
+        ];
+        let mut issues: Vec<_> = issues(&report)
+            .into_iter()
+            .map(|(offset, problem)| (offset, problem.to_string()))
+            .collect();
+        issues.sort();
+        expected.sort_by_key(|(offset, problem)| (*offset, problem.to_string()));
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(offset, problem)| (offset, problem.to_string()))
+            .collect();
+        assert_eq!(issues, expected);
+        assert_eq!((report.accounts, report.storage_slots), (4, 5));
+    }
+
+    #[test]
+    fn rejects_files_of_another_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, file(&[(1, &[1])])).unwrap();
+        let source = source();
+        let verify = |order, chain_id| {
+            verify(
+                path,
+                order,
+                chain_id,
+                PlainIterator::new(&source).unwrap(),
+                false,
+                None,
+                AddressProgressBar::hidden(),
+                None,
+                None,
+                false,
+            )
+            .unwrap_err()
+            .to_string()
+        };
+        assert_eq!(
+            verify(Order::Plain, 5),
+            "Preimage file is for chain id 1, expected 5"
+        );
+        assert_eq!(
+            verify(Order::Eip7748, 1),
+            "Preimage file has plain ordering, expected eip7748"
+        );
+
+        let delta_path = dir.path().join("delta.bin");
+        let delta_path = delta_path.to_str().unwrap();
+        let delta = StateDelta {
+            chain_id: 5,
+            from_block: 100,
+            to_block: 110,
+            state_root: B256::ZERO,
+            removals: true,
+            accounts: Default::default(),
+        };
+        delta
+            .write(File::create(delta_path).unwrap(), true)
+            .unwrap();
+        let output_path = dir.path().join("output.bin");
+        let err = apply_delta(path, delta_path, output_path.to_str().unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The preimages file is for chain id 1 but the delta for chain id 5"
+        );
+    }
+
+    #[test]
+    fn rejects_the_full_scan_of_raw_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, address(1)).unwrap();
+        let source = source();
+        let verify = |full_scan| {
+            verify(
+                path,
+                Order::Plain,
+                1,
+                PlainIterator::new(&source).unwrap(),
+                full_scan,
+                None,
+                AddressProgressBar::hidden(),
+                None,
+                None,
+                false,
+            )
+        };
+        let err = verify(true).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("--full-scan requires a delimited"));
+        // Stopping at the first mismatch, the storage slot of the first account.
+        let err = verify(false).unwrap_err();
+        assert!(err.to_string().contains("preimage (address: "));
+    }
+
+    #[test]
+    fn dumps_every_item_as_jsonl_and_csv() {
+        let address = Address::with_last_byte(1);
+        let (slot, code_hash) = (B256::with_last_byte(2), B256::repeat_byte(0xcc));
+        let items = || [A(address), S(address, slot), C(address, code_hash)].map(Ok);
+        let (hashed_address, hashed_slot) = (keccak256(address), keccak256(slot));
+
+        let mut out = Vec::new();
+        write_dump(items().into_iter(), DumpFormat::Jsonl, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                concat!(
+                    "{{\"address\":\"{0:#x}\",\"hashed_address\":\"{1:#x}\"}}\n",
+                    "{{\"address\":\"{0:#x}\",\"hashed_address\":\"{1:#x}\",\"slot\":\"{2:#x}\",\"hashed_slot\":\"{3:#x}\"}}\n",
+                    "{{\"address\":\"{0:#x}\",\"hashed_address\":\"{1:#x}\",\"code_hash\":\"{4:#x}\"}}\n",
+                ),
+                address, hashed_address, slot, hashed_slot, code_hash
+            )
+        );
+
+        let mut out = Vec::new();
+        write_dump(items().into_iter(), DumpFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "address,hashed_address,slot,hashed_slot,code_hash\n{0:#x},{1:#x},,,\n{0:#x},{1:#x},{2:#x},{3:#x},\n{0:#x},{1:#x},,,{4:#x}\n",
+                address, hashed_address, slot, hashed_slot, code_hash
+            )
+        );
+
+        let failing = [
+            Ok(A(address)),
+            Err(anyhow!("Truncated record at offset 55")),
+        ];
+        let mut out = Vec::new();
+        let err = write_dump(failing.into_iter(), DumpFormat::Jsonl, &mut out).unwrap_err();
+        assert_eq!(err.to_string(), "Truncated record at offset 55");
+    }
+}
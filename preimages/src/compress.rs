@@ -0,0 +1,332 @@
+//! Compression of preimage files.
+//!
+//! `generate` can compress the file it writes with zstd or xz as a stream, which also works when it's
+//! written to the standard output. Compressed files are detected from their magic bytes, so the commands
+//! reading preimage files decompress them transparently. Offsets in reports and checkpoints are offsets
+//! in the decompressed content.
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    ops::RangeInclusive,
+};
+use xz2::{read::XzDecoder, write::XzEncoder};
+
+/// Path standing for the standard input or output.
+pub const STDIO_PATH: &str = "-";
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// Returns the compression of the content starting with `buf`, if any.
+    pub fn detect(buf: &[u8]) -> Option<Self> {
+        if buf.starts_with(&ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if buf.starts_with(&XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Compression::Zstd => zstd::DEFAULT_COMPRESSION_LEVEL as u32,
+            Compression::Xz => 6,
+        }
+    }
+
+    fn levels(self) -> RangeInclusive<u32> {
+        match self {
+            Compression::Zstd => 1..=22,
+            Compression::Xz => 0..=9,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Xz => write!(f, "xz"),
+        }
+    }
+}
+
+/// Compression and level of a written file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compressor {
+    pub compression: Compression,
+    pub level: u32,
+}
+
+impl Compressor {
+    /// Creates a compressor, with the default level of `compression` unless `level` is set.
+    pub fn new(compression: Compression, level: Option<u32>) -> Result<Self> {
+        let level = level.unwrap_or(compression.default_level());
+        let levels = compression.levels();
+        if !levels.contains(&level) {
+            bail!(
+                "Invalid {} compression level {}, expected {} to {}",
+                compression,
+                level,
+                levels.start(),
+                levels.end()
+            );
+        }
+        Ok(Self { compression, level })
+    }
+}
+
+/// Opens the preimage file at `path`, or the standard input if it's `-`, decompressing it if needed.
+pub fn open(path: &str) -> Result<Box<dyn BufRead + Send>> {
+    let r: Box<dyn BufRead + Send> = match path {
+        STDIO_PATH => Box::new(BufReader::new(io::stdin())),
+        _ => Box::new(BufReader::new(
+            File::open(path).with_context(|| format!("opening {}", path))?,
+        )),
+    };
+    decompress(r)
+}
+
+/// Returns `r`, decompressing it if its content is compressed.
+fn decompress(mut r: Box<dyn BufRead + Send>) -> Result<Box<dyn BufRead + Send>> {
+    Ok(match Compression::detect(r.fill_buf()?) {
+        None => r,
+        Some(Compression::Zstd) => Box::new(BufReader::new(zstd::Decoder::with_buffer(r)?)),
+        Some(Compression::Xz) => Box::new(BufReader::new(XzDecoder::new(r))),
+    })
+}
+
+pub type Output = CompressedWriter<BufWriter<Destination>>;
+
+/// Creates the file at `path`, or writes to the standard output if it's `-`, compressing what's written
+/// with `compressor` if set.
+pub fn create(path: &str, compressor: Option<Compressor>) -> Result<Output> {
+    let w = match path {
+        STDIO_PATH => Destination::Stdout(io::stdout()),
+        _ => Destination::File(File::create(path).with_context(|| format!("creating {}", path))?),
+    };
+    CompressedWriter::new(BufWriter::new(w), compressor)
+}
+
+/// File or standard output written by an [`Output`].
+pub enum Destination {
+    File(File),
+    Stdout(io::Stdout),
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Destination::File(w) => w.write(buf),
+            Destination::Stdout(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Destination::File(w) => w.flush(),
+            Destination::Stdout(w) => w.flush(),
+        }
+    }
+}
+
+pub enum CompressedWriter<W: Write> {
+    Plain(W),
+    Zstd(zstd::Encoder<'static, Counting<W>>),
+    Xz(XzEncoder<Counting<W>>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(w: W, compressor: Option<Compressor>) -> Result<Self> {
+        let Some(Compressor { compression, level }) = compressor else {
+            return Ok(CompressedWriter::Plain(w));
+        };
+        let w = Counting { inner: w, bytes: 0 };
+        Ok(match compression {
+            Compression::Zstd => CompressedWriter::Zstd(zstd::Encoder::new(w, level as i32)?),
+            Compression::Xz => CompressedWriter::Xz(XzEncoder::new(w, level)),
+        })
+    }
+
+    /// Ends the compressed stream and flushes it, returning the number of compressed bytes written if
+    /// compressed.
+    pub fn finish(self) -> io::Result<Option<u64>> {
+        let (mut w, bytes) = match self {
+            CompressedWriter::Plain(w) => (w, None),
+            CompressedWriter::Zstd(encoder) => {
+                let counting = encoder.finish()?;
+                (counting.inner, Some(counting.bytes))
+            }
+            CompressedWriter::Xz(encoder) => {
+                let counting = encoder.finish()?;
+                (counting.inner, Some(counting.bytes))
+            }
+        };
+        w.flush()?;
+        Ok(bytes)
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Plain(w) => w.write(buf),
+            CompressedWriter::Zstd(w) => w.write(buf),
+            CompressedWriter::Xz(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Plain(w) => w.flush(),
+            CompressedWriter::Zstd(w) => w.flush(),
+            CompressedWriter::Xz(w) => w.flush(),
+        }
+    }
+}
+
+/// Writer whose content can be synced to disk, so a checkpoint never points past data that isn't on it.
+pub trait SyncWrite: Write {
+    /// Flushes what was written and syncs it to disk.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl SyncWrite for Output {
+    /// Only uncompressed files are synced, compressed streams and the standard output can't be resumed.
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        match self {
+            CompressedWriter::Plain(w) => match w.get_ref() {
+                Destination::File(file) => file.sync_all(),
+                Destination::Stdout(_) => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl SyncWrite for BufWriter<File> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_ref().sync_all()
+    }
+}
+
+/// Wraps a writer counting the bytes written to it.
+pub struct Counting<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Content compressing well, starting like an uncompressed delimited file.
+    fn content() -> Vec<u8> {
+        b"ESPF".iter().copied().cycle().take(100_000).collect()
+    }
+
+    fn read_all(mut r: impl Read) -> Vec<u8> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn round_trips_compressed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for compression in [Compression::Zstd, Compression::Xz] {
+            let path = dir.path().join(format!("preimages.bin.{}", compression));
+            let path = path.to_str().unwrap();
+            let compressor = Compressor::new(compression, None).unwrap();
+            let mut w = create(path, Some(compressor)).unwrap();
+            w.write_all(&content()).unwrap();
+            // Compressed streams aren't synced, but still flushed.
+            w.sync().unwrap();
+            let compressed_bytes = w.finish().unwrap().unwrap();
+
+            let data = std::fs::read(path).unwrap();
+            assert_eq!(data.len() as u64, compressed_bytes);
+            assert!(data.len() < content().len() / 10, "{}", compression);
+            assert_eq!(Compression::detect(&data), Some(compression));
+            assert_eq!(read_all(open(path).unwrap()), content());
+            let r = decompress(Box::new(io::Cursor::new(data))).unwrap();
+            assert_eq!(read_all(r), content());
+        }
+    }
+
+    #[test]
+    fn reads_uncompressed_files_as_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        let mut w = create(path, None).unwrap();
+        w.write_all(&content()).unwrap();
+        // Synced to disk before the writer is finished.
+        w.sync().unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), content());
+        assert_eq!(w.finish().unwrap(), None);
+
+        assert_eq!(Compression::detect(&content()), None);
+        // Shorter than the magic bytes, or only starting like them.
+        assert_eq!(Compression::detect(&ZSTD_MAGIC[..2]), None);
+        assert_eq!(Compression::detect(&XZ_MAGIC[..5]), None);
+        assert_eq!(read_all(open(path).unwrap()), content());
+        assert!(read_all(decompress(Box::new(&[][..])).unwrap()).is_empty());
+        assert!(open(&format!("{}.missing", path)).is_err());
+    }
+
+    #[test]
+    fn writes_the_standard_output() {
+        let w = create(STDIO_PATH, None).unwrap();
+        assert!(matches!(
+            &w,
+            CompressedWriter::Plain(w) if matches!(w.get_ref(), Destination::Stdout(_))
+        ));
+        let compressor = Compressor::new(Compression::Zstd, None).unwrap();
+        assert!(matches!(
+            create(STDIO_PATH, Some(compressor)).unwrap(),
+            CompressedWriter::Zstd(_)
+        ));
+    }
+
+    #[test]
+    fn validates_compression_levels() {
+        let level = |compression, level| Compressor::new(compression, level).map(|c| c.level);
+        assert_eq!(level(Compression::Zstd, None).unwrap(), 3);
+        assert_eq!(level(Compression::Xz, None).unwrap(), 6);
+        assert_eq!(level(Compression::Zstd, Some(22)).unwrap(), 22);
+        assert_eq!(level(Compression::Xz, Some(0)).unwrap(), 0);
+        assert_eq!(
+            level(Compression::Zstd, Some(0)).unwrap_err().to_string(),
+            "Invalid zstd compression level 0, expected 1 to 22"
+        );
+        assert!(level(Compression::Xz, Some(10)).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Simulation of the EIP-7748 state conversion.
+//!
+//! EIP-7748 converts the state in the EIP-7748 ordering, a fixed number of conversion units (the stride)
+//! per block. A conversion unit is a storage slot or an account: its data (nonce, balance and code hash)
+//! along with its code, in chunks, converted after its storage slots. The code chunks are reported, but
+//! they don't count as units. A [`Timeline`] cuts the conversion units in blocks for a given stride.
+
+use anyhow::Result;
+use std::io::Write;
+
+use crate::cmds::DumpFormat;
+
+/// Size of the code chunks of the converted tree.
+pub const CODE_CHUNK_SIZE: u64 = 31;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    /// An account, with the number of chunks of its code if it's converted with it.
+    Account {
+        code_chunks: Option<u64>,
+    },
+    StorageSlot,
+}
+
+/// What a range of conversion units converts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Batch {
+    pub accounts: u64,
+    pub storage_slots: u64,
+    /// Accounts converted with their code.
+    pub codes: u64,
+    pub code_chunks: u64,
+}
+
+impl Batch {
+    pub fn units(&self) -> u64 {
+        self.accounts + self.storage_slots
+    }
+
+    /// Returns the size of the address and storage slot preimages needed to convert the batch, as in the
+    /// raw format.
+    pub fn preimage_bytes(&self) -> u64 {
+        20 * self.accounts + 32 * self.storage_slots
+    }
+
+    fn add(&mut self, unit: Unit) {
+        match unit {
+            Unit::Account { code_chunks } => {
+                self.accounts += 1;
+                if let Some(chunks) = code_chunks {
+                    self.codes += 1;
+                    self.code_chunks += chunks;
+                }
+            }
+            Unit::StorageSlot => self.storage_slots += 1,
+        }
+    }
+
+    fn extend(&mut self, other: &Batch) {
+        self.accounts += other.accounts;
+        self.storage_slots += other.storage_slots;
+        self.codes += other.codes;
+        self.code_chunks += other.code_chunks;
+    }
+}
+
+/// A block of the conversion, with what it converts and what was converted up to it (included).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub number: u64,
+    pub batch: Batch,
+    pub total: Batch,
+}
+
+/// Conversion of the state `stride` units per block, from `start_block`.
+pub struct Timeline {
+    pub stride: u64,
+    pub start_block: u64,
+    next_block: u64,
+    current: Batch,
+    total: Batch,
+}
+
+impl Timeline {
+    pub fn new(stride: u64, start_block: u64) -> Self {
+        Self {
+            stride,
+            start_block,
+            next_block: start_block,
+            current: Batch::default(),
+            total: Batch::default(),
+        }
+    }
+
+    /// Adds the next conversion unit, returning the block it completes, if any.
+    pub fn push(&mut self, unit: Unit) -> Option<Block> {
+        self.current.add(unit);
+        (self.current.units() >= self.stride).then(|| self.finish_block())
+    }
+
+    /// Returns the last block, converting less than `stride` units, if any.
+    pub fn finish(&mut self) -> Option<Block> {
+        (self.current.units() > 0).then(|| self.finish_block())
+    }
+
+    /// Returns the number of blocks of the conversion so far.
+    pub fn blocks(&self) -> u64 {
+        self.next_block - self.start_block
+    }
+
+    /// Returns what was converted so far.
+    pub fn total(&self) -> &Batch {
+        &self.total
+    }
+
+    fn finish_block(&mut self) -> Block {
+        let batch = std::mem::take(&mut self.current);
+        self.total.extend(&batch);
+        let block = Block {
+            number: self.next_block,
+            batch,
+            total: self.total,
+        };
+        self.next_block += 1;
+        block
+    }
+}
+
+/// Writes the header of the blocks in `format`, if any.
+pub fn write_header(out: &mut impl Write, format: DumpFormat) -> Result<()> {
+    if format == DumpFormat::Csv {
+        writeln!(
+            out,
+            "stride,block,accounts,storage_slots,codes,code_chunks,units,preimage_bytes,total_units,total_preimage_bytes,progress"
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes `block` of the conversion with `stride` in `format`, with its progress out of `accounts`.
+pub fn write_block(
+    out: &mut impl Write,
+    format: DumpFormat,
+    stride: u64,
+    block: &Block,
+    accounts: u64,
+) -> Result<()> {
+    let (batch, total) = (&block.batch, &block.total);
+    let progress = total.accounts as f64 / accounts.max(1) as f64 * 100.0;
+    match format {
+        DumpFormat::Jsonl => writeln!(
+            out,
+            r#"{{"stride":{},"block":{},"accounts":{},"storage_slots":{},"codes":{},"code_chunks":{},"units":{},"preimage_bytes":{},"total_units":{},"total_preimage_bytes":{},"progress":{:.4}}}"#,
+            stride,
+            block.number,
+            batch.accounts,
+            batch.storage_slots,
+            batch.codes,
+            batch.code_chunks,
+            batch.units(),
+            batch.preimage_bytes(),
+            total.units(),
+            total.preimage_bytes(),
+            progress
+        )?,
+        DumpFormat::Csv => writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{},{:.4}",
+            stride,
+            block.number,
+            batch.accounts,
+            batch.storage_slots,
+            batch.codes,
+            batch.code_chunks,
+            batch.units(),
+            batch.preimage_bytes(),
+            total.units(),
+            total.preimage_bytes(),
+            progress
+        )?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EOA: Unit = Unit::Account { code_chunks: None };
+    const CONTRACT: Unit = Unit::Account {
+        code_chunks: Some(4),
+    };
+
+    fn batch(accounts: u64, storage_slots: u64, codes: u64, code_chunks: u64) -> Batch {
+        Batch {
+            accounts,
+            storage_slots,
+            codes,
+            code_chunks,
+        }
+    }
+
+    #[test]
+    fn cuts_blocks_at_the_stride() {
+        let mut timeline = Timeline::new(3, 100);
+        let units = [
+            Unit::StorageSlot,
+            Unit::StorageSlot,
+            CONTRACT,
+            EOA,
+            Unit::StorageSlot,
+            CONTRACT,
+            EOA,
+        ];
+        let mut blocks: Vec<_> = units
+            .into_iter()
+            .filter_map(|unit| timeline.push(unit))
+            .collect();
+        blocks.extend(timeline.finish());
+        assert_eq!(
+            blocks,
+            vec![
+                Block {
+                    number: 100,
+                    batch: batch(1, 2, 1, 4),
+                    total: batch(1, 2, 1, 4),
+                },
+                Block {
+                    number: 101,
+                    batch: batch(2, 1, 1, 4),
+                    total: batch(3, 3, 2, 8),
+                },
+                Block {
+                    number: 102,
+                    batch: batch(1, 0, 0, 0),
+                    total: batch(4, 3, 2, 8),
+                },
+            ]
+        );
+        assert_eq!(timeline.blocks(), 3);
+        assert_eq!(timeline.total().units(), 7);
+        assert_eq!(timeline.total().preimage_bytes(), 4 * 20 + 3 * 32);
+    }
+
+    #[test]
+    fn converts_the_code_with_its_account() {
+        // The code chunks don't count as units, so the code can't be cut from its account.
+        let mut timeline = Timeline::new(1, 0);
+        let block = timeline.push(Unit::Account {
+            code_chunks: Some(1000),
+        });
+        assert_eq!(block.map(|block| block.batch), Some(batch(1, 0, 1, 1000)));
+        assert_eq!(timeline.finish(), None);
+    }
+
+    #[test]
+    fn writes_blocks() {
+        let block = Block {
+            number: 101,
+            batch: batch(2, 1, 1, 4),
+            total: batch(3, 3, 2, 8),
+        };
+        let mut csv = Vec::new();
+        write_header(&mut csv, DumpFormat::Csv).unwrap();
+        write_block(&mut csv, DumpFormat::Csv, 3, &block, 4).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "stride,block,accounts,storage_slots,codes,code_chunks,units,preimage_bytes,total_units,total_preimage_bytes,progress\n\
+             3,101,2,1,1,4,3,72,6,156,75.0000\n"
+        );
+
+        let mut jsonl = Vec::new();
+        write_header(&mut jsonl, DumpFormat::Jsonl).unwrap();
+        write_block(&mut jsonl, DumpFormat::Jsonl, 3, &block, 4).unwrap();
+        assert_eq!(
+            String::from_utf8(jsonl).unwrap(),
+            r#"{"stride":3,"block":101,"accounts":2,"storage_slots":1,"codes":1,"code_chunks":4,"units":3,"preimage_bytes":72,"total_units":6,"total_preimage_bytes":156,"progress":75.0000}"#
+                .to_string()
+                + "\n"
+        );
+    }
+}
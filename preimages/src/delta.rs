@@ -0,0 +1,526 @@
+//! Preimage deltas between two block heights.
+//!
+//! A delta contains the accounts and storage slots that were added (or removed) between two blocks,
+//! computed from the reth account and storage changesets. Applying it to a preimage file generated at
+//! the first block gives the same preimages a fresh generation at the second block would produce.
+//!
+//! The delta file layout is (all integers are big-endian):
+//!
+//! ```text
+//! header:  magic "ESPD" (4) | version (1) | has removals (1) | chain id (8) | from block (8) | to block (8) |
+//!          state root (32)
+//! account: 0x01 | address (20) | change (1) | storage slots count (4) | (change (1) | storage slot (32)) * count
+//! footer:  0xff | accounts count (8) | checksum (32)
+//! ```
+//!
+//! A change is `0` (unchanged), `1` (added) or `2` (removed). The checksum is the keccak256 of every byte
+//! preceding it in the file.
+
+use alloy_primitives::{Address, B256};
+use anyhow::{anyhow, bail, Context, Result};
+use reth_db::mdbx::tx::Tx;
+use reth_db::mdbx::RO;
+use reth_db::{AccountChangeSets, PlainAccountState, PlainStorageState, StorageChangeSets};
+use reth_db_api::cursor::{DbCursorRO, DbDupCursorRO};
+use reth_db_api::models::BlockNumberAddress;
+use reth_db_api::transaction::DbTx;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Read, Write};
+
+use crate::format::Hashing;
+
+pub const MAGIC: [u8; 4] = *b"ESPD";
+pub const VERSION: u8 = 1;
+
+const TAG_ACCOUNT: u8 = 0x01;
+const TAG_FOOTER: u8 = 0xff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+}
+
+impl Change {
+    fn reversed(self) -> Self {
+        match self {
+            Change::Added => Change::Removed,
+            Change::Removed => Change::Added,
+        }
+    }
+
+    fn to_byte(change: Option<Self>) -> u8 {
+        match change {
+            None => 0,
+            Some(Change::Added) => 1,
+            Some(Change::Removed) => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Option<Self>> {
+        match b {
+            0 => Ok(None),
+            1 => Ok(Some(Change::Added)),
+            2 => Ok(Some(Change::Removed)),
+            _ => Err(anyhow!("Unknown change {}", b)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AccountDelta {
+    /// Account change, or `None` if the account exists at both blocks.
+    pub change: Option<Change>,
+    pub storage_slots: BTreeMap<B256, Change>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StateDelta {
+    pub chain_id: u64,
+    pub from_block: u64,
+    pub to_block: u64,
+    /// State root at `to_block`.
+    pub state_root: B256,
+    /// Whether removed accounts and storage slots are included.
+    pub removals: bool,
+    pub accounts: BTreeMap<Address, AccountDelta>,
+}
+
+impl StateDelta {
+    /// Returns the delta going from `to_block` back to `from_block`, whose state root is `state_root`.
+    pub fn reversed(self, state_root: B256) -> Self {
+        Self {
+            chain_id: self.chain_id,
+            from_block: self.to_block,
+            to_block: self.from_block,
+            state_root,
+            removals: self.removals,
+            accounts: self
+                .accounts
+                .into_iter()
+                .map(|(address, delta)| {
+                    let delta = AccountDelta {
+                        change: delta.change.map(Change::reversed),
+                        storage_slots: delta
+                            .storage_slots
+                            .into_iter()
+                            .map(|(slot, change)| (slot, change.reversed()))
+                            .collect(),
+                    };
+                    (address, delta)
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the number of added and removed accounts and storage slots.
+    pub fn counts(&self) -> DeltaCounts {
+        let mut counts = DeltaCounts::default();
+        for delta in self.accounts.values() {
+            match delta.change {
+                Some(Change::Added) => counts.added_accounts += 1,
+                Some(Change::Removed) => counts.removed_accounts += 1,
+                None => {}
+            }
+            for change in delta.storage_slots.values() {
+                match change {
+                    Change::Added => counts.added_storage_slots += 1,
+                    Change::Removed => counts.removed_storage_slots += 1,
+                }
+            }
+        }
+        counts
+    }
+
+    pub fn write(&self, w: impl Write, include_removals: bool) -> Result<()> {
+        let removals = self.removals && include_removals;
+        let mut w = Hashing::new(w);
+        w.write_all(&MAGIC)?;
+        w.write_all(&[VERSION, removals as u8])?;
+        w.write_all(&self.chain_id.to_be_bytes())?;
+        w.write_all(&self.from_block.to_be_bytes())?;
+        w.write_all(&self.to_block.to_be_bytes())?;
+        w.write_all(self.state_root.as_slice())?;
+
+        let mut accounts: u64 = 0;
+        for (address, delta) in &self.accounts {
+            if !removals && delta.change == Some(Change::Removed) {
+                continue;
+            }
+            let storage_slots: Vec<_> = delta
+                .storage_slots
+                .iter()
+                .filter(|(_, change)| removals || **change == Change::Added)
+                .collect();
+            if delta.change.is_none() && storage_slots.is_empty() {
+                continue;
+            }
+            let count = u32::try_from(storage_slots.len())
+                .map_err(|_| anyhow!("Account {} has too many storage slots", address))?;
+
+            w.write_all(&[TAG_ACCOUNT])?;
+            w.write_all(address.as_slice())?;
+            w.write_all(&[Change::to_byte(delta.change)])?;
+            w.write_all(&count.to_be_bytes())?;
+            for (slot, change) in storage_slots {
+                w.write_all(&[Change::to_byte(Some(*change))])?;
+                w.write_all(slot.as_slice())?;
+            }
+            accounts += 1;
+        }
+
+        w.write_all(&[TAG_FOOTER])?;
+        w.write_all(&accounts.to_be_bytes())?;
+        let checksum = w.checksum();
+        w.write_all(checksum.as_slice())?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn read(r: impl BufRead) -> Result<Self> {
+        let mut r = Hashing::new(r);
+        let mut header = [0u8; MAGIC.len() + 2];
+        r.read_exact(&mut header).context("reading delta header")?;
+        if header[..MAGIC.len()] != MAGIC {
+            bail!("Not a preimage delta file");
+        }
+        let version = header[MAGIC.len()];
+        if version != VERSION {
+            bail!("Unsupported preimage delta file version {}", version);
+        }
+        let mut delta = StateDelta {
+            removals: header[MAGIC.len() + 1] != 0,
+            chain_id: read_u64(&mut r)?,
+            from_block: read_u64(&mut r)?,
+            to_block: read_u64(&mut r)?,
+            state_root: B256::default(),
+            accounts: BTreeMap::new(),
+        };
+        r.read_exact(delta.state_root.as_mut_slice())
+            .context("reading delta header")?;
+
+        loop {
+            let mut tag = [0u8; 1];
+            r.read_exact(&mut tag).context("reading delta record")?;
+            match tag[0] {
+                TAG_ACCOUNT => {
+                    let mut address = Address::default();
+                    let mut change = [0u8; 1];
+                    let mut count = [0u8; 4];
+                    r.read_exact(address.as_mut_slice())?;
+                    r.read_exact(&mut change)?;
+                    r.read_exact(&mut count)?;
+                    let mut account = AccountDelta {
+                        change: Change::from_byte(change[0])?,
+                        storage_slots: BTreeMap::new(),
+                    };
+                    for _ in 0..u32::from_be_bytes(count) {
+                        let mut slot = B256::default();
+                        r.read_exact(&mut change)?;
+                        r.read_exact(slot.as_mut_slice())?;
+                        let change = Change::from_byte(change[0])?
+                            .ok_or(anyhow!("Storage slot {} without change", slot))?;
+                        account.storage_slots.insert(slot, change);
+                    }
+                    delta.accounts.insert(address, account);
+                }
+                TAG_FOOTER => {
+                    let mut count = [0u8; 8];
+                    r.read_exact(&mut count)?;
+                    let expected_checksum = r.checksum();
+                    let mut checksum = B256::default();
+                    r.read_exact(checksum.as_mut_slice())?;
+                    if u64::from_be_bytes(count) != delta.accounts.len() as u64 {
+                        bail!("Delta footer accounts count mismatch");
+                    }
+                    if checksum != expected_checksum {
+                        bail!("Delta checksum mismatch");
+                    }
+                    break;
+                }
+                tag => bail!("Unknown delta record tag {:#04x}", tag),
+            }
+        }
+        if !r.into_inner().fill_buf()?.is_empty() {
+            bail!("Trailing bytes after the delta footer");
+        }
+        Ok(delta)
+    }
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf).context("reading delta header")?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+#[derive(Debug, Default)]
+pub struct DeltaCounts {
+    pub added_accounts: u64,
+    pub removed_accounts: u64,
+    pub added_storage_slots: u64,
+    pub removed_storage_slots: u64,
+}
+
+impl StateDelta {
+    /// Computes the delta between `from_block` and the plain state of the database, which must be at
+    /// `to_block`, from the account and storage changesets of the blocks in between.
+    ///
+    /// The changesets hold the value of each changed key _before_ the block, so the first change of a
+    /// key after `from_block` tells whether it existed at `from_block`.
+    ///
+    /// The `AccountsHistory` and `StoragesHistory` indices aren't used: they map each key to the blocks
+    /// that changed it, to look up the value of a given key at a past block, while the delta needs every
+    /// key changed in the range. The changesets of the range list exactly those keys in a single
+    /// sequential walk, whereas the indices would have to be scanned for every key of the state.
+    pub fn from_changesets(
+        tx: &Tx<RO>,
+        chain_id: u64,
+        from_block: u64,
+        to_block: u64,
+        state_root: B256,
+    ) -> Result<Self> {
+        if from_block > to_block {
+            bail!(
+                "Block {} is ahead of the database block {}",
+                from_block,
+                to_block
+            );
+        }
+        // The account and storage history are pruned independently, so either can start later.
+        let mut cursor_account_changes = tx.cursor_read::<AccountChangeSets>()?;
+        let mut cursor_storage_changes = tx.cursor_read::<StorageChangeSets>()?;
+        let first_blocks = [
+            (
+                "Account",
+                cursor_account_changes.first()?.map(|(block, _)| block),
+            ),
+            (
+                "Storage",
+                cursor_storage_changes
+                    .first()?
+                    .map(|(block_address, _)| block_address.block_number()),
+            ),
+        ];
+        for (changesets, first_block) in first_blocks {
+            if let Some(first_block) = first_block.filter(|block| *block > from_block + 1) {
+                bail!(
+                    "{} changesets before block {} are pruned, can't go back to block {}",
+                    changesets,
+                    first_block,
+                    from_block
+                );
+            }
+        }
+
+        let mut delta = StateDelta {
+            chain_id,
+            from_block,
+            to_block,
+            state_root,
+            removals: true,
+            accounts: BTreeMap::new(),
+        };
+
+        // Whether each changed account and storage slot existed at `from_block`.
+        let mut accounts_before = BTreeMap::<Address, bool>::new();
+        for entry in cursor_account_changes.walk_range(from_block + 1..=to_block)? {
+            let (_, before) = entry?;
+            accounts_before
+                .entry(before.address)
+                .or_insert(before.info.is_some());
+        }
+        let mut storage_slots_before = BTreeMap::<(Address, B256), bool>::new();
+        for entry in cursor_storage_changes
+            .walk_range(BlockNumberAddress::range(from_block + 1..to_block + 1))?
+        {
+            let (block_address, before) = entry?;
+            storage_slots_before
+                .entry((block_address.address(), before.key))
+                .or_insert(!before.value.is_zero());
+        }
+
+        for (address, existed) in accounts_before {
+            let exists = tx.get::<PlainAccountState>(address)?.is_some();
+            if existed != exists {
+                delta.accounts.entry(address).or_default().change = Some(if exists {
+                    Change::Added
+                } else {
+                    Change::Removed
+                });
+            }
+        }
+        let mut cursor_storage = tx.cursor_dup_read::<PlainStorageState>()?;
+        for ((address, slot), existed) in storage_slots_before {
+            let exists = cursor_storage
+                .seek_by_key_subkey(address, slot)?
+                .is_some_and(|entry| entry.key == slot);
+            if existed != exists {
+                let change = if exists {
+                    Change::Added
+                } else {
+                    Change::Removed
+                };
+                delta
+                    .accounts
+                    .entry(address)
+                    .or_default()
+                    .storage_slots
+                    .insert(slot, change);
+            }
+        }
+
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER_LEN: usize = MAGIC.len() + 2 + 8 + 8 + 8 + 32;
+
+    fn delta() -> StateDelta {
+        let account = |change, storage_slots: &[(u8, Change)]| AccountDelta {
+            change,
+            storage_slots: storage_slots
+                .iter()
+                .map(|(slot, change)| (B256::with_last_byte(*slot), *change))
+                .collect(),
+        };
+        StateDelta {
+            chain_id: 1,
+            from_block: 100,
+            to_block: 110,
+            state_root: B256::repeat_byte(0xaa),
+            removals: true,
+            accounts: BTreeMap::from([
+                (
+                    Address::with_last_byte(1),
+                    account(Some(Change::Added), &[(1, Change::Added)]),
+                ),
+                (
+                    Address::with_last_byte(2),
+                    account(None, &[(1, Change::Removed), (2, Change::Added)]),
+                ),
+                (
+                    Address::with_last_byte(3),
+                    account(Some(Change::Removed), &[(3, Change::Removed)]),
+                ),
+                (
+                    Address::with_last_byte(4),
+                    account(None, &[(4, Change::Removed)]),
+                ),
+            ]),
+        }
+    }
+
+    fn write(delta: &StateDelta, include_removals: bool) -> Vec<u8> {
+        let mut data = Vec::new();
+        delta.write(&mut data, include_removals).unwrap();
+        data
+    }
+
+    /// Account change and storage slot changes of an account.
+    type Changes = (Address, Option<Change>, Vec<(B256, Change)>);
+
+    /// Returns the delta accounts as comparable tuples.
+    fn accounts(delta: &StateDelta) -> Vec<Changes> {
+        delta
+            .accounts
+            .iter()
+            .map(|(address, account)| {
+                let slots = account.storage_slots.clone().into_iter().collect();
+                (*address, account.change, slots)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_delta_files() {
+        let data = write(&delta(), true);
+        let read = StateDelta::read(&data[..]).unwrap();
+        assert_eq!(accounts(&read), accounts(&delta()));
+        assert_eq!(
+            (
+                read.chain_id,
+                read.from_block,
+                read.to_block,
+                read.state_root
+            ),
+            (1, 100, 110, B256::repeat_byte(0xaa))
+        );
+        assert!(read.removals);
+
+        // Without removals, the removed account and the account with only removals are left out.
+        let read = StateDelta::read(&write(&delta(), false)[..]).unwrap();
+        assert!(!read.removals);
+        assert_eq!(
+            accounts(&read),
+            vec![
+                (
+                    Address::with_last_byte(1),
+                    Some(Change::Added),
+                    vec![(B256::with_last_byte(1), Change::Added)]
+                ),
+                (
+                    Address::with_last_byte(2),
+                    None,
+                    vec![(B256::with_last_byte(2), Change::Added)]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reverses_and_counts_changes() {
+        let counts = delta().counts();
+        assert_eq!(
+            (
+                counts.added_accounts,
+                counts.removed_accounts,
+                counts.added_storage_slots,
+                counts.removed_storage_slots
+            ),
+            (1, 1, 2, 3)
+        );
+
+        let reversed = delta().reversed(B256::repeat_byte(0xbb));
+        assert_eq!((reversed.from_block, reversed.to_block), (110, 100));
+        assert_eq!(reversed.state_root, B256::repeat_byte(0xbb));
+        let counts = reversed.counts();
+        assert_eq!(
+            (
+                counts.added_accounts,
+                counts.removed_accounts,
+                counts.added_storage_slots,
+                counts.removed_storage_slots
+            ),
+            (1, 1, 3, 2)
+        );
+        assert_eq!(
+            accounts(&reversed.reversed(B256::repeat_byte(0xaa))),
+            accounts(&delta())
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_files() {
+        let data = write(&delta(), true);
+        let read_err = |data: &[u8]| format!("{:#}", StateDelta::read(data).unwrap_err());
+        let flipped = |offset: usize| {
+            let mut data = data.clone();
+            data[offset] ^= 0xff;
+            data
+        };
+
+        assert!(read_err(&flipped(0)).contains("Not a preimage delta file"));
+        assert!(read_err(&flipped(MAGIC.len())).contains("Unsupported preimage delta file version"));
+        assert!(read_err(&flipped(HEADER_LEN + 1)).contains("Delta checksum mismatch"));
+        assert!(read_err(&flipped(data.len() - 33)).contains("accounts count mismatch"));
+        assert!(read_err(&data[..data.len() - 1]).contains("failed to fill whole buffer"));
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(read_err(&trailing).contains("Trailing bytes"));
+    }
+}
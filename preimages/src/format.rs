@@ -0,0 +1,1484 @@
+//! Preimage file formats.
+//!
+//! Three formats are supported:
+//! - Raw: a plain concatenation of 20-byte addresses and 32-byte storage slots. It can't be interpreted
+//!   without the database since there's no way to know where an account's storage slots end.
+//! - Delimited: a self-describing format with a header, storage slot counts after each address and a
+//!   footer.
+//! - Geth RLP: a stream of RLP strings, one per address or storage slot, as written by geth's
+//!   `export-preimages` and read by its `import-preimages` (see [`crate::geth`]).
+//!
+//! The delimited layout is (all integers are big-endian):
+//!
+//! ```text
+//! header:     magic "ESPF" (4) | version (1) | ordering (1) | encoding (1) | chain id (8) | block number (8) | state root (32)
+//! dictionary: prefixes count (1) | prefixes (29 * count), only with the slot dictionary encoding
+//! account:    0x01 | address (20) | storage slot runs
+//! run:        storage slots count (4) | storage slots
+//! code:       0x02 | code hash (32)
+//! footer:     0xff | accounts count (8) | storage slots count (8) | checksum (32)
+//! ```
+//!
+//! The storage slots of an account are written in runs of [`STORAGE_SLOTS_RUN`] storage slots, the last
+//! run having fewer (possibly none), so the writer only buffers a run instead of all the storage slots of
+//! the largest contracts. Code records follow the account record of their contract, and are only written
+//! for files generated with the code of the contracts. Addresses and storage slots are written as is (20
+//! and 32) unless the file has an encoding (see [`Encoding`]). The checksum is the keccak256 of every
+//! byte preceding it in the file.
+
+use alloy_primitives::{keccak256, Address, Keccak256, B256};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+pub const MAGIC: [u8; 4] = *b"ESPF";
+pub const VERSION: u8 = 1;
+
+const TAG_ACCOUNT: u8 = 0x01;
+/// Tag of the code hash of the contract of the previous account record.
+const TAG_CODE: u8 = 0x02;
+const TAG_FOOTER: u8 = 0xff;
+
+/// Number of storage slots of the runs of an account but the last one.
+pub const STORAGE_SLOTS_RUN: u32 = 4096;
+
+/// Tag of a storage slot written as is with the slot dictionary encoding.
+const TAG_LITERAL_STORAGE_SLOT: u8 = 0xff;
+
+/// Length of the storage slot prefixes of the slot dictionary encoding.
+pub const SLOT_PREFIX_LEN: usize = 29;
+/// Maximum number of prefixes of a slot dictionary, the last index being the literal tag.
+pub const MAX_SLOT_DICTIONARY_SIZE: usize = TAG_LITERAL_STORAGE_SLOT as usize;
+
+/// RLP string prefixes of addresses and storage slots in the geth RLP format.
+pub const RLP_ADDRESS_PREFIX: u8 = 0x80 + 20;
+pub const RLP_STORAGE_SLOT_PREFIX: u8 = 0x80 + 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FileFormat {
+    /// Concatenation of addresses and storage slots without any framing.
+    Raw,
+    /// Header, per-account storage slot counts and footer with totals and checksum.
+    Delimited,
+    /// RLP stream of addresses and storage slots, compatible with geth's `import-preimages`. Storage
+    /// slots shared by several accounts are written for each of them, which geth imports once since it
+    /// keys preimages by their hash.
+    GethRlp,
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileFormat::Raw => write!(f, "raw"),
+            FileFormat::Delimited => write!(f, "delimited"),
+            FileFormat::GethRlp => write!(f, "geth-rlp"),
+        }
+    }
+}
+
+/// Encoding of the storage slots of a delimited file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Storage slots are written as is.
+    #[default]
+    None,
+    /// Storage slots whose 29-byte prefix is in the dictionary are written as the prefix index and the
+    /// 3-byte suffix, and the others as the `0xff` tag followed by the storage slot.
+    SlotDictionary(SlotDictionary),
+    /// Addresses are written as the length of the prefix they share with the previous address (1)
+    /// followed by the rest of their bytes, and storage slots likewise against the previous storage slot
+    /// of the account, the first one against zero. Only for the plain ordering, where consecutive keys
+    /// are sorted and small storage slot indexes are mostly zeros.
+    FrontCoding,
+}
+
+impl Encoding {
+    fn to_byte(&self) -> u8 {
+        match self {
+            Encoding::None => 0,
+            Encoding::SlotDictionary(_) => 1,
+            Encoding::FrontCoding => 2,
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::None => write!(f, "none"),
+            Encoding::SlotDictionary(dictionary) => {
+                write!(f, "slot dictionary of {} prefixes", dictionary.len())
+            }
+            Encoding::FrontCoding => write!(f, "front coding"),
+        }
+    }
+}
+
+/// Dictionary of the most frequent storage slot prefixes, e.g: all zeros for small slot indexes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SlotDictionary {
+    prefixes: Vec<[u8; SLOT_PREFIX_LEN]>,
+    indexes: HashMap<[u8; SLOT_PREFIX_LEN], u8>,
+}
+
+impl SlotDictionary {
+    pub fn new(prefixes: Vec<[u8; SLOT_PREFIX_LEN]>) -> Result<Self> {
+        if prefixes.len() > MAX_SLOT_DICTIONARY_SIZE {
+            bail!(
+                "Slot dictionary has {} prefixes, the maximum is {}",
+                prefixes.len(),
+                MAX_SLOT_DICTIONARY_SIZE
+            );
+        }
+        let mut indexes = HashMap::with_capacity(prefixes.len());
+        for (i, prefix) in prefixes.iter().enumerate() {
+            if indexes.insert(*prefix, i as u8).is_some() {
+                bail!("Duplicate slot dictionary prefix {}", hex::encode(prefix));
+            }
+        }
+        Ok(Self { prefixes, indexes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    fn index(&self, slot: &B256) -> Option<u8> {
+        let prefix: &[u8; SLOT_PREFIX_LEN] = slot[..SLOT_PREFIX_LEN].try_into().ok()?;
+        self.indexes.get(prefix).copied()
+    }
+}
+
+/// A preimage of a hashed key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preimage {
+    Address(Address),
+    StorageSlot(B256),
+}
+
+impl Preimage {
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Preimage::Address(address) => address.as_slice(),
+            Preimage::StorageSlot(slot) => slot.as_slice(),
+        }
+    }
+}
+
+impl fmt::Display for Preimage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Preimage::Address(address) => write!(f, "address {}", address),
+            Preimage::StorageSlot(slot) => write!(f, "storage slot {}", slot),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Plain,
+    Eip7748,
+}
+
+impl Order {
+    /// Returns the key accounts are sorted by in this ordering.
+    pub fn account_key(self, address: Address) -> B256 {
+        match self {
+            Order::Plain => address.into_word(),
+            Order::Eip7748 => keccak256(address),
+        }
+    }
+
+    /// Returns the key storage slots are sorted by (within an account) in this ordering.
+    pub fn storage_slot_key(self, slot: B256) -> B256 {
+        match self {
+            Order::Plain => slot,
+            Order::Eip7748 => keccak256(slot),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Order::Plain => 0,
+            Order::Eip7748 => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            0 => Ok(Order::Plain),
+            1 => Ok(Order::Eip7748),
+            _ => Err(anyhow!("Unknown ordering {}", b)),
+        }
+    }
+}
+
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Order::Plain => write!(f, "plain"),
+            Order::Eip7748 => write!(f, "eip7748"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub order: Order,
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub state_root: B256,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Footer {
+    pub accounts: u64,
+    pub storage_slots: u64,
+    pub checksum: B256,
+}
+
+/// Position in a preimage file at an account boundary: the offset of the next account record and the
+/// totals of the records before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub offset: u64,
+    pub accounts: u64,
+    pub storage_slots: u64,
+}
+
+/// Wraps a reader or writer keeping track of the offset and keccak256 of the bytes that went through it.
+pub(crate) struct Hashing<T> {
+    inner: T,
+    hasher: Keccak256,
+    offset: u64,
+}
+
+impl<T> Hashing<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: Keccak256::new(),
+            offset: 0,
+        }
+    }
+
+    pub(crate) fn checksum(&self) -> B256 {
+        self.hasher.clone().finalize()
+    }
+
+    pub(crate) fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Size of a written preimage file compared with the raw format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub bytes: u64,
+    pub accounts: u64,
+    pub storage_slots: u64,
+    /// Storage slots written with their prefix index in the slot dictionary.
+    pub dictionary_storage_slots: u64,
+    /// Code hashes of contracts, not counted in the raw format.
+    pub codes: u64,
+    /// Size of the file once compressed, if it was.
+    pub compressed_bytes: Option<u64>,
+}
+
+impl Summary {
+    /// Returns the size of the same preimages in the raw format.
+    pub fn raw_bytes(&self) -> u64 {
+        20 * self.accounts + 32 * self.storage_slots
+    }
+
+    /// Prints the summary to the standard error, since the file may be written to the standard output.
+    pub fn print(&self) {
+        let raw_bytes = self.raw_bytes();
+        eprintln!(
+            "Wrote {} accounts and {} storage slots in {} bytes, {:.2}% of the {} bytes of the raw format ({}, {:.2}x compression ratio)",
+            self.accounts,
+            self.storage_slots,
+            self.bytes,
+            self.bytes as f64 / raw_bytes.max(1) as f64 * 100.0,
+            raw_bytes,
+            match raw_bytes.checked_sub(self.bytes) {
+                Some(saved) => format!("{} bytes saved", saved),
+                None => format!("{} bytes more", self.bytes - raw_bytes),
+            },
+            raw_bytes as f64 / self.bytes.max(1) as f64
+        );
+        if self.dictionary_storage_slots > 0 {
+            eprintln!(
+                "{} storage slots ({:.2}%) were encoded with the slot dictionary",
+                self.dictionary_storage_slots,
+                self.dictionary_storage_slots as f64 / self.storage_slots.max(1) as f64 * 100.0
+            );
+        }
+        if self.codes > 0 {
+            eprintln!("Wrote the code hashes of {} contracts", self.codes);
+        }
+        if let Some(compressed_bytes) = self.compressed_bytes {
+            eprintln!(
+                "Compressed to {} bytes, {:.2}% of the raw format ({:.2}x compression ratio)",
+                compressed_bytes,
+                compressed_bytes as f64 / raw_bytes.max(1) as f64 * 100.0,
+                raw_bytes as f64 / compressed_bytes.max(1) as f64
+            );
+        }
+    }
+}
+
+pub struct PreimageWriter<W: Write> {
+    inner: Hashing<W>,
+    format: FileFormat,
+    encoding: Encoding,
+
+    /// Last account written, whether its storage slots can still be written and whether it has a code
+    /// hash.
+    account: Option<Address>,
+    open_account: bool,
+    code: bool,
+    /// Storage slots of the current run, up to [`STORAGE_SLOTS_RUN`].
+    buf_storage_slots: Vec<B256>,
+    /// Previous address and storage slot written, which the next ones are front-coded against.
+    previous_address: Address,
+    previous_slot: B256,
+
+    accounts: u64,
+    storage_slots: u64,
+    dictionary_storage_slots: u64,
+    codes: u64,
+}
+
+impl<W: Write> PreimageWriter<W> {
+    pub fn new(w: W, format: FileFormat, header: &Header) -> Result<Self> {
+        Self::with_encoding(w, format, header, Encoding::None)
+    }
+
+    /// Creates a writer encoding the storage slots with `encoding`, which requires the delimited format.
+    pub fn with_encoding(
+        w: W,
+        format: FileFormat,
+        header: &Header,
+        encoding: Encoding,
+    ) -> Result<Self> {
+        if encoding != Encoding::None && format != FileFormat::Delimited {
+            bail!("The {} format doesn't support encodings", format);
+        }
+        if encoding == Encoding::FrontCoding && header.order != Order::Plain {
+            bail!(
+                "Front coding requires the plain ordering, not {}",
+                header.order
+            );
+        }
+        let mut inner = Hashing::new(w);
+        if format == FileFormat::Delimited {
+            inner.write_all(&MAGIC)?;
+            inner.write_all(&[VERSION, header.order.to_byte(), encoding.to_byte()])?;
+            inner.write_all(&header.chain_id.to_be_bytes())?;
+            inner.write_all(&header.block_number.to_be_bytes())?;
+            inner.write_all(header.state_root.as_slice())?;
+            if let Encoding::SlotDictionary(dictionary) = &encoding {
+                inner.write_all(&[dictionary.len() as u8])?;
+                for prefix in &dictionary.prefixes {
+                    inner.write_all(prefix)?;
+                }
+            }
+        }
+        Ok(Self {
+            inner,
+            format,
+            encoding,
+            account: None,
+            open_account: false,
+            code: false,
+            buf_storage_slots: Vec::new(),
+            previous_address: Address::ZERO,
+            previous_slot: B256::ZERO,
+            accounts: 0,
+            storage_slots: 0,
+            dictionary_storage_slots: 0,
+            codes: 0,
+        })
+    }
+
+    /// Returns the underlying writer, e.g: to sync it once flushed.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner.inner
+    }
+
+    /// Returns the number of bytes written so far, not counting the buffered storage slots.
+    pub fn offset(&self) -> u64 {
+        self.inner.offset
+    }
+
+    pub fn write_account(&mut self, address: Address) -> Result<()> {
+        self.accounts += 1;
+        match self.format {
+            FileFormat::Raw => self
+                .inner
+                .write_all(address.as_slice())
+                .context("writing address preimage"),
+            FileFormat::GethRlp => {
+                self.inner.write_all(&[RLP_ADDRESS_PREFIX])?;
+                self.inner
+                    .write_all(address.as_slice())
+                    .context("writing address preimage")
+            }
+            FileFormat::Delimited => {
+                self.close_account()?;
+                self.inner.write_all(&[TAG_ACCOUNT])?;
+                match self.encoding {
+                    Encoding::FrontCoding => {
+                        write_front_coded(&mut self.inner, &self.previous_address[..], &address[..])
+                    }
+                    _ => self.inner.write_all(address.as_slice()),
+                }
+                .context("writing address preimage")?;
+                self.previous_address = address;
+                self.previous_slot = B256::ZERO;
+                self.account = Some(address);
+                self.open_account = true;
+                self.code = false;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_storage_slot(&mut self, slot: B256) -> Result<()> {
+        self.storage_slots += 1;
+        match self.format {
+            FileFormat::Raw => self
+                .inner
+                .write_all(slot.as_slice())
+                .context("writing storage slot preimage"),
+            FileFormat::GethRlp => {
+                self.inner.write_all(&[RLP_STORAGE_SLOT_PREFIX])?;
+                self.inner
+                    .write_all(slot.as_slice())
+                    .context("writing storage slot preimage")
+            }
+            FileFormat::Delimited => {
+                let Some(address) = self.account else {
+                    bail!("Storage slot {} written before any account", slot);
+                };
+                if !self.open_account {
+                    bail!(
+                        "Storage slot {} (address: {}) written after its code hash",
+                        slot,
+                        address
+                    );
+                }
+                self.buf_storage_slots.push(slot);
+                if self.buf_storage_slots.len() == STORAGE_SLOTS_RUN as usize {
+                    self.write_run()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the code hash of the current account, after its storage slots. Only the delimited format
+    /// has code hashes.
+    pub fn write_code(&mut self, code_hash: B256) -> Result<()> {
+        if self.format != FileFormat::Delimited {
+            bail!("The {} format doesn't support code hashes", self.format);
+        }
+        let Some(address) = self.account else {
+            bail!("Code hash {} written before any account", code_hash);
+        };
+        if std::mem::replace(&mut self.code, true) {
+            bail!("Account {} has more than one code hash", address);
+        }
+        self.close_account()?;
+        self.inner.write_all(&[TAG_CODE])?;
+        self.inner
+            .write_all(code_hash.as_slice())
+            .context("writing code hash")?;
+        self.codes += 1;
+        Ok(())
+    }
+
+    /// Writes the storage slots of the current account and flushes the underlying writer, returning the
+    /// position after it. The next call must be [`PreimageWriter::write_account`] or
+    /// [`PreimageWriter::finish`].
+    pub fn flush(&mut self) -> Result<Position> {
+        self.close_account()?;
+        self.inner.flush()?;
+        Ok(Position {
+            offset: self.inner.offset,
+            accounts: self.accounts,
+            storage_slots: self.storage_slots,
+        })
+    }
+
+    /// Writes the storage slots of the current account and the footer, returning the underlying writer
+    /// and the summary of the file.
+    pub fn finish(mut self) -> Result<(W, Summary)> {
+        if self.format == FileFormat::Delimited {
+            self.close_account()?;
+            self.inner.write_all(&[TAG_FOOTER])?;
+            self.inner.write_all(&self.accounts.to_be_bytes())?;
+            self.inner.write_all(&self.storage_slots.to_be_bytes())?;
+            let checksum = self.inner.checksum();
+            self.inner.write_all(checksum.as_slice())?;
+        }
+        self.inner.flush()?;
+        let summary = Summary {
+            bytes: self.inner.offset,
+            accounts: self.accounts,
+            storage_slots: self.storage_slots,
+            dictionary_storage_slots: self.dictionary_storage_slots,
+            codes: self.codes,
+            compressed_bytes: None,
+        };
+        Ok((self.inner.inner, summary))
+    }
+
+    /// Writes the last run of the storage slots of the current account, if they can still be written.
+    fn close_account(&mut self) -> Result<()> {
+        if std::mem::take(&mut self.open_account) {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered storage slots as a run of the current account.
+    fn write_run(&mut self) -> Result<()> {
+        let count = self.buf_storage_slots.len() as u32;
+        self.inner.write_all(&count.to_be_bytes())?;
+        for slot in std::mem::take(&mut self.buf_storage_slots) {
+            let previous_slot = std::mem::replace(&mut self.previous_slot, slot);
+            self.write_encoded_storage_slot(slot, &previous_slot)
+                .context("writing storage slot preimage")?;
+        }
+        Ok(())
+    }
+
+    fn write_encoded_storage_slot(&mut self, slot: B256, previous_slot: &B256) -> io::Result<()> {
+        match &self.encoding {
+            Encoding::None => self.inner.write_all(slot.as_slice()),
+            Encoding::FrontCoding => {
+                write_front_coded(&mut self.inner, &previous_slot[..], &slot[..])
+            }
+            Encoding::SlotDictionary(dictionary) => match dictionary.index(&slot) {
+                Some(index) => {
+                    self.dictionary_storage_slots += 1;
+                    self.inner.write_all(&[index])?;
+                    self.inner.write_all(&slot[SLOT_PREFIX_LEN..])
+                }
+                None => {
+                    self.inner.write_all(&[TAG_LITERAL_STORAGE_SLOT])?;
+                    self.inner.write_all(slot.as_slice())
+                }
+            },
+        }
+    }
+}
+
+impl PreimageWriter<BufWriter<File>> {
+    /// Resumes writing `file` at `position`, returned by [`PreimageWriter::flush`] in a previous run,
+    /// truncating anything after it. The bytes before it are read again to restore the checksum, and the
+    /// encoding is the one of the file. `last_account` is the last account written before `position`.
+    pub fn resume(
+        mut file: File,
+        format: FileFormat,
+        position: &Position,
+        last_account: Address,
+    ) -> Result<Self> {
+        let len = file.metadata()?.len();
+        if len < position.offset {
+            bail!(
+                "File has {} bytes, less than the resumed offset {}",
+                len,
+                position.offset
+            );
+        }
+        let encoding = match format {
+            FileFormat::Delimited => PreimageReader::new(io::BufReader::new(&file))?
+                .encoding()
+                .clone(),
+            _ => Encoding::None,
+        };
+        file.set_len(position.offset)?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut hashing = Hashing::new(io::sink());
+        io::copy(&mut (&file).take(position.offset), &mut hashing)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            inner: Hashing {
+                inner: BufWriter::new(file),
+                hasher: hashing.hasher,
+                offset: hashing.offset,
+            },
+            format,
+            encoding,
+            account: None,
+            open_account: false,
+            code: false,
+            buf_storage_slots: Vec::new(),
+            previous_address: last_account,
+            previous_slot: B256::ZERO,
+            accounts: position.accounts,
+            storage_slots: position.storage_slots,
+            // Only counted for the resumed part of the file.
+            dictionary_storage_slots: 0,
+            codes: 0,
+        })
+    }
+}
+
+pub struct PreimageReader<R: BufRead> {
+    inner: Hashing<R>,
+    header: Option<Header>,
+    encoding: Encoding,
+    footer: Option<Footer>,
+
+    /// Whether the storage slots of the last read account weren't all read, how many are left in the
+    /// current run and whether it's the last one.
+    open_account: bool,
+    remaining_storage_slots: u32,
+    last_run: bool,
+    /// Offset of the last read storage slot.
+    storage_slot_offset: u64,
+    /// Code hash of the last read account, once all of its storage slots were read.
+    code_hash: Option<B256>,
+    /// Previous address and storage slot read, which the next ones are front-coded against.
+    previous_address: Address,
+    previous_slot: B256,
+    accounts: u64,
+    storage_slots: u64,
+}
+
+impl<R: BufRead> PreimageReader<R> {
+    /// Creates a reader detecting the file format from its first bytes. Geth RLP files are rejected,
+    /// since their preimages would be read as raw ones.
+    pub fn new(mut r: R) -> Result<Self> {
+        let delimited = r.fill_buf()?.starts_with(&MAGIC);
+        if !delimited && is_geth_rlp(r.fill_buf()?) {
+            bail!(
+                "The file looks like a geth RLP preimage file, verify it with verify-geth instead"
+            );
+        }
+        let mut reader = Self {
+            inner: Hashing::new(r),
+            header: None,
+            encoding: Encoding::None,
+            footer: None,
+            open_account: false,
+            remaining_storage_slots: 0,
+            last_run: true,
+            storage_slot_offset: 0,
+            code_hash: None,
+            previous_address: Address::ZERO,
+            previous_slot: B256::ZERO,
+            accounts: 0,
+            storage_slots: 0,
+        };
+        if delimited {
+            reader.header = Some(reader.read_header()?);
+        }
+        Ok(reader)
+    }
+
+    pub fn format(&self) -> FileFormat {
+        match self.header {
+            Some(_) => FileFormat::Delimited,
+            None => FileFormat::Raw,
+        }
+    }
+
+    /// Returns the header of a delimited file, or `None` for a raw file.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// Returns the encoding of the storage slots of a delimited file.
+    pub fn encoding(&self) -> &Encoding {
+        &self.encoding
+    }
+
+    /// Returns the footer of a delimited file once it was read and validated.
+    pub fn footer(&self) -> Option<&Footer> {
+        self.footer.as_ref()
+    }
+
+    /// Returns the number of bytes read so far.
+    pub fn offset(&self) -> u64 {
+        self.inner.offset
+    }
+
+    /// Returns the position after the last read account, once all of its storage slots were read.
+    pub fn position(&self) -> Position {
+        Position {
+            offset: self.offset(),
+            accounts: self.accounts,
+            storage_slots: self.storage_slots,
+        }
+    }
+
+    /// Skips to `position`, returned by [`PreimageReader::position`] in a previous run, after
+    /// `last_account`. The skipped bytes are still read to verify the checksum.
+    pub fn resume(&mut self, position: &Position, last_account: Address) -> Result<()> {
+        let Some(len) = position.offset.checked_sub(self.offset()) else {
+            bail!(
+                "Can't resume at offset {}, before the header",
+                position.offset
+            );
+        };
+        let skipped = io::copy(&mut (&mut self.inner).take(len), &mut io::sink())?;
+        if skipped != len {
+            bail!(
+                "File has {} bytes, less than the resumed offset {}",
+                self.offset(),
+                position.offset
+            );
+        }
+        self.previous_address = last_account;
+        self.accounts = position.accounts;
+        self.storage_slots = position.storage_slots;
+        Ok(())
+    }
+
+    /// Reads the next account address. Returns `None` when there are no more accounts, which in a
+    /// delimited file means the footer was read and validated.
+    pub fn read_account(&mut self) -> Result<Option<Address>> {
+        if self.header.is_none() {
+            let mut address = Address::default();
+            return Ok(self
+                .read_record(address.as_mut_slice())
+                .context("reading address preimage")?
+                .then_some(address));
+        }
+        if self.footer.is_some() {
+            return Ok(None);
+        }
+        if self.open_account {
+            if self.remaining_storage_slots != 0 || !self.last_run {
+                bail!(
+                    "Account {} has unread storage slots at offset {}",
+                    self.previous_address,
+                    self.offset()
+                );
+            }
+            self.close_account()?;
+        }
+
+        let offset = self.offset();
+        let mut tag = [0u8; 1];
+        if !self.read_record(&mut tag)? {
+            bail!("Missing footer at offset {}", offset);
+        }
+        match tag[0] {
+            TAG_ACCOUNT => {
+                let mut address = self.previous_address;
+                match self.encoding {
+                    Encoding::FrontCoding => self.read_front_coded(address.as_mut_slice()),
+                    _ => self.read_exact(address.as_mut_slice()),
+                }
+                .context("reading address preimage")?;
+                self.previous_address = address;
+                self.previous_slot = B256::ZERO;
+                self.code_hash = None;
+                self.open_account = true;
+                self.read_run()?;
+                self.accounts += 1;
+                Ok(Some(address))
+            }
+            TAG_CODE => Err(anyhow!("Code hash without account at offset {}", offset)),
+            TAG_FOOTER => {
+                self.footer = Some(self.read_footer()?);
+                Ok(None)
+            }
+            tag => Err(anyhow!(
+                "Unknown record tag {:#04x} at offset {}",
+                tag,
+                offset
+            )),
+        }
+    }
+
+    /// Returns the code hash of the last read account, if it's a contract written with its code hash.
+    /// Only known once all the storage slots of the account were read.
+    pub fn code_hash(&self) -> Option<B256> {
+        self.code_hash
+    }
+
+    /// Returns the offset of the last read storage slot.
+    pub fn storage_slot_offset(&self) -> u64 {
+        self.storage_slot_offset
+    }
+
+    /// Reads the next storage slot of the current account. In a delimited file, returns `None` once
+    /// all the storage slots of the current account were read, along with its code hash.
+    pub fn read_storage_slot(&mut self) -> Result<Option<B256>> {
+        let mut slot = B256::default();
+        if self.header.is_some() {
+            while self.remaining_storage_slots == 0 {
+                if !self.open_account {
+                    return Ok(None);
+                }
+                if self.last_run {
+                    self.close_account()?;
+                    return Ok(None);
+                }
+                self.read_run()?;
+            }
+            self.storage_slot_offset = self.offset();
+            self.read_encoded_storage_slot(&mut slot)
+                .context("reading storage slot preimage")?;
+            self.remaining_storage_slots -= 1;
+            self.storage_slots += 1;
+            return Ok(Some(slot));
+        }
+        self.storage_slot_offset = self.offset();
+        Ok(self
+            .read_record(slot.as_mut_slice())
+            .context("reading storage slot preimage")?
+            .then_some(slot))
+    }
+
+    /// Reads the storage slots count of the next run of the current account.
+    fn read_run(&mut self) -> Result<()> {
+        let mut count = [0u8; 4];
+        self.read_exact(&mut count)
+            .context("reading storage slots count")?;
+        self.remaining_storage_slots = u32::from_be_bytes(count);
+        if self.remaining_storage_slots > STORAGE_SLOTS_RUN {
+            bail!(
+                "Run of {} storage slots, more than {} at offset {}",
+                self.remaining_storage_slots,
+                STORAGE_SLOTS_RUN,
+                self.offset() - 4
+            );
+        }
+        self.last_run = self.remaining_storage_slots < STORAGE_SLOTS_RUN;
+        Ok(())
+    }
+
+    /// Reads the code hash following the storage slots of the current account, if any.
+    fn close_account(&mut self) -> Result<()> {
+        self.open_account = false;
+        if self.inner.inner.fill_buf()?.first() == Some(&TAG_CODE) {
+            let mut tag = [0u8; 1];
+            self.read_exact(&mut tag)?;
+            let mut code_hash = B256::default();
+            self.read_exact(code_hash.as_mut_slice())
+                .context("reading code hash")?;
+            self.code_hash = Some(code_hash);
+        }
+        Ok(())
+    }
+
+    fn read_encoded_storage_slot(&mut self, slot: &mut B256) -> Result<()> {
+        match self.encoding {
+            Encoding::None => return self.read_exact(slot.as_mut_slice()),
+            Encoding::FrontCoding => {
+                *slot = self.previous_slot;
+                self.read_front_coded(slot.as_mut_slice())?;
+                self.previous_slot = *slot;
+                return Ok(());
+            }
+            Encoding::SlotDictionary(_) => {}
+        }
+        let offset = self.offset();
+        let mut tag = [0u8; 1];
+        self.read_exact(&mut tag)?;
+        if tag[0] == TAG_LITERAL_STORAGE_SLOT {
+            return self.read_exact(slot.as_mut_slice());
+        }
+        let Encoding::SlotDictionary(dictionary) = &self.encoding else {
+            unreachable!("encoded storage slot without dictionary");
+        };
+        let prefix = *dictionary.prefixes.get(tag[0] as usize).ok_or(anyhow!(
+            "Unknown slot dictionary index {} at offset {}",
+            tag[0],
+            offset
+        ))?;
+        slot[..SLOT_PREFIX_LEN].copy_from_slice(&prefix);
+        self.read_exact(&mut slot[SLOT_PREFIX_LEN..])
+    }
+
+    /// Reads a front-coded key into `buf`, which holds the previous key.
+    fn read_front_coded(&mut self, buf: &mut [u8]) -> Result<()> {
+        let offset = self.offset();
+        let mut shared = [0u8; 1];
+        self.read_exact(&mut shared)?;
+        let shared = shared[0] as usize;
+        if shared > buf.len() {
+            bail!(
+                "Front-coded key shares {} bytes with the previous one, more than its {} bytes at offset {}",
+                shared,
+                buf.len(),
+                offset
+            );
+        }
+        self.read_exact(&mut buf[shared..])
+    }
+
+    /// Returns `true` if there are no bytes left to read.
+    pub fn is_eof(&mut self) -> Result<bool> {
+        Ok(self.inner.inner.fill_buf()?.is_empty())
+    }
+
+    fn read_header(&mut self) -> Result<Header> {
+        let mut buf = [0u8; MAGIC.len() + 2];
+        self.read_exact(&mut buf).context("reading header")?;
+        let version = buf[MAGIC.len()];
+        if version != VERSION {
+            bail!("Unsupported preimage file version {}", version);
+        }
+        let order = Order::from_byte(buf[MAGIC.len() + 1])?;
+        let mut encoding = [0u8; 1];
+        self.read_exact(&mut encoding).context("reading header")?;
+        let chain_id = self.read_u64().context("reading header")?;
+        let block_number = self.read_u64().context("reading header")?;
+        let mut state_root = B256::default();
+        self.read_exact(state_root.as_mut_slice())
+            .context("reading header")?;
+        self.encoding = self.read_encoding(encoding[0])?;
+        if self.encoding == Encoding::FrontCoding && order != Order::Plain {
+            bail!("Front coding requires the plain ordering, not {}", order);
+        }
+        Ok(Header {
+            order,
+            chain_id,
+            block_number,
+            state_root,
+        })
+    }
+
+    fn read_encoding(&mut self, encoding: u8) -> Result<Encoding> {
+        match encoding {
+            0 => Ok(Encoding::None),
+            1 => {
+                let mut count = [0u8; 1];
+                self.read_exact(&mut count)
+                    .context("reading slot dictionary")?;
+                let mut prefixes = vec![[0u8; SLOT_PREFIX_LEN]; count[0] as usize];
+                for prefix in &mut prefixes {
+                    self.read_exact(prefix).context("reading slot dictionary")?;
+                }
+                Ok(Encoding::SlotDictionary(SlotDictionary::new(prefixes)?))
+            }
+            2 => Ok(Encoding::FrontCoding),
+            encoding => Err(anyhow!("Unknown storage slot encoding {}", encoding)),
+        }
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn read_footer(&mut self) -> Result<Footer> {
+        let mut counts = [0u8; 16];
+        self.read_exact(&mut counts).context("reading footer")?;
+        let expected_checksum = self.inner.checksum();
+        let mut checksum = B256::default();
+        self.read_exact(checksum.as_mut_slice())
+            .context("reading footer")?;
+
+        let footer = Footer {
+            accounts: u64::from_be_bytes(counts[..8].try_into()?),
+            storage_slots: u64::from_be_bytes(counts[8..].try_into()?),
+            checksum,
+        };
+        if footer.accounts != self.accounts || footer.storage_slots != self.storage_slots {
+            bail!(
+                "Footer totals mismatch: {} accounts and {} storage slots (read {} and {})",
+                footer.accounts,
+                footer.storage_slots,
+                self.accounts,
+                self.storage_slots
+            );
+        }
+        if footer.checksum != expected_checksum {
+            bail!(
+                "Checksum mismatch: footer has {} but content hashes to {}",
+                footer.checksum,
+                expected_checksum
+            );
+        }
+        Ok(footer)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let offset = self.offset();
+        self.inner
+            .read_exact(buf)
+            .map_err(|e| anyhow!("Truncated record at offset {}: {}", offset, e))
+    }
+
+    /// Reads a full record, returning `false` if the reader was already at the end.
+    fn read_record(&mut self, buf: &mut [u8]) -> Result<bool> {
+        if self.is_eof()? {
+            return Ok(false);
+        }
+        self.read_exact(buf)?;
+        Ok(true)
+    }
+}
+
+/// Returns whether `buf`, the first bytes of a file, are the RLP strings of addresses and storage slots:
+/// either at least two complete ones, or exactly one for a file of a single preimage. Raw files starting
+/// the same way are unlikely, since their records would have to start with the RLP prefixes.
+fn is_geth_rlp(buf: &[u8]) -> bool {
+    let (mut offset, mut records) = (0, 0);
+    while let Some(prefix) = buf.get(offset) {
+        offset += match *prefix {
+            RLP_ADDRESS_PREFIX => 1 + 20,
+            RLP_STORAGE_SLOT_PREFIX => 1 + 32,
+            _ => return false,
+        };
+        if offset <= buf.len() {
+            records += 1;
+        }
+    }
+    records >= 2 || (records == 1 && offset == buf.len())
+}
+
+/// Writes `key` as the length of the prefix it shares with `previous` and the rest of its bytes.
+fn write_front_coded<W: Write>(w: &mut W, previous: &[u8], key: &[u8]) -> io::Result<()> {
+    let shared = previous.iter().zip(key).take_while(|(a, b)| a == b).count();
+    w.write_all(&[shared as u8])?;
+    w.write_all(&key[shared..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Account, its storage slots and code hash.
+    type Record = (Address, Vec<B256>, Option<B256>);
+
+    /// Length of the header of the files without encoding.
+    const HEADER_LEN: usize = MAGIC.len() + 3 + 8 + 8 + 32;
+
+    fn header(order: Order) -> Header {
+        Header {
+            order,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::repeat_byte(0xaa),
+        }
+    }
+
+    fn records() -> Vec<Record> {
+        vec![
+            (Address::with_last_byte(1), vec![], None),
+            (
+                Address::with_last_byte(2),
+                vec![B256::with_last_byte(1), B256::with_last_byte(2)],
+                Some(B256::repeat_byte(0xcc)),
+            ),
+            (
+                Address::with_last_byte(3),
+                vec![B256::repeat_byte(0x11)],
+                None,
+            ),
+        ]
+    }
+
+    fn write(
+        format: FileFormat,
+        header: &Header,
+        encoding: Encoding,
+        records: &[Record],
+    ) -> (Vec<u8>, Summary) {
+        let mut w = PreimageWriter::with_encoding(Vec::new(), format, header, encoding).unwrap();
+        for (address, slots, code_hash) in records {
+            w.write_account(*address).unwrap();
+            for slot in slots {
+                w.write_storage_slot(*slot).unwrap();
+            }
+            if let Some(code_hash) = code_hash {
+                w.write_code(*code_hash).unwrap();
+            }
+        }
+        w.finish().unwrap()
+    }
+
+    fn read(data: &[u8]) -> Result<(Vec<Record>, PreimageReader<&[u8]>)> {
+        let mut r = PreimageReader::new(data)?;
+        let mut records = Vec::new();
+        while let Some(address) = r.read_account()? {
+            let mut slots = Vec::new();
+            while let Some(slot) = r.read_storage_slot()? {
+                slots.push(slot);
+            }
+            records.push((address, slots, r.code_hash()));
+        }
+        Ok((records, r))
+    }
+
+    fn read_err(data: &[u8]) -> String {
+        format!("{:#}", read(data).err().expect("read should fail"))
+    }
+
+    /// Returns `data` with the byte at `offset` flipped.
+    fn flipped(data: &[u8], offset: usize) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data[offset] ^= 0xff;
+        data
+    }
+
+    #[test]
+    fn round_trips_delimited_files() {
+        for order in [Order::Plain, Order::Eip7748] {
+            let (data, summary) = write(
+                FileFormat::Delimited,
+                &header(order),
+                Encoding::None,
+                &records(),
+            );
+            let (read_records, mut r) = read(&data).unwrap();
+            assert_eq!(read_records, records());
+            assert_eq!(r.format(), FileFormat::Delimited);
+            assert_eq!(r.header(), Some(&header(order)));
+            assert_eq!(r.encoding(), &Encoding::None);
+            assert!(r.is_eof().unwrap());
+
+            let footer = r.footer().unwrap();
+            assert_eq!((footer.accounts, footer.storage_slots), (3, 3));
+            assert_eq!(footer.checksum, keccak256(&data[..data.len() - 32]));
+            assert_eq!(
+                summary,
+                Summary {
+                    bytes: data.len() as u64,
+                    accounts: 3,
+                    storage_slots: 3,
+                    dictionary_storage_slots: 0,
+                    codes: 1,
+                    compressed_bytes: None,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn writes_raw_files_as_concatenated_preimages() {
+        let records: Vec<_> = records()
+            .into_iter()
+            .map(|(address, slots, _)| (address, slots, None))
+            .collect();
+        let (data, summary) = write(
+            FileFormat::Raw,
+            &header(Order::Plain),
+            Encoding::None,
+            &records,
+        );
+        let mut expected = Vec::<u8>::new();
+        for (address, slots, _) in &records {
+            expected.extend(address.as_slice());
+            for slot in slots {
+                expected.extend(slot.as_slice());
+            }
+        }
+        assert_eq!(data, expected);
+        assert_eq!(summary.bytes, summary.raw_bytes());
+
+        let mut r = PreimageReader::new(&data[..]).unwrap();
+        assert_eq!(r.format(), FileFormat::Raw);
+        assert_eq!(r.header(), None);
+        assert_eq!(r.read_account().unwrap(), Some(records[0].0));
+
+        let mut w =
+            PreimageWriter::new(Vec::new(), FileFormat::Raw, &header(Order::Plain)).unwrap();
+        w.write_account(Address::ZERO).unwrap();
+        assert!(w.write_code(B256::ZERO).is_err());
+    }
+
+    #[test]
+    fn rejects_geth_rlp_files() {
+        let records: Vec<_> = records()
+            .into_iter()
+            .map(|(address, slots, _)| (address, slots, None))
+            .collect();
+        for records in [&records[..], &records[..1]] {
+            let (data, _) = write(
+                FileFormat::GethRlp,
+                &header(Order::Plain),
+                Encoding::None,
+                records,
+            );
+            let err = PreimageReader::new(&data[..]).err().unwrap();
+            assert!(err.to_string().contains("verify it with verify-geth"));
+        }
+
+        // Raw files of an address starting with the RLP prefix of an address, or of a storage slot.
+        let mut data = vec![RLP_ADDRESS_PREFIX; 20];
+        assert_eq!(
+            PreimageReader::new(&data[..]).unwrap().format(),
+            FileFormat::Raw
+        );
+        data.extend([RLP_STORAGE_SLOT_PREFIX; 32]);
+        assert_eq!(
+            PreimageReader::new(&data[..]).unwrap().format(),
+            FileFormat::Raw
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let (data, _) = write(
+            FileFormat::Delimited,
+            &header(Order::Eip7748),
+            Encoding::None,
+            &records(),
+        );
+        assert_eq!(data[MAGIC.len()], VERSION);
+        for version in [0, VERSION + 1] {
+            let mut data = data.clone();
+            data[MAGIC.len()] = version;
+            assert!(read_err(&data).contains("Unsupported preimage file version"));
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_files() {
+        let (data, _) = write(
+            FileFormat::Delimited,
+            &header(Order::Plain),
+            Encoding::None,
+            &records(),
+        );
+        let len = data.len();
+        let checksum_offset = len - 32;
+        let counts_offset = checksum_offset - 16;
+
+        // First byte of the first address and last byte of the checksum.
+        assert!(read_err(&flipped(&data, HEADER_LEN + 1)).contains("Checksum mismatch"));
+        assert!(read_err(&flipped(&data, len - 1)).contains("Checksum mismatch"));
+        assert!(read_err(&flipped(&data, counts_offset + 7)).contains("Footer totals mismatch"));
+        assert!(read_err(&flipped(&data, HEADER_LEN)).contains("Unknown record tag"));
+
+        assert!(read_err(&data[..len - 10]).contains("Truncated record"));
+        assert!(read_err(&data[..counts_offset - 1]).contains("Missing footer"));
+        assert!(read_err(&data[..HEADER_LEN - 1]).contains("reading header"));
+    }
+
+    #[test]
+    fn writes_the_storage_slots_in_runs() {
+        let run = STORAGE_SLOTS_RUN as usize;
+        let slots = |n: usize| {
+            (0..n as u64)
+                .map(|i| B256::left_padding_from(&i.to_be_bytes()))
+                .collect()
+        };
+        let records: Vec<Record> = vec![
+            (Address::with_last_byte(1), slots(run), None),
+            (
+                Address::with_last_byte(2),
+                slots(2 * run + 1),
+                Some(B256::repeat_byte(0xcc)),
+            ),
+            (Address::with_last_byte(3), slots(run - 1), None),
+        ];
+        let (data, summary) = write(
+            FileFormat::Delimited,
+            &header(Order::Plain),
+            Encoding::FrontCoding,
+            &records,
+        );
+        assert_eq!(summary.storage_slots, 4 * run as u64);
+        let (read_records, r) = read(&data).unwrap();
+        assert_eq!(read_records, records);
+        assert_eq!(r.footer().unwrap().storage_slots, 4 * run as u64);
+
+        // A full run is followed by an empty one, the first run of the next account.
+        let (data, _) = write(
+            FileFormat::Delimited,
+            &header(Order::Plain),
+            Encoding::None,
+            &records[..1],
+        );
+        let last_run = HEADER_LEN + 1 + 20 + 4 + 32 * run;
+        assert_eq!(
+            data[HEADER_LEN + 21..HEADER_LEN + 25],
+            STORAGE_SLOTS_RUN.to_be_bytes()
+        );
+        assert_eq!(data[last_run..last_run + 4], [0; 4]);
+        let mut oversized = data.clone();
+        oversized[HEADER_LEN + 24] += 1;
+        assert!(read_err(&oversized).contains("Run of 4097 storage slots"));
+    }
+
+    #[test]
+    fn reads_the_code_hash_after_the_storage_slots() {
+        let records = records();
+        let (data, _) = write(
+            FileFormat::Delimited,
+            &header(Order::Plain),
+            Encoding::None,
+            &records,
+        );
+        let mut r = PreimageReader::new(&data[..]).unwrap();
+        r.read_account().unwrap();
+        assert_eq!(r.read_account().unwrap(), Some(records[1].0));
+        assert_eq!(r.read_storage_slot().unwrap(), Some(records[1].1[0]));
+        assert_eq!(r.storage_slot_offset(), (HEADER_LEN + 25 + 25) as u64);
+        assert_eq!(r.code_hash(), None);
+        assert_eq!(r.read_storage_slot().unwrap(), Some(records[1].1[1]));
+        assert_eq!(r.read_storage_slot().unwrap(), None);
+        assert_eq!(r.code_hash(), records[1].2);
+        assert_eq!(r.read_account().unwrap(), Some(records[2].0));
+
+        // The code record of the second account, without its account record.
+        let code_offset = HEADER_LEN + 25 + 25 + 64;
+        let mut unexpected = data[..HEADER_LEN].to_vec();
+        unexpected.extend_from_slice(&data[code_offset..]);
+        assert!(read_err(&unexpected).contains("Code hash without account"));
+
+        // Code hashes can't be written before any account, twice or before storage slots.
+        let mut w =
+            PreimageWriter::new(Vec::new(), FileFormat::Delimited, &header(Order::Plain)).unwrap();
+        assert!(w.write_code(B256::ZERO).is_err());
+        w.write_account(Address::ZERO).unwrap();
+        w.write_code(B256::ZERO).unwrap();
+        assert!(w.write_code(B256::ZERO).is_err());
+        assert!(w.write_storage_slot(B256::ZERO).is_err());
+    }
+
+    #[test]
+    fn round_trips_slot_dictionary_encoding() {
+        let dictionary = SlotDictionary::new(vec![[0; SLOT_PREFIX_LEN]]).unwrap();
+        let encoding = Encoding::SlotDictionary(dictionary);
+        let (data, summary) = write(
+            FileFormat::Delimited,
+            &header(Order::Eip7748),
+            encoding.clone(),
+            &records(),
+        );
+        let (read_records, r) = read(&data).unwrap();
+        assert_eq!(read_records, records());
+        assert_eq!(r.encoding(), &encoding);
+        assert_eq!(summary.dictionary_storage_slots, 2);
+
+        // The two small slots take an index instead of their prefix, the other one a tag more.
+        let (plain, _) = write(
+            FileFormat::Delimited,
+            &header(Order::Eip7748),
+            Encoding::None,
+            &records(),
+        );
+        let dictionary_len = 1 + SLOT_PREFIX_LEN;
+        assert_eq!(
+            data.len() + 2 * (SLOT_PREFIX_LEN - 1),
+            plain.len() + dictionary_len + 1
+        );
+
+        // The index of the first dictionary slot, past the dictionary, accounts and storage slots counts.
+        let index_offset = HEADER_LEN + dictionary_len + 1 + 20 + 4 + 1 + 20 + 4;
+        assert_eq!(data[index_offset], 0);
+        let mut corrupted = data.clone();
+        corrupted[index_offset] = 1;
+        assert!(read_err(&corrupted).contains("Unknown slot dictionary index 1"));
+    }
+
+    #[test]
+    fn rejects_invalid_slot_dictionaries() {
+        assert!(SlotDictionary::new(vec![[0; SLOT_PREFIX_LEN]; 2]).is_err());
+        let prefixes = (0..=MAX_SLOT_DICTIONARY_SIZE)
+            .map(|i| {
+                let mut prefix = [0; SLOT_PREFIX_LEN];
+                prefix[..8].copy_from_slice(&(i as u64).to_be_bytes());
+                prefix
+            })
+            .collect::<Vec<_>>();
+        assert!(SlotDictionary::new(prefixes[1..].to_vec()).is_ok());
+        assert!(SlotDictionary::new(prefixes).is_err());
+        assert!(PreimageWriter::with_encoding(
+            Vec::new(),
+            FileFormat::Raw,
+            &header(Order::Plain),
+            Encoding::SlotDictionary(SlotDictionary::default()),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn round_trips_front_coding() {
+        let mut records = records();
+        // Resets the previous storage slot at each account.
+        records.push((
+            Address::with_last_byte(4),
+            vec![B256::with_last_byte(1)],
+            None,
+        ));
+        let (data, summary) = write(
+            FileFormat::Delimited,
+            &header(Order::Plain),
+            Encoding::FrontCoding,
+            &records,
+        );
+        let (read_records, r) = read(&data).unwrap();
+        assert_eq!(read_records, records);
+        assert_eq!(r.encoding(), &Encoding::FrontCoding);
+        assert_eq!(summary.bytes, data.len() as u64);
+
+        // The addresses share 19 bytes with the previous one, and the slots 31, 31, 0 and 31 bytes.
+        let (plain, _) = write(
+            FileFormat::Delimited,
+            &header(Order::Plain),
+            Encoding::None,
+            &records,
+        );
+        assert_eq!(data.len() + 4 * 18 + 3 * 30 - 1, plain.len());
+    }
+
+    #[test]
+    fn rejects_front_coding_of_the_eip7748_ordering() {
+        assert!(PreimageWriter::with_encoding(
+            Vec::new(),
+            FileFormat::Delimited,
+            &header(Order::Eip7748),
+            Encoding::FrontCoding,
+        )
+        .is_err());
+
+        let (data, _) = write(
+            FileFormat::Delimited,
+            &header(Order::Plain),
+            Encoding::FrontCoding,
+            &records(),
+        );
+        let mut data = data[..data.len() - 32].to_vec();
+        data[MAGIC.len() + 1] = Order::Eip7748.to_byte();
+        let checksum = keccak256(&data);
+        data.extend(checksum.as_slice());
+        assert!(read_err(&data).contains("Front coding requires the plain ordering"));
+    }
+}
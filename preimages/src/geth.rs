@@ -0,0 +1,199 @@
+//! Geth preimage files.
+//!
+//! Geth stores preimages as `secure-key-<hash> -> preimage` in its database. Its `export-preimages`
+//! command writes them as a stream of RLP strings, gzip-compressed if the file name ends with `.gz`,
+//! and `import-preimages` reads them back hashing each preimage to get its key. Exports of the database
+//! come in key order, while exports of the snapshot come in the EIP-7748 ordering. Files compressed
+//! with zstd or xz are read as well (see [`crate::compress`]).
+//!
+//! State preimages are 20-byte addresses and 32-byte storage slots, which is how they are told apart.
+
+use alloy_primitives::{Address, B256};
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+};
+
+use crate::{compress, format::Preimage};
+
+pub struct GethPreimageReader {
+    inner: Box<dyn BufRead>,
+    offset: u64,
+}
+
+impl GethPreimageReader {
+    pub fn open(path: &str) -> Result<Self> {
+        let inner: Box<dyn BufRead> = if path.ends_with(".gz") {
+            let file = File::open(path).with_context(|| format!("opening {}", path))?;
+            Box::new(BufReader::new(GzDecoder::new(file)))
+        } else {
+            compress::open(path)?
+        };
+        Ok(Self { inner, offset: 0 })
+    }
+
+    /// Returns the number of (uncompressed) bytes read so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn read_preimage(&mut self) -> Result<Option<Preimage>> {
+        if self.inner.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let offset = self.offset;
+        let len = self.read_string_header()?;
+        let preimage = match len {
+            20 => {
+                let mut address = Address::default();
+                self.read_exact(address.as_mut_slice())?;
+                Preimage::Address(address)
+            }
+            32 => {
+                let mut slot = B256::default();
+                self.read_exact(slot.as_mut_slice())?;
+                Preimage::StorageSlot(slot)
+            }
+            len => bail!(
+                "Preimage of {} bytes at offset {} is neither an address nor a storage slot",
+                len,
+                offset
+            ),
+        };
+        Ok(Some(preimage))
+    }
+
+    /// Reads the header of an RLP string, returning its length.
+    fn read_string_header(&mut self) -> Result<u64> {
+        let offset = self.offset;
+        let mut prefix = [0u8; 1];
+        self.read_exact(&mut prefix)?;
+        match prefix[0] {
+            // A single byte is its own encoding, which can't be a state preimage.
+            0x00..=0x7f => bail!("Preimage of 1 byte at offset {}", offset),
+            prefix @ 0x80..=0xb7 => Ok((prefix - 0x80) as u64),
+            prefix @ 0xb8..=0xbf => {
+                let mut len = [0u8; 8];
+                let size = (prefix - 0xb7) as usize;
+                self.read_exact(&mut len[8 - size..])?;
+                Ok(u64::from_be_bytes(len))
+            }
+            _ => Err(anyhow!("Unexpected RLP list at offset {}", offset)),
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                anyhow!("Truncated preimage at offset {}", self.offset)
+            }
+            _ => e.into(),
+        })?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl Iterator for GethPreimageReader {
+    type Item = Result<Preimage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_preimage().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    use super::*;
+    use crate::format::{FileFormat, Header, Order, PreimageWriter};
+
+    fn preimages() -> Vec<Preimage> {
+        vec![
+            Preimage::Address(Address::with_last_byte(1)),
+            Preimage::StorageSlot(B256::with_last_byte(1)),
+            Preimage::StorageSlot(B256::repeat_byte(0xff)),
+            Preimage::Address(Address::with_last_byte(2)),
+        ]
+    }
+
+    /// Returns the geth RLP stream of `preimages`, as written by `generate --format geth-rlp`.
+    fn encode(preimages: &[Preimage]) -> Vec<u8> {
+        let header = Header {
+            order: Order::Plain,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::ZERO,
+        };
+        let mut w = PreimageWriter::new(Vec::new(), FileFormat::GethRlp, &header).unwrap();
+        for preimage in preimages {
+            match preimage {
+                Preimage::Address(address) => w.write_account(*address).unwrap(),
+                Preimage::StorageSlot(slot) => w.write_storage_slot(*slot).unwrap(),
+            }
+        }
+        w.finish().unwrap().0
+    }
+
+    fn read(path: &str) -> Result<Vec<Preimage>> {
+        GethPreimageReader::open(path)?.collect()
+    }
+
+    #[test]
+    fn reads_plain_and_gzip_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = encode(&preimages());
+        assert_eq!(data.len(), 2 * 21 + 2 * 33);
+
+        let path = dir.path().join("preimages.rlp");
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(read(path.to_str().unwrap()).unwrap(), preimages());
+
+        let path = dir.path().join("preimages.rlp.gz");
+        let mut w = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        w.write_all(&data).unwrap();
+        w.finish().unwrap();
+        let mut r = GethPreimageReader::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(r.by_ref().collect::<Result<Vec<_>>>().unwrap(), preimages());
+        assert_eq!(r.offset(), data.len() as u64);
+    }
+
+    #[test]
+    fn writes_shared_storage_slots_for_each_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.rlp");
+        let shared = Preimage::StorageSlot(B256::ZERO);
+        let preimages = [
+            Preimage::Address(Address::with_last_byte(1)),
+            shared,
+            Preimage::Address(Address::with_last_byte(2)),
+            shared,
+        ];
+        // Not deduplicated, geth imports the preimage of the same hash once.
+        std::fs::write(&path, encode(&preimages)).unwrap();
+        assert_eq!(read(path.to_str().unwrap()).unwrap(), preimages);
+    }
+
+    #[test]
+    fn rejects_non_state_preimages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.rlp");
+        let path = path.to_str().unwrap();
+        let mut data = encode(&preimages()[..1]);
+        for (bytes, err) in [
+            (&[0x01][..], "Preimage of 1 byte at offset 21"),
+            (&[0x82, 0x01, 0x02], "Preimage of 2 bytes at offset 21"),
+            (&[0xc0], "Unexpected RLP list at offset 21"),
+            (&[0x94, 0x01], "Truncated preimage at offset 22"),
+        ] {
+            data.truncate(21);
+            data.extend(bytes);
+            std::fs::write(path, &data).unwrap();
+            assert!(read(path).unwrap_err().to_string().starts_with(err));
+        }
+    }
+}
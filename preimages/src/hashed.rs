@@ -0,0 +1,43 @@
+//! Hashed state of a Reth database.
+//!
+//! Reth keeps the state keyed by `keccak256(address)` and `keccak256(slot)` in the `HashedAccounts` and
+//! `HashedStorages` tables for the state root computation. They are filled by reth's hashing stages, so
+//! they are an independent source of the keys of the EIP-7748 ordering, already sorted.
+
+use alloy_primitives::B256;
+use anyhow::Result;
+use reth_db::mdbx::tx::Tx;
+use reth_db::mdbx::RO;
+use reth_db::{HashedAccounts, HashedStorages};
+use reth_db_api::cursor::DbCursorRO;
+use reth_db_api::transaction::DbTx;
+
+/// Hashed address of an account and the hashed keys of its storage slots, sorted.
+pub type HashedAccount = (B256, Vec<B256>);
+
+/// Returns the hashed accounts of the database with their storage slots, sorted by hash. Each table is
+/// read with a single sequential scan.
+pub fn hashed_state(tx: &Tx<RO>) -> Result<impl Iterator<Item = Result<HashedAccount>> + '_> {
+    let mut accounts = tx.cursor_read::<HashedAccounts>()?;
+    let mut storage = tx.cursor_read::<HashedStorages>()?;
+    let mut next_slot = storage.first()?;
+    let mut next_account = move || -> Result<Option<HashedAccount>> {
+        let Some((hashed_address, _)) = accounts.next()? else {
+            return Ok(None);
+        };
+        // Storage without a hashed account isn't part of the state.
+        while next_slot
+            .as_ref()
+            .is_some_and(|(key, _)| *key < hashed_address)
+        {
+            next_slot = storage.next()?;
+        }
+        let mut hashed_slots = Vec::new();
+        while let Some((_, entry)) = next_slot.as_ref().filter(|(key, _)| *key == hashed_address) {
+            hashed_slots.push(entry.key);
+            next_slot = storage.next()?;
+        }
+        Ok(Some((hashed_address, hashed_slots)))
+    };
+    Ok(std::iter::from_fn(move || next_account().transpose()))
+}
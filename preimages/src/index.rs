@@ -0,0 +1,372 @@
+//! Lookup index of a preimage file by hashed key.
+//!
+//! Stateless and converting clients have `keccak256(address)` or `keccak256(slot)` and need the
+//! preimage. The index is a sidecar file mapping the hashed keys to the offset of their preimage in a
+//! delimited preimage file, as a table sorted by hash with a fanout on the first two bytes of the hash.
+//! The layout is (all integers are big-endian):
+//!
+//! ```text
+//! header:  magic "ESPI" (4) | version (1) | preimage file length (8) | preimage file checksum (32)
+//! fanout:  entries up to and including each 2-byte hash prefix (8 * 65536)
+//! entries: hash bytes 2..10 (8) | preimage offset (8), sorted by hash
+//! ```
+//!
+//! The top bit of the preimage offset is set for storage slots (32 bytes) and clear for addresses (20
+//! bytes). Only 10 bytes of each hash are stored, so a lookup hashes the candidate preimages to confirm
+//! the match. Storage slots that are repeated across accounts are indexed once.
+
+use alloy_primitives::{keccak256, Address, B256};
+use anyhow::{anyhow, bail, Context, Result};
+use eth_stateless::{extsort::ExternalSorter, progress::AddressProgressBar, Preimages};
+use memmap2::Mmap;
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+};
+
+use crate::compress::Compression;
+use crate::format::{Encoding, Order, Preimage, PreimageReader};
+
+pub const MAGIC: [u8; 4] = *b"ESPI";
+pub const VERSION: u8 = 1;
+
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 8 + 32;
+const FANOUT_BUCKETS: usize = 1 << 16;
+const FANOUT_SIZE: usize = 8 * FANOUT_BUCKETS;
+const ENTRY_SIZE: usize = 16;
+const STORAGE_SLOT_FLAG: u64 = 1 << 63;
+
+/// Sorted record: hash (32) | tagged preimage offset (8).
+const RECORD_SIZE: usize = 32 + 8;
+
+/// Returns the default index path of the preimage file at `path`.
+pub fn default_path(path: &str) -> String {
+    format!("{}.idx", path)
+}
+
+pub struct IndexStats {
+    pub entries: u64,
+    /// Storage slots already indexed for a previous account.
+    pub duplicates: u64,
+}
+
+/// Builds the index of the delimited preimage file at `path` to `index_path`, sorting the hashed keys
+/// with at most `max_memory` bytes of memory.
+pub fn build(path: &str, index_path: &str, max_memory: usize) -> Result<IndexStats> {
+    let mut r = BufReader::new(File::open(path)?);
+    if let Some(compression) = Compression::detect(r.fill_buf()?) {
+        bail!("{} compressed preimage files can't be indexed", compression);
+    }
+    let mut reader = PreimageReader::new(r)?;
+    let order = reader
+        .header()
+        .map(|header| header.order)
+        .ok_or(anyhow!("Raw preimage files can't be indexed"))?;
+    if *reader.encoding() != Encoding::None {
+        bail!("Encoded preimage files can't be indexed");
+    }
+
+    let mut sorter = ExternalSorter::<RECORD_SIZE>::new(max_memory);
+    let mut pb = AddressProgressBar::new(order == Order::Eip7748);
+    loop {
+        // The address follows the account record tag.
+        let offset = reader.offset() + 1;
+        let Some(address) = reader.read_account()? else {
+            break;
+        };
+        sorter.push(record(keccak256(address), offset))?;
+        while let Some(slot) = reader.read_storage_slot()? {
+            let offset = reader.storage_slot_offset();
+            sorter.push(record(keccak256(slot), offset | STORAGE_SLOT_FLAG))?;
+        }
+        pb.progress(address);
+    }
+    if !reader.is_eof()? {
+        bail!(
+            "Trailing bytes after the footer at offset {}",
+            reader.offset()
+        );
+    }
+    let checksum = reader.footer().expect("footer was read").checksum;
+
+    // Written to a temporary path first, so a failed build doesn't leave a partial index behind.
+    let tmp_path = format!("{}.tmp", index_path);
+    let mut w = BufWriter::new(File::create(&tmp_path)?);
+    w.write_all(&MAGIC)?;
+    w.write_all(&[VERSION])?;
+    w.write_all(&reader.offset().to_be_bytes())?;
+    w.write_all(checksum.as_slice())?;
+    // The fanout is written once the entries are counted.
+    w.write_all(&vec![0u8; FANOUT_SIZE])?;
+
+    let mut stats = IndexStats {
+        entries: 0,
+        duplicates: 0,
+    };
+    let mut counts = vec![0u64; FANOUT_BUCKETS];
+    let mut last_hash: Option<[u8; 32]> = None;
+    for record in sorter.finish()? {
+        let record = record?;
+        let hash: [u8; 32] = record[..32].try_into().expect("32 bytes hash");
+        if last_hash == Some(hash) {
+            stats.duplicates += 1;
+            continue;
+        }
+        last_hash = Some(hash);
+        w.write_all(&record[2..10])?;
+        w.write_all(&record[32..])?;
+        counts[bucket(&hash)] += 1;
+        stats.entries += 1;
+    }
+
+    let mut file = w.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+    let mut w = BufWriter::new(file);
+    let mut total = 0;
+    for count in counts {
+        total += count;
+        w.write_all(&total.to_be_bytes())?;
+    }
+    w.flush()?;
+    fs::rename(&tmp_path, index_path)?;
+    Ok(stats)
+}
+
+fn record(hash: B256, offset: u64) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    record[..32].copy_from_slice(hash.as_slice());
+    record[32..].copy_from_slice(&offset.to_be_bytes());
+    record
+}
+
+fn bucket(hash: &[u8]) -> usize {
+    u16::from_be_bytes([hash[0], hash[1]]) as usize
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+/// Preimages looked up by hash from a preimage file and its index, both memory-mapped.
+pub struct PreimageStore {
+    file: Mmap,
+    index: Mmap,
+}
+
+impl PreimageStore {
+    /// Opens the preimage file at `path` and its index at `index_path`, checking the index was built
+    /// from that file.
+    pub fn open(path: &str, index_path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", path))?;
+        let index =
+            File::open(index_path).with_context(|| format!("opening index {}", index_path))?;
+        // SAFETY: both files are only read. They must not be modified while they are mapped, which is
+        // the case of preimage files and indexes once they are generated.
+        let (file, index) = unsafe { (Mmap::map(&file)?, Mmap::map(&index)?) };
+
+        if index.len() < HEADER_SIZE + FANOUT_SIZE || index[..MAGIC.len()] != MAGIC {
+            bail!("{} is not a preimage index", index_path);
+        }
+        if index[MAGIC.len()] != VERSION {
+            bail!("Unsupported preimage index version {}", index[MAGIC.len()]);
+        }
+        let checksum = &index[MAGIC.len() + 1 + 8..HEADER_SIZE];
+        if read_u64(&index, MAGIC.len() + 1) != file.len() as u64
+            || file[file.len().saturating_sub(32)..] != *checksum
+        {
+            bail!("Index {} was built from another preimage file", index_path);
+        }
+
+        let store = Self { file, index };
+        let mut previous = 0;
+        for bucket in 0..FANOUT_BUCKETS {
+            let end = store.fanout(bucket);
+            if end < previous {
+                bail!("Index {} has a malformed fanout", index_path);
+            }
+            previous = end;
+        }
+        let entries_size = store.index.len() - HEADER_SIZE - FANOUT_SIZE;
+        if Some(entries_size as u64) != previous.checked_mul(ENTRY_SIZE as u64) {
+            bail!(
+                "Index {} has {} bytes of entries, expected {} entries",
+                index_path,
+                entries_size,
+                previous
+            );
+        }
+        Ok(store)
+    }
+
+    /// Returns the number of indexed preimages.
+    pub fn len(&self) -> u64 {
+        self.fanout(FANOUT_BUCKETS - 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the address or storage slot whose keccak256 is `hash`, if it's in the preimage file.
+    pub fn get(&self, hash: B256) -> Option<Preimage> {
+        let bucket = bucket(hash.as_slice());
+        let end = self.fanout(bucket);
+        let start = match bucket {
+            0 => 0,
+            _ => self.fanout(bucket - 1),
+        };
+        let key = read_u64(hash.as_slice(), 2);
+
+        // Lower bound of the key within the bucket, then every entry sharing the stored hash bytes.
+        let (mut lo, mut hi) = (start, end);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.entry(mid).0 < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        (lo..end)
+            .map(|i| self.entry(i))
+            .take_while(|(entry_key, _)| *entry_key == key)
+            .filter_map(|(_, offset)| self.preimage(offset))
+            .find(|(bytes, _)| keccak256(bytes) == hash)
+            .map(|(_, preimage)| preimage)
+    }
+
+    fn fanout(&self, bucket: usize) -> u64 {
+        read_u64(&self.index, HEADER_SIZE + 8 * bucket)
+    }
+
+    fn entry(&self, i: u64) -> (u64, u64) {
+        let offset = HEADER_SIZE + FANOUT_SIZE + i as usize * ENTRY_SIZE;
+        (
+            read_u64(&self.index, offset),
+            read_u64(&self.index, offset + 8),
+        )
+    }
+
+    fn preimage(&self, offset: u64) -> Option<(&[u8], Preimage)> {
+        let start = (offset & !STORAGE_SLOT_FLAG) as usize;
+        if offset & STORAGE_SLOT_FLAG != 0 {
+            let bytes = self.file.get(start..start + 32)?;
+            Some((bytes, Preimage::StorageSlot(B256::from_slice(bytes))))
+        } else {
+            let bytes = self.file.get(start..start + 20)?;
+            Some((bytes, Preimage::Address(Address::from_slice(bytes))))
+        }
+    }
+}
+
+/// Preimages of the hashed state of a source keyed by hash, see
+/// [`eth_stateless::RethStateSource::with_preimages`].
+impl Preimages for PreimageStore {
+    fn address(&self, hash: B256) -> Result<Option<Address>> {
+        Ok(match self.get(hash) {
+            Some(Preimage::Address(address)) => Some(address),
+            _ => None,
+        })
+    }
+
+    fn storage_slot(&self, hash: B256) -> Result<Option<B256>> {
+        Ok(match self.get(hash) {
+            Some(Preimage::StorageSlot(slot)) => Some(slot),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FileFormat, Header, PreimageWriter};
+
+    /// Writes a delimited preimage file with the accounts `1..=count`, each with the storage slots
+    /// `0..=i`, so the small ones are repeated across accounts.
+    fn write_file(path: &str, count: u8, encoding: Encoding) {
+        let header = Header {
+            order: Order::Plain,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::repeat_byte(0xaa),
+        };
+        let file = BufWriter::new(File::create(path).unwrap());
+        let mut w =
+            PreimageWriter::with_encoding(file, FileFormat::Delimited, &header, encoding).unwrap();
+        for i in 1..=count {
+            w.write_account(Address::with_last_byte(i)).unwrap();
+            for j in 0..=i {
+                w.write_storage_slot(B256::with_last_byte(j)).unwrap();
+            }
+        }
+        w.finish().unwrap();
+    }
+
+    #[test]
+    fn looks_up_every_preimage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        write_file(path, 10, Encoding::None);
+
+        // Sorted in memory and on disk.
+        for max_memory in [1 << 20, 4 * RECORD_SIZE] {
+            let stats = build(path, &default_path(path), max_memory).unwrap();
+            assert_eq!(stats.entries, 10 + 11);
+            assert_eq!(stats.duplicates, (2..=11).sum::<u64>() - 11);
+
+            let store = PreimageStore::open(path, &default_path(path)).unwrap();
+            assert_eq!(store.len(), stats.entries);
+            for i in 1..=10 {
+                let address = Address::with_last_byte(i);
+                assert_eq!(
+                    store.get(keccak256(address)),
+                    Some(Preimage::Address(address))
+                );
+            }
+            for i in 0..=10 {
+                let slot = B256::with_last_byte(i);
+                assert_eq!(
+                    store.get(keccak256(slot)),
+                    Some(Preimage::StorageSlot(slot))
+                );
+            }
+            assert_eq!(store.get(keccak256(Address::with_last_byte(11))), None);
+            assert_eq!(store.get(B256::ZERO), None);
+
+            let address = Address::with_last_byte(1);
+            let slot = B256::with_last_byte(1);
+            assert_eq!(store.address(keccak256(address)).unwrap(), Some(address));
+            assert_eq!(store.storage_slot(keccak256(address)).unwrap(), None);
+            assert_eq!(store.storage_slot(keccak256(slot)).unwrap(), Some(slot));
+            assert_eq!(store.address(keccak256(slot)).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_indexes_of_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        write_file(path, 10, Encoding::None);
+        build(path, &default_path(path), 1 << 20).unwrap();
+
+        write_file(path, 9, Encoding::None);
+        let err = PreimageStore::open(path, &default_path(path))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("built from another preimage file"));
+
+        let mut index = fs::read(default_path(path)).unwrap();
+        index.truncate(HEADER_SIZE + FANOUT_SIZE - 1);
+        fs::write(default_path(path), index).unwrap();
+        let err = PreimageStore::open(path, &default_path(path))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("is not a preimage index"));
+
+        write_file(path, 10, Encoding::FrontCoding);
+        assert!(build(path, &default_path(path), 1 << 20).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Implementation of the delta preimage iterator.
+//!
+//! This module provides an iterator applying a state delta on top of another preimage iterator,
+//! e.g. an existing preimage file or the database tip state. Both the base iterator and the output
+//! follow the same ordering: accounts and storage slots added by the delta are merged in place, and
+//! the removed ones are skipped. Base preimages with code hashes aren't supported, since the delta
+//! doesn't track code changes.
+
+use alloy_primitives::{Address, B256};
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+
+use super::{AccountStorageItem, PreimageIterator};
+use crate::delta::{AccountDelta, Change, StateDelta};
+use crate::format::Order;
+
+pub struct DeltaIterator<I> {
+    base: I,
+    base_done: bool,
+    order: Order,
+
+    accounts: std::vec::IntoIter<(B256, Address, AccountDelta)>,
+    next_delta: Option<(B256, Address, AccountDelta)>,
+
+    current: Option<CurrentAccount>,
+    pending: VecDeque<AccountStorageItem>,
+}
+
+/// Account of the base iterator being merged with its storage slots delta.
+struct CurrentAccount {
+    address: Address,
+    removed: bool,
+    storage_slots: VecDeque<(B256, B256, Change)>,
+}
+
+impl<I: PreimageIterator> DeltaIterator<I> {
+    pub fn new(base: I, delta: StateDelta, order: Order) -> Self {
+        let mut accounts: Vec<_> = delta
+            .accounts
+            .into_iter()
+            .map(|(address, delta)| (order.account_key(address), address, delta))
+            .collect();
+        accounts.sort_unstable_by_key(|(key, _, _)| *key);
+        let mut accounts = accounts.into_iter();
+
+        Self {
+            base,
+            base_done: false,
+            order,
+            next_delta: accounts.next(),
+            accounts,
+            current: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn sorted_storage_slots(&self, delta: AccountDelta) -> VecDeque<(B256, B256, Change)> {
+        let mut storage_slots: Vec<_> = delta
+            .storage_slots
+            .into_iter()
+            .map(|(slot, change)| (self.order.storage_slot_key(slot), slot, change))
+            .collect();
+        storage_slots.sort_unstable_by_key(|(key, _, _)| *key);
+        storage_slots.into()
+    }
+
+    /// Queues the storage slots added to the current account that sort before `key`, or all of them if
+    /// `key` is `None`. Returns the change of the storage slot equal to `key`, if any.
+    fn flush_storage_slots(&mut self, key: Option<B256>) -> Option<Change> {
+        let current = self.current.as_mut()?;
+        while let Some((slot_key, slot, change)) = current.storage_slots.front().copied() {
+            if key.is_some_and(|key| slot_key > key) {
+                break;
+            }
+            current.storage_slots.pop_front();
+            if Some(slot_key) == key {
+                return Some(change);
+            }
+            if change == Change::Added && !current.removed {
+                self.pending
+                    .push_back(AccountStorageItem::StorageSlot(current.address, slot));
+            }
+        }
+        None
+    }
+
+    /// Queues the accounts added by the delta that sort before `key`, or all of them if `key` is
+    /// `None`. Returns the delta of the account equal to `key`, if any.
+    fn flush_accounts(&mut self, key: Option<B256>) -> Result<Option<AccountDelta>> {
+        while let Some((account_key, address, delta)) = self.next_delta.take() {
+            if key.is_some_and(|key| account_key > key) {
+                self.next_delta = Some((account_key, address, delta));
+                break;
+            }
+            self.next_delta = self.accounts.next();
+            if Some(account_key) == key {
+                return Ok(Some(delta));
+            }
+            if delta.change != Some(Change::Added) {
+                return Err(anyhow!(
+                    "Account {} is in the delta but missing from the base preimages",
+                    address
+                ));
+            }
+            self.pending.push_back(AccountStorageItem::Account(address));
+            for (_, slot, change) in self.sorted_storage_slots(delta) {
+                if change == Change::Added {
+                    self.pending
+                        .push_back(AccountStorageItem::StorageSlot(address, slot));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_base(&mut self) -> Result<()> {
+        match self.base.next().transpose()? {
+            Some(AccountStorageItem::Account(address)) => {
+                self.flush_storage_slots(None);
+                let delta = self.flush_accounts(Some(self.order.account_key(address)))?;
+                let mut current = CurrentAccount {
+                    address,
+                    removed: false,
+                    storage_slots: VecDeque::new(),
+                };
+                if let Some(delta) = delta {
+                    if delta.change == Some(Change::Added) {
+                        return Err(anyhow!(
+                            "Account {} is added by the delta but already in the base preimages",
+                            address
+                        ));
+                    }
+                    current.removed = delta.change == Some(Change::Removed);
+                    current.storage_slots = self.sorted_storage_slots(delta);
+                }
+                if !current.removed {
+                    self.pending.push_back(AccountStorageItem::Account(address));
+                }
+                self.current = Some(current);
+            }
+            Some(AccountStorageItem::StorageSlot(address, slot)) => {
+                let key = self.order.storage_slot_key(slot);
+                let change = self.flush_storage_slots(Some(key));
+                let removed = self.current.as_ref().is_none_or(|c| c.removed);
+                if !removed && change != Some(Change::Removed) {
+                    self.pending
+                        .push_back(AccountStorageItem::StorageSlot(address, slot));
+                }
+            }
+            Some(AccountStorageItem::Code(address, _)) => {
+                return Err(anyhow!(
+                    "Account {} has a code hash, deltas of preimages with code aren't supported",
+                    address
+                ));
+            }
+            None => {
+                self.base_done = true;
+                self.flush_storage_slots(None);
+                self.current = None;
+                self.flush_accounts(None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<I: PreimageIterator> PreimageIterator for DeltaIterator<I> {}
+
+impl<I: PreimageIterator> Iterator for DeltaIterator<I> {
+    type Item = Result<AccountStorageItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(Ok(item));
+            }
+            if self.base_done {
+                return None;
+            }
+            if let Err(e) = self.next_base() {
+                self.base_done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{FileFormat, Header, PreimageWriter};
+    use crate::iterators::file::PreimageFileReader;
+    use std::collections::BTreeMap;
+    use AccountStorageItem::{Account as A, Code as C, StorageSlot as S};
+
+    /// Accounts with their storage slots.
+    type State<'a> = &'a [(u8, &'a [u8])];
+
+    /// Accounts 1 to 3 at the first block, the second one with two storage slots.
+    const BEFORE: State = &[(1, &[1]), (2, &[1, 2]), (3, &[])];
+    /// Accounts at the second block: a storage slot added to the first account, the second one removed
+    /// with its storage slots, and an account added after the last one in the plain ordering.
+    const AFTER: State = &[(1, &[1, 3]), (3, &[]), (4, &[4])];
+
+    fn delta() -> StateDelta {
+        let account = |change, storage_slots: &[(u8, Change)]| AccountDelta {
+            change,
+            storage_slots: storage_slots
+                .iter()
+                .map(|(slot, change)| (B256::with_last_byte(*slot), *change))
+                .collect(),
+        };
+        StateDelta {
+            chain_id: 1,
+            from_block: 100,
+            to_block: 110,
+            state_root: B256::repeat_byte(0xbb),
+            removals: true,
+            accounts: BTreeMap::from([
+                (
+                    Address::with_last_byte(1),
+                    account(None, &[(3, Change::Added)]),
+                ),
+                (
+                    Address::with_last_byte(2),
+                    account(
+                        Some(Change::Removed),
+                        &[(1, Change::Removed), (2, Change::Removed)],
+                    ),
+                ),
+                (
+                    Address::with_last_byte(4),
+                    account(Some(Change::Added), &[(4, Change::Added)]),
+                ),
+            ]),
+        }
+    }
+
+    /// Returns the items of `state` in `order`.
+    fn items(state: State, order: Order) -> Vec<AccountStorageItem> {
+        let mut accounts: Vec<_> = state.iter().collect();
+        accounts.sort_by_key(|(i, _)| order.account_key(Address::with_last_byte(*i)));
+        let mut items = Vec::new();
+        for (i, slots) in accounts {
+            let address = Address::with_last_byte(*i);
+            let mut slots: Vec<_> = slots.iter().map(|j| B256::with_last_byte(*j)).collect();
+            slots.sort_by_key(|slot| order.storage_slot_key(*slot));
+            items.push(A(address));
+            items.extend(slots.into_iter().map(|slot| S(address, slot)));
+        }
+        items
+    }
+
+    /// Writes `items` to a delimited file in `order`.
+    fn file(items: &[AccountStorageItem], order: Order) -> Vec<u8> {
+        let header = Header {
+            order,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::repeat_byte(0xaa),
+        };
+        let mut w = PreimageWriter::new(Vec::new(), FileFormat::Delimited, &header).unwrap();
+        for item in items {
+            match *item {
+                A(address) => w.write_account(address),
+                S(_, slot) => w.write_storage_slot(slot),
+                C(_, code_hash) => w.write_code(code_hash),
+            }
+            .unwrap();
+        }
+        w.finish().unwrap().0
+    }
+
+    /// Applies `delta` to the preimage file of `base`.
+    fn apply(
+        base: &[AccountStorageItem],
+        delta: StateDelta,
+        order: Order,
+    ) -> Result<Vec<AccountStorageItem>> {
+        let data = file(base, order);
+        let base = PreimageFileReader::new(&data[..])?;
+        DeltaIterator::new(base, delta, order).collect()
+    }
+
+    #[test]
+    fn merges_added_and_removed_accounts_and_storage_slots() {
+        for order in [Order::Plain, Order::Eip7748] {
+            let merged = apply(&items(BEFORE, order), delta(), order).unwrap();
+            assert_eq!(merged, items(AFTER, order), "{} ordering", order);
+        }
+    }
+
+    #[test]
+    fn rewinds_the_state_with_the_reversed_delta() {
+        // The state of the database tip rewound to the first block, as generated with `--block`: the
+        // removed account is added back with its storage slots, and the added ones are removed.
+        for order in [Order::Plain, Order::Eip7748] {
+            let reversed = delta().reversed(B256::repeat_byte(0xaa));
+            let rewound = apply(&items(AFTER, order), reversed, order).unwrap();
+            assert_eq!(rewound, items(BEFORE, order), "{} ordering", order);
+        }
+    }
+
+    #[test]
+    fn rejects_deltas_of_other_states() {
+        let order = Order::Plain;
+        let err = apply(&items(AFTER, order), delta(), order).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Account {} is in the delta but missing from the base preimages",
+                Address::with_last_byte(2)
+            )
+        );
+        let err = apply(
+            &items(&[(1, &[1]), (2, &[]), (4, &[])], order),
+            delta(),
+            order,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("added by the delta but already in the base"));
+
+        let mut base = items(BEFORE, order);
+        base.insert(2, C(Address::with_last_byte(1), B256::repeat_byte(0xcc)));
+        let err = apply(&base, delta(), order).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("deltas of preimages with code aren't supported"));
+    }
+}
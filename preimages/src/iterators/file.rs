@@ -0,0 +1,306 @@
+//! Implementation of the preimage file iterator.
+//!
+//! This module provides an account and storage slot iterator over an existing delimited preimage file,
+//! so the preimages can be interpreted without a database. The ordering is the one the file was
+//! generated with, which is recorded in its header.
+//!
+//! Chunked preimage files are read from their manifest, chunk after chunk (see [`crate::chunks`]).
+//!
+//! Raw preimage files aren't supported since the storage slots can't be attributed to an account
+//! without the database.
+
+use alloy_primitives::Address;
+use anyhow::{anyhow, bail, Result};
+use std::{collections::VecDeque, io::BufRead};
+
+use super::{AccountStorageItem, PreimageIterator};
+use crate::chunks::{self, Manifest};
+use crate::compress;
+use crate::format::{Encoding, Header, PreimageReader};
+
+pub struct PreimageFileReader<R: BufRead> {
+    reader: PreimageReader<R>,
+    header: Header,
+    encoding: Encoding,
+    /// Paths of the chunks left to read, and how to open them.
+    chunks: VecDeque<String>,
+    open_chunk: Option<fn(&str) -> Result<R>>,
+
+    state: State,
+}
+
+enum State {
+    Account,
+    StorageSlot(Address),
+    End,
+}
+
+impl PreimageFileReader<Box<dyn BufRead + Send>> {
+    /// Opens the preimage file at `path`, or the standard input if it's `-`, decompressing it if needed.
+    /// If `path` is the manifest of a chunked preimage file, its chunks are read in order.
+    pub fn open(path: &str) -> Result<Self> {
+        if !chunks::is_manifest(path) {
+            return Self::new(compress::open(path)?);
+        }
+        let manifest = Manifest::read(path)?;
+        let mut chunks: VecDeque<_> = manifest
+            .chunks
+            .iter()
+            .map(|chunk| manifest.chunk_path(chunk))
+            .collect();
+        let Some(first) = chunks.pop_front() else {
+            bail!("Manifest {} has no chunks", path);
+        };
+        let mut reader = Self::new(compress::open(&first)?)?;
+        if reader.header != manifest.header {
+            bail!("Chunk {} has another header than the manifest", first);
+        }
+        reader.chunks = chunks;
+        reader.open_chunk = Some(compress::open);
+        Ok(reader)
+    }
+}
+
+impl<R: BufRead> PreimageFileReader<R> {
+    pub fn new(r: R) -> Result<Self> {
+        let reader = PreimageReader::new(r)?;
+        let header = reader.header().cloned().ok_or(anyhow!(
+            "Raw preimage files can't be read without the database"
+        ))?;
+        let encoding = reader.encoding().clone();
+        Ok(Self {
+            reader,
+            header,
+            encoding,
+            chunks: VecDeque::new(),
+            open_chunk: None,
+            state: State::Account,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn encoding(&self) -> &Encoding {
+        &self.encoding
+    }
+
+    /// Continues with the next chunk, returning `false` if there are no more.
+    fn next_chunk(&mut self) -> Result<bool> {
+        let (Some(path), Some(open_chunk)) = (self.chunks.pop_front(), self.open_chunk) else {
+            return Ok(false);
+        };
+        let reader = PreimageReader::new(open_chunk(&path)?)?;
+        if reader.header() != Some(&self.header) || *reader.encoding() != self.encoding {
+            bail!(
+                "Chunk {} has another header or encoding than the first one",
+                path
+            );
+        }
+        self.reader = reader;
+        Ok(true)
+    }
+}
+
+impl<R: BufRead> PreimageIterator for PreimageFileReader<R> {}
+
+impl<R: BufRead> Iterator for PreimageFileReader<R> {
+    type Item = Result<AccountStorageItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.state {
+            State::Account => match self.reader.read_account() {
+                Ok(Some(address)) => {
+                    self.state = State::StorageSlot(address);
+                    Some(Ok(AccountStorageItem::Account(address)))
+                }
+                Ok(None) => {
+                    self.state = State::End;
+                    match self.reader.is_eof() {
+                        Ok(true) => match self.next_chunk() {
+                            Ok(true) => {
+                                self.state = State::Account;
+                                self.next()
+                            }
+                            Ok(false) => None,
+                            Err(e) => Some(Err(e)),
+                        },
+                        Ok(false) => Some(Err(anyhow!(
+                            "Trailing bytes after the footer at offset {}",
+                            self.reader.offset()
+                        ))),
+                        Err(e) => Some(Err(e)),
+                    }
+                }
+                Err(e) => {
+                    self.state = State::End;
+                    Some(Err(e))
+                }
+            },
+            State::StorageSlot(address) => match self.reader.read_storage_slot() {
+                Ok(Some(key)) => Some(Ok(AccountStorageItem::StorageSlot(address, key))),
+                Ok(None) => {
+                    self.state = State::Account;
+                    match self.reader.code_hash() {
+                        Some(code_hash) => Some(Ok(AccountStorageItem::Code(address, code_hash))),
+                        None => self.next(),
+                    }
+                }
+                Err(e) => {
+                    self.state = State::End;
+                    Some(Err(e))
+                }
+            },
+            State::End => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::{manifest_path, ChunkedWriter};
+    use crate::cmds::PreimageSink;
+    use crate::format::{FileFormat, Order, PreimageWriter};
+    use alloy_primitives::B256;
+    use AccountStorageItem::{Account as A, Code as C, StorageSlot as S};
+
+    fn header() -> Header {
+        Header {
+            order: Order::Plain,
+            chain_id: 1,
+            block_number: 100,
+            state_root: B256::repeat_byte(0xaa),
+        }
+    }
+
+    fn items() -> Vec<AccountStorageItem> {
+        let (a, b, c) = (
+            Address::with_last_byte(1),
+            Address::with_last_byte(2),
+            Address::with_last_byte(3),
+        );
+        vec![
+            A(a),
+            A(b),
+            S(b, B256::with_last_byte(1)),
+            S(b, B256::with_last_byte(2)),
+            C(b, B256::repeat_byte(0xcc)),
+            A(c),
+            S(c, B256::with_last_byte(3)),
+        ]
+    }
+
+    fn file(header: &Header, items: &[AccountStorageItem]) -> Vec<u8> {
+        let mut w = PreimageWriter::new(Vec::new(), FileFormat::Delimited, header).unwrap();
+        for item in items {
+            match *item {
+                A(address) => w.write_account(address),
+                S(_, slot) => w.write_storage_slot(slot),
+                C(_, code_hash) => w.write_code(code_hash),
+            }
+            .unwrap();
+        }
+        w.finish().unwrap().0
+    }
+
+    /// Reads `data` until the first error, returning the items read before it and the error.
+    fn read(data: &[u8]) -> (Vec<AccountStorageItem>, Option<String>) {
+        let mut it = PreimageFileReader::new(data).unwrap();
+        let mut items = Vec::new();
+        for item in &mut it {
+            match item {
+                Ok(item) => items.push(item),
+                Err(e) => {
+                    assert!(it.next().is_none(), "items after an error");
+                    return (items, Some(format!("{:#}", e)));
+                }
+            }
+        }
+        (items, None)
+    }
+
+    #[test]
+    fn reads_every_item_of_a_file() {
+        let data = file(&header(), &items());
+        let it = PreimageFileReader::new(&data[..]).unwrap();
+        assert_eq!(it.header(), &header());
+        assert_eq!(it.encoding(), &Encoding::None);
+        assert_eq!(read(&data), (items(), None));
+
+        let raw = PreimageWriter::new(Vec::new(), FileFormat::Raw, &header()).unwrap();
+        let raw = raw.finish().unwrap().0;
+        let err = PreimageFileReader::new(&raw[..]).err().unwrap();
+        assert!(err.to_string().contains("Raw preimage files"));
+    }
+
+    #[test]
+    fn rejects_truncated_files_and_trailing_bytes() {
+        let data = file(&header(), &items());
+        // The footer is 49 bytes, and the last storage slot is the 32 bytes before it.
+        let last_slot = data.len() - 49 - 32;
+        let (read_items, err) = read(&data[..last_slot + 10]);
+        assert_eq!(read_items, items()[..6]);
+        assert!(err
+            .unwrap()
+            .contains(&format!("Truncated record at offset {}", last_slot)));
+
+        let (read_items, err) = read(&data[..data.len() - 1]);
+        assert_eq!(read_items, items());
+        assert!(err.unwrap().contains("reading footer"));
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        let (read_items, err) = read(&trailing);
+        assert_eq!(read_items, items());
+        assert_eq!(
+            err.unwrap(),
+            format!("Trailing bytes after the footer at offset {}", data.len())
+        );
+    }
+
+    #[test]
+    fn reads_the_chunks_of_a_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("preimages.bin");
+        let path = path.to_str().unwrap();
+        // Chunks of a single account, since each one is bigger than the chunk size.
+        let mut w = ChunkedWriter::new(path, &header(), Encoding::None, None, 1).unwrap();
+        for item in items() {
+            match item {
+                A(address) => w.write_account(address),
+                S(_, slot) => w.write_storage_slot(slot),
+                C(_, code_hash) => w.write_code(code_hash),
+            }
+            .unwrap();
+        }
+        let (manifest, _) = w.finish().unwrap();
+        assert_eq!(manifest.chunks.len(), 3);
+
+        let it = PreimageFileReader::open(&manifest_path(path)).unwrap();
+        assert_eq!(it.header(), &header());
+        let read: Vec<_> = it.map(|item| item.unwrap()).collect();
+        assert_eq!(read, items());
+
+        // A chunk of another state than the first one.
+        let mut other = header();
+        other.block_number += 1;
+        let chunk_path = manifest.chunk_path(&manifest.chunks[2]);
+        std::fs::write(&chunk_path, file(&other, &items()[5..])).unwrap();
+        let err = PreimageFileReader::open(&manifest_path(path))
+            .unwrap()
+            .find_map(|item| item.err())
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Chunk {} has another header or encoding than the first one",
+                chunk_path
+            )
+        );
+
+        std::fs::remove_file(manifest.chunk_path(&manifest.chunks[0])).unwrap();
+        assert!(PreimageFileReader::open(&manifest_path(path)).is_err());
+    }
+}
@@ -1,19 +1,15 @@
 //! Multiple iterator implementators to dump the preimages in different orders
 //!
-//! This crate provides two different implementations of the preimage iterator:
-//! - EIP-7748: The iterator respects the order defined in EIP-7748.
-//! - Plain: The iterator respects the plain ordering in the database.
+//! On top of the state source iterators of the `eth-stateless` crate (EIP-7748 and Plain), this module
+//! provides:
+//! - File: The iterator reads an existing delimited preimage file, in the order it was generated.
+//! - Delta: The iterator applies a state delta on top of another iterator, keeping its ordering.
 //!
 //! See each module docs for more information.
 
-use alloy_primitives::{Address, B256};
-use anyhow::Result;
+pub use eth_stateless::iterators::{
+    code, eip7748, eip7748_external, plain, AccountStorageItem, PreimageIterator,
+};
 
-pub mod eip7748;
-pub mod plain;
-
-pub enum AccountStorageItem {
-    Account(Address),
-    StorageSlot(Address, B256),
-}
-pub trait PreimageIterator: Iterator<Item = Result<AccountStorageItem>> {}
+pub mod delta;
+pub mod file;
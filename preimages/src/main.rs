@@ -1,27 +1,55 @@
-use anyhow::{anyhow, Result};
+use alloy_primitives::{keccak256, B256};
+use anyhow::{anyhow, bail, Result};
+use checkpoint::{Checkpoint, Checkpointer, ResumedIterator};
 use clap::{command, Args, Parser};
-use iterators::{eip7748::Eip7748Iterator, plain::PlainIterator};
-use progress::AddressProgressBar;
-use reth_chainspec::ChainSpecBuilder;
-use reth_db::{
-    mdbx::{tx::Tx, DatabaseArguments, MaxReadTransactionDuration, RO},
-    DatabaseEnv,
+use cmds::DumpFormat;
+use compress::{Compression, Compressor, STDIO_PATH};
+use delta::StateDelta;
+use eth_stateless::{
+    chain_spec, progress::AddressProgressBar, JsonStateSource, RethStateSource, StateSource,
 };
-use reth_node_ethereum::EthereumNode;
-use reth_node_types::NodeTypesWithDBAdapter;
-use reth_provider::{providers::StaticFileProvider, ProviderFactory, StageCheckpointReader};
-use reth_stages::StageId;
-use std::{path::Path, sync::Arc};
+use format::{Encoding, FileFormat, Header, Order};
+use iterators::{
+    code::CodeItems, delta::DeltaIterator, eip7748::Eip7748Iterator,
+    eip7748_external::Eip7748ExternalIterator, plain::PlainIterator, PreimageIterator,
+};
+use reth_provider::HeaderProvider;
+use shards::{Shard, ShardedAccounts};
+use std::thread::{self, Scope};
 
+mod checkpoint;
+mod chunks;
 mod cmds;
+mod compress;
+mod conversion;
+mod delta;
+mod format;
+mod geth;
+mod hashed;
+mod index;
 mod iterators;
-mod progress;
+mod report;
+mod shards;
 
 #[derive(Parser)]
 #[command(name = "report")]
 struct Cli {
     #[arg(short = 'd', long = "datadir", help = "Reth datadir path")]
-    datadir: String,
+    datadir: Option<String>,
+
+    #[arg(
+        long = "state-json",
+        help = "JSON state dump path (genesis alloc, geth dump or debug_dumpBlock) to use instead of a datadir",
+        conflicts_with = "datadir"
+    )]
+    state_json: Option<String>,
+
+    #[arg(
+        long = "chain",
+        help = "Chain name (mainnet, sepolia, holesky, dev) or genesis JSON file path",
+        default_value = "mainnet"
+    )]
+    chain: String,
 
     #[command(subcommand)]
     subcmd: SubCommand,
@@ -33,13 +61,89 @@ enum SubCommand {
     Generate {
         #[arg(
             long = "output-path",
-            help = "Preimages file output path",
+            help = "Preimages file output path, or - for the standard output",
             default_value = "preimages.bin"
         )]
         path: String,
 
+        #[arg(
+            long = "format",
+            help = "Preimages file format",
+            value_enum,
+            default_value_t = FileFormat::Raw
+        )]
+        format: FileFormat,
+
+        #[arg(
+            long = "block",
+            help = "Block number of the state, rewound from the database tip with the changesets"
+        )]
+        block: Option<u64>,
+
+        #[arg(
+            long = "max-memory",
+            value_name = "MiB",
+            help = "Sort the EIP-7748 ordering on disk using at most this much memory",
+            requires = "eip7748",
+            conflicts_with = "shards"
+        )]
+        max_memory: Option<usize>,
+
+        #[arg(
+            long = "resume",
+            help = "Resume from the checkpoint of a previous run",
+            conflicts_with = "shards"
+        )]
+        resume: bool,
+
+        #[arg(
+            long = "slot-dictionary",
+            value_name = "K",
+            help = "Encode storage slots with a dictionary of their K most frequent 29-byte prefixes (up to 255)",
+            value_parser = clap::value_parser!(u8).range(1..)
+        )]
+        slot_dictionary: Option<u8>,
+
+        #[arg(
+            long = "front-coding",
+            help = "Encode addresses and storage slots as the bytes they don't share with the previous one",
+            requires = "plain",
+            conflicts_with = "slot_dictionary"
+        )]
+        front_coding: bool,
+
+        #[arg(
+            long = "compress",
+            help = "Compress the preimages file as it's written",
+            value_enum,
+            conflicts_with_all = ["resume", "shards"]
+        )]
+        compress: Option<Compression>,
+
+        #[arg(
+            long = "compress-level",
+            help = "Compression level, from 1 to 22 for zstd (default 3) and 0 to 9 for xz (default 6)",
+            requires = "compress"
+        )]
+        compress_level: Option<u32>,
+
+        #[arg(
+            long = "chunk-size",
+            value_name = "SIZE",
+            help = "Split the preimages file at account boundaries in chunks of about SIZE bytes (e.g: 256MiB), listed in <output-path>.manifest.json",
+            value_parser = parse_size,
+            conflicts_with_all = ["resume", "shards"]
+        )]
+        chunk_size: Option<u64>,
+
+        #[command(flatten)]
+        code: CodeArgs,
+
         #[command(flatten)]
         order: OrderArgs,
+
+        #[command(flatten)]
+        shards: ShardArgs,
     },
 
     #[command(name = "verify", about = "Verify preimage file")]
@@ -47,10 +151,235 @@ enum SubCommand {
         #[arg(long = "path", help = "Preimages file path to verify")]
         path: String,
 
+        #[arg(
+            long = "full-scan",
+            help = "Keep verifying after the first problem and report all of them (delimited files only)"
+        )]
+        full_scan: bool,
+
+        #[arg(
+            long = "max-errors",
+            help = "Stop the full scan after this many problems",
+            requires = "full_scan"
+        )]
+        max_errors: Option<usize>,
+
+        #[arg(
+            long = "block",
+            help = "Block number of the state, rewound from the database tip with the changesets"
+        )]
+        block: Option<u64>,
+
+        #[arg(
+            long = "max-memory",
+            value_name = "MiB",
+            help = "Sort the EIP-7748 ordering on disk using at most this much memory",
+            requires = "eip7748"
+        )]
+        max_memory: Option<usize>,
+
+        #[arg(long = "resume", help = "Resume from the checkpoint of a previous run")]
+        resume: bool,
+
+        #[arg(
+            long = "chunk",
+            help = "The file is a single chunk of a chunked preimages file, only verify its key range"
+        )]
+        chunk: bool,
+
+        #[arg(
+            long = "hashed",
+            help = "Verify an EIP-7748 file against the hashed state tables instead, checking every hashed key has a preimage",
+            requires = "eip7748",
+            conflicts_with_all = ["block", "max_memory", "resume", "chunk", "include_code"]
+        )]
+        hashed: bool,
+
+        #[command(flatten)]
+        code: CodeArgs,
+
         #[command(flatten)]
         order: OrderArgs,
     },
 
+    #[command(
+        name = "verify-geth",
+        about = "Verify a geth-exported preimage file has the preimages of the state"
+    )]
+    VerifyGeth {
+        #[arg(
+            long = "path",
+            help = "Geth preimages file path (export-preimages output, gzip-compressed if it ends with .gz)"
+        )]
+        path: String,
+
+        #[arg(long = "max-errors", help = "Stop after this many missing preimages")]
+        max_errors: Option<usize>,
+
+        #[arg(
+            long = "block",
+            help = "Block number of the state, rewound from the database tip with the changesets"
+        )]
+        block: Option<u64>,
+
+        #[arg(
+            long = "max-memory",
+            value_name = "MiB",
+            help = "Sort the preimage hashes using at most this much memory",
+            default_value_t = 1024
+        )]
+        max_memory: usize,
+    },
+
+    #[command(
+        name = "state-root",
+        about = "Recompute the state root from an EIP-7748 preimage file and the state values"
+    )]
+    StateRoot {
+        #[arg(long = "path", help = "Preimages file path (or chunked file manifest)")]
+        path: String,
+    },
+
+    #[command(
+        name = "simulate-conversion",
+        about = "Simulate the EIP-7748 conversion of the state, printing the timeline of the converted blocks"
+    )]
+    SimulateConversion {
+        #[arg(
+            long = "stride",
+            value_name = "N",
+            help = "Conversion units per block, several comma-separated strides are simulated in a single pass",
+            required = true,
+            value_delimiter = ',',
+            value_parser = clap::value_parser!(u64).range(1..)
+        )]
+        strides: Vec<u64>,
+
+        #[arg(
+            long = "start-block",
+            value_name = "B",
+            help = "Block the conversion starts at [default: the block after the state]"
+        )]
+        start_block: Option<u64>,
+
+        #[arg(
+            long = "max-memory",
+            value_name = "MiB",
+            help = "Sort the EIP-7748 ordering on disk using at most this much memory"
+        )]
+        max_memory: Option<usize>,
+
+        #[arg(
+            long = "output",
+            help = "Output format",
+            value_enum,
+            default_value_t = DumpFormat::Csv
+        )]
+        output: DumpFormat,
+    },
+
+    #[command(
+        name = "delta",
+        about = "Generate preimage delta since the block of an existing preimage file"
+    )]
+    Delta {
+        #[arg(
+            long = "from",
+            value_name = "FILE[@BLOCK]",
+            help = "Preimages file the delta starts from (the block is required for raw files)"
+        )]
+        from: String,
+
+        #[arg(
+            long = "output-path",
+            help = "Delta file output path",
+            default_value = "preimages.delta"
+        )]
+        path: String,
+
+        #[arg(
+            long = "include-removed",
+            help = "Include removed accounts and storage slots (required to apply the delta)"
+        )]
+        include_removed: bool,
+    },
+
+    #[command(
+        name = "apply-delta",
+        about = "Apply preimage delta to an existing preimage file"
+    )]
+    ApplyDelta {
+        #[arg(long = "base", help = "Preimages file the delta was generated from")]
+        base: String,
+
+        #[arg(long = "delta", help = "Delta file path")]
+        delta: String,
+
+        #[arg(long = "output-path", help = "Preimages file output path")]
+        path: String,
+    },
+
+    #[command(
+        name = "check",
+        about = "Check preimage file structure without a datadir"
+    )]
+    Check {
+        #[arg(long = "path", help = "Preimages file path to check")]
+        path: String,
+
+        #[arg(long = "max-errors", help = "Stop after this many problems")]
+        max_errors: Option<usize>,
+    },
+
+    #[command(name = "dump", about = "Dump preimage file as JSON lines or CSV")]
+    Dump {
+        #[arg(long = "path", help = "Preimages file path to dump")]
+        path: String,
+
+        #[arg(
+            long = "output",
+            help = "Output format",
+            value_enum,
+            default_value_t = DumpFormat::Jsonl
+        )]
+        output: DumpFormat,
+    },
+
+    #[command(
+        name = "index",
+        about = "Build the lookup index of a delimited preimage file by hashed key"
+    )]
+    Index {
+        #[arg(long = "path", help = "Preimages file path to index")]
+        path: String,
+
+        #[arg(long = "output-path", help = "Index output path [default: <path>.idx]")]
+        index_path: Option<String>,
+
+        #[arg(
+            long = "max-memory",
+            value_name = "MiB",
+            help = "Sort the hashed keys using at most this much memory",
+            default_value_t = 1024
+        )]
+        max_memory: usize,
+    },
+
+    #[command(
+        name = "lookup",
+        about = "Look up preimages by hash in an indexed preimage file"
+    )]
+    Lookup {
+        #[arg(long = "path", help = "Preimages file path")]
+        path: String,
+
+        #[arg(long = "index", help = "Index path [default: <path>.idx]")]
+        index_path: Option<String>,
+
+        #[arg(required = true, help = "Hashed addresses or storage slots to look up")]
+        hashes: Vec<B256>,
+    },
+
     #[command(
         name = "storage-slot-freq",
         about = "Analyze storage-slot 29-byte prefix frequency and size impact"
@@ -58,6 +387,67 @@ enum SubCommand {
     StorageSlotsFrequency,
 }
 
+#[derive(Args)]
+struct ShardArgs {
+    #[arg(
+        long = "shards",
+        value_name = "N",
+        help = "Generate N shards of the key space in parallel and concatenate them"
+    )]
+    shards: Option<usize>,
+
+    #[arg(
+        long = "shard",
+        value_name = "I",
+        help = "Only (re)generate shard I, concatenating the shards once all of them are present",
+        requires = "shards"
+    )]
+    shard: Option<usize>,
+
+    #[arg(
+        long = "keep-shards",
+        help = "Keep the shard files after concatenating them",
+        requires = "shards"
+    )]
+    keep_shards: bool,
+}
+
+#[derive(Args)]
+struct CodeArgs {
+    #[arg(
+        long = "include-code",
+        help = "Include the code hash of each contract after its storage slots (delimited format only)"
+    )]
+    include_code: bool,
+
+    #[arg(
+        long = "dedup-code",
+        help = "Only include the code hash of the first contract with each code",
+        requires = "include_code"
+    )]
+    dedup_code: bool,
+}
+
+impl CodeArgs {
+    /// Returns the code items of the contracts to include. The code hashes are read from the database
+    /// tip, and deduplicating them requires every contract from the first account.
+    fn items(&self, target: &TargetState, resume: bool) -> Result<CodeItems> {
+        if !self.include_code {
+            return Ok(CodeItems::Skip);
+        }
+        if target.rewind.is_some() {
+            bail!("--include-code requires the state of the database tip, not a past block");
+        }
+        if self.dedup_code && resume {
+            bail!("--dedup-code can't be resumed");
+        }
+        Ok(match self.dedup_code {
+            true => CodeItems::Deduplicated,
+            false => CodeItems::All,
+        })
+    }
+}
+
 #[derive(Args)]
 #[group(required = true, multiple = false)]
 struct OrderArgs {
@@ -67,80 +457,679 @@ struct OrderArgs {
     eip7748: bool,
 }
 
+impl OrderArgs {
+    fn order(&self) -> Result<Order> {
+        match (self.plain, self.eip7748) {
+            (true, _) => Ok(Order::Plain),
+            (_, true) => Ok(Order::Eip7748),
+            _ => Err(anyhow!("No ordering specified")),
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    let db_path = Path::new(&cli.datadir).join("db");
-    let db = reth_db::open_db_read_only(
-        db_path.as_ref(),
-        DatabaseArguments::default()
-            .with_max_read_transaction_duration(Some(MaxReadTransactionDuration::Unbounded)),
-    )
-    .map_err(|err| anyhow!(err))?;
-    let spec = ChainSpecBuilder::mainnet().build();
-    let factory = ProviderFactory::<NodeTypesWithDBAdapter<EthereumNode, Arc<DatabaseEnv>>>::new(
-        db.into(),
-        spec.into(),
-        StaticFileProvider::read_only(db_path.join("static_files"), true)?,
-    );
-    let provider = factory.provider()?;
+    // Commands working only on preimage files don't require a datadir.
+    match cli.subcmd {
+        SubCommand::ApplyDelta { base, delta, path } => {
+            return cmds::apply_delta(&base, &delta, &path)
+        }
+        SubCommand::Check { path, max_errors } => return cmds::check(&path, max_errors),
+        SubCommand::Dump { path, output } => return cmds::dump(&path, output),
+        SubCommand::Index {
+            path,
+            index_path,
+            max_memory,
+        } => {
+            let index_path = index_path.unwrap_or_else(|| index::default_path(&path));
+            return cmds::index(&path, &index_path, max_memory << 20);
+        }
+        SubCommand::Lookup {
+            path,
+            index_path,
+            hashes,
+        } => {
+            let index_path = index_path.unwrap_or_else(|| index::default_path(&path));
+            return cmds::lookup(&path, &index_path, &hashes);
+        }
+        _ => {}
+    }
+
+    if let Some(path) = cli.state_json {
+        let source = JsonStateSource::open(&path)?;
+        let chain_id = chain_spec(&cli.chain)?.chain.id();
+        eprintln!("State dump accounts: {}", source.accounts_count()?);
+        let target_state = |block: Option<u64>| -> Result<TargetState> {
+            if block.is_some() {
+                bail!("--block requires --datadir");
+            }
+            Ok(TargetState {
+                chain_id,
+                source_block_number: 0,
+                block_number: 0,
+                state_root: source.state_root.unwrap_or_default(),
+                rewind: None,
+            })
+        };
+        return run(&source, cli.subcmd, target_state);
+    }
 
-    let latest_block_number = provider
-        .get_stage_checkpoint(StageId::Finish)?
-        .map(|ch| ch.block_number)
-        .ok_or(anyhow!("No finish checkpoint"))?;
-    println!("Database block number: {:?}", latest_block_number);
+    let datadir = cli.datadir.ok_or(anyhow!(
+        "--datadir or --state-json is required for this command"
+    ))?;
+    let source = RethStateSource::open(&datadir, &cli.chain)?;
+    let chain_id = source.chain_id();
+    let latest_block_number = source.block_number;
+    eprintln!("Chain: {} (id {})", source.chain_spec.chain, chain_id);
+    eprintln!("Database block number: {:?}", latest_block_number);
+    let state_root_at = |block_number: u64| -> Result<B256> {
+        source
+            .provider()
+            .header_by_number(block_number)?
+            .map(|h| h.state_root)
+            .ok_or(anyhow!("No header for block {}", block_number))
+    };
+    let state_root = state_root_at(latest_block_number)?;
 
-    let tx = provider.tx_ref();
+    let tx = source.tx();
+    let target_state = |block: Option<u64>| -> Result<TargetState> {
+        let Some(block_number) = block.filter(|b| *b != latest_block_number) else {
+            return Ok(TargetState {
+                chain_id,
+                source_block_number: latest_block_number,
+                block_number: latest_block_number,
+                state_root,
+                rewind: None,
+            });
+        };
+        eprintln!("Rewinding state to block {}...", block_number);
+        let block_state_root = state_root_at(block_number)?;
+        let delta = StateDelta::from_changesets(
+            tx,
+            chain_id,
+            block_number,
+            latest_block_number,
+            state_root,
+        )?;
+        Ok(TargetState {
+            chain_id,
+            source_block_number: latest_block_number,
+            block_number,
+            state_root: block_state_root,
+            rewind: Some(delta.reversed(block_state_root)),
+        })
+    };
     match cli.subcmd {
-        SubCommand::Generate { path, order } => generate_cmd(tx, &path, order)?,
-        SubCommand::Verify { path, order } => {
-            verify_cmd(tx, &path, order)?;
+        SubCommand::Delta {
+            from,
+            path,
+            include_removed,
+        } => cmds::delta(
+            tx,
+            chain_id,
+            &from,
+            latest_block_number,
+            state_root,
+            &path,
+            include_removed,
+        ),
+        SubCommand::Verify {
+            path,
+            full_scan,
+            max_errors,
+            hashed: true,
+            ..
+        } => verify_hashed_cmd(&source, &path, full_scan, max_errors),
+        subcmd => run(&source, subcmd, target_state),
+    }
+}
+
+/// Runs the commands supported by any state source.
+fn run(
+    source: &impl StateSource,
+    subcmd: SubCommand,
+    target_state: impl Fn(Option<u64>) -> Result<TargetState>,
+) -> Result<()> {
+    match subcmd {
+        SubCommand::Generate {
+            path,
+            format,
+            block,
+            max_memory,
+            resume,
+            slot_dictionary,
+            front_coding,
+            compress,
+            compress_level,
+            chunk_size,
+            code,
+            order,
+            shards,
+        } => match shards.shards {
+            Some(_) if code.include_code => bail!("--include-code can't be used with --shards"),
+            Some(count) => generate_sharded_cmd(
+                source,
+                &path,
+                format,
+                target_state(block)?,
+                slot_dictionary,
+                front_coding,
+                order,
+                count,
+                shards,
+            )?,
+            None => generate_cmd(
+                source,
+                &path,
+                format,
+                target_state(block)?,
+                max_memory,
+                resume,
+                slot_dictionary,
+                front_coding,
+                compress
+                    .map(|compression| Compressor::new(compression, compress_level))
+                    .transpose()?,
+                chunk_size,
+                code,
+                order,
+            )?,
+        },
+        SubCommand::Verify {
+            path,
+            full_scan,
+            max_errors,
+            block,
+            max_memory,
+            resume,
+            chunk,
+            hashed: false,
+            code,
+            order,
+        } => {
+            verify_cmd(
+                source,
+                &path,
+                full_scan,
+                max_errors,
+                target_state(block)?,
+                max_memory,
+                resume,
+                chunk,
+                code,
+                order,
+            )?;
+        }
+        SubCommand::VerifyGeth {
+            path,
+            max_errors,
+            block,
+            max_memory,
+        } => {
+            let it = target_state(block)?.iter(PlainIterator::new(source)?, Order::Plain, None);
+            cmds::verify_geth(&path, it, max_memory << 20, max_errors)?;
+        }
+        SubCommand::StateRoot { path } => {
+            cmds::state_root(source, &path, &target_state(None)?.header(Order::Eip7748))?
         }
-        SubCommand::StorageSlotsFrequency => cmds::storage_slot_freq::<29>(tx, 1_000)?,
+        SubCommand::SimulateConversion {
+            strides,
+            start_block,
+            max_memory,
+            output,
+        } => {
+            let start_block = start_block.unwrap_or(target_state(None)?.block_number + 1);
+            let mut steps = Steps::new(2);
+            thread::scope(|scope| -> Result<()> {
+                steps.next("Ordering account addresses by hash");
+                let code = CodeItems::All;
+                let it = source_iter(scope, source, Order::Eip7748, max_memory, code, None)?;
+                steps.next("Simulating the conversion");
+                cmds::simulate_conversion(
+                    source,
+                    it,
+                    &strides,
+                    start_block,
+                    output,
+                    AddressProgressBar::new(true),
+                )
+            })?;
+        }
+        SubCommand::StorageSlotsFrequency => cmds::storage_slot_freq::<29>(source, 1_000)?,
+        SubCommand::Delta { .. } => bail!("The delta command requires --datadir"),
+        SubCommand::Verify { hashed: true, .. } => bail!("verify --hashed requires --datadir"),
+        SubCommand::ApplyDelta { .. }
+        | SubCommand::Check { .. }
+        | SubCommand::Dump { .. }
+        | SubCommand::Index { .. }
+        | SubCommand::Lookup { .. } => unreachable!("handled without a datadir"),
     }
 
     Ok(())
 }
 
-fn generate_cmd(tx: &Tx<RO>, path: &str, order: OrderArgs) -> Result<()> {
-    if order.plain {
-        println!("[1/1] Generating preimage file...");
-        cmds::generate(
-            path,
-            PlainIterator::new(tx)?,
-            AddressProgressBar::new(false),
-        )?;
-    } else if order.eip7748 {
-        println!("[1/2] Ordering account addresses by hash...");
-        let mut pb = AddressProgressBar::new(false);
-        let it = Eip7748Iterator::new(tx, Some(|addr| pb.progress(addr)))?;
-        println!("[2/2] Generating preimage file...");
-        cmds::generate(path, it, AddressProgressBar::new(true))?;
-    } else {
-        return Err(anyhow!("No ordering specified"));
+/// State the preimages are generated or verified for: the database tip, a past block whose keys are
+/// rebuilt by rewinding the tip state with the changesets, or a JSON state dump (recorded as block 0).
+struct TargetState {
+    chain_id: u64,
+    /// Block number of the state source, e.g: the database tip.
+    source_block_number: u64,
+    block_number: u64,
+    state_root: B256,
+    rewind: Option<StateDelta>,
+}
+
+impl TargetState {
+    fn header(&self, order: Order) -> Header {
+        Header {
+            order,
+            chain_id: self.chain_id,
+            block_number: self.block_number,
+            state_root: self.state_root,
+        }
+    }
+
+    /// Returns `it` applying the rewind delta, if any. If `resume` is set, `it` must start at or after the
+    /// last account of the checkpoint, which is skipped along with any account before it.
+    fn iter<'a>(
+        self,
+        it: impl PreimageIterator + 'a,
+        order: Order,
+        resume: Option<&Checkpoint>,
+    ) -> Box<dyn PreimageIterator + 'a> {
+        let it: Box<dyn PreimageIterator + 'a> = match self.rewind {
+            Some(mut delta) => {
+                if let Some(checkpoint) = resume {
+                    let last_key = order.account_key(checkpoint.last_account);
+                    delta
+                        .accounts
+                        .retain(|address, _| order.account_key(*address) > last_key);
+                }
+                Box::new(DeltaIterator::new(it, delta, order))
+            }
+            None => Box::new(it),
+        };
+        match resume {
+            Some(checkpoint) => Box::new(ResumedIterator::new(it, order, checkpoint.last_account)),
+            None => it,
+        }
+    }
+}
+
+/// Returns the iterator of `source` in `order` with the `code` items of its contracts, starting at the
+/// last account of `resume` if set. The EIP-7748 ordering is sorted on disk if `max_memory` (in MiB) is
+/// set, which can't seek so it starts from the first account.
+fn source_iter<'scope, 'env>(
+    scope: &'scope Scope<'scope, 'env>,
+    source: &'env impl StateSource,
+    order: Order,
+    max_memory: Option<usize>,
+    code: CodeItems,
+    resume: Option<&Checkpoint>,
+) -> Result<Box<dyn PreimageIterator + 'env>> {
+    let last_account = resume.map(|checkpoint| checkpoint.last_account);
+    if order == Order::Plain {
+        let it = PlainIterator::range(source, last_account.unwrap_or_default(), None)?;
+        return Ok(Box::new(it.with_code(code)));
+    }
+
+    let mut pb = AddressProgressBar::new(false);
+    let progress = Some(|addr| pb.progress(addr));
+    Ok(match max_memory {
+        Some(max_memory) => Box::new(Eip7748ExternalIterator::new(
+            source,
+            max_memory << 20,
+            code,
+            progress,
+        )?),
+        None => {
+            let mut addresses = Eip7748Iterator::sorted_addresses(source, progress)?;
+            if let Some(last_account) = last_account {
+                let last_key = keccak256(last_account);
+                let done = addresses.partition_point(|address| keccak256(address) < last_key);
+                addresses.drain(..done);
+            }
+            Box::new(
+                Eip7748Iterator::with_sorted_addresses(scope, source, addresses).with_code(code),
+            )
+        }
+    })
+}
+
+/// Parses a size in bytes, with an optional `KiB`, `MiB`, `GiB` or `TiB` unit.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| format!("invalid size {}", s))?;
+    let shift = match unit.trim() {
+        "" | "B" => 0,
+        "KiB" => 10,
+        "MiB" => 20,
+        "GiB" => 30,
+        "TiB" => 40,
+        unit => {
+            return Err(format!(
+                "unknown size unit {}, expected KiB, MiB, GiB or TiB",
+                unit
+            ))
+        }
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or(format!("size {} is too large", s))
+}
+
+/// Returns the checkpointer of a run on the preimage file at `path`, and the checkpoint to resume from
+/// if `resume` is set.
+fn checkpoints(
+    path: String,
+    header: Header,
+    format: Option<FileFormat>,
+    source_block_number: u64,
+    resume: bool,
+) -> Result<(Checkpointer, Option<Checkpoint>)> {
+    let checkpointer = Checkpointer::new(path, header, source_block_number, resume)?;
+    let checkpoint = resume.then(|| checkpointer.resume(format)).transpose()?;
+    if let Some(checkpoint) = &checkpoint {
+        eprintln!(
+            "Resuming after account {} at offset {}",
+            checkpoint.last_account, checkpoint.position.offset
+        );
+    }
+    Ok((checkpointer, checkpoint))
+}
+
+/// Prints the numbered steps of a command, e.g: `[1/2] Generating preimage file...`.
+struct Steps {
+    current: usize,
+    total: usize,
+}
+
+impl Steps {
+    fn new(total: usize) -> Self {
+        Self { current: 0, total }
+    }
+
+    fn next(&mut self, step: &str) {
+        self.current += 1;
+        eprintln!("[{}/{}] {}...", self.current, self.total, step);
+    }
+}
+
+/// Returns the encoding of a new file, choosing a dictionary of `slot_dictionary` prefixes from `source`
+/// if set.
+fn encoding(
+    source: &impl StateSource,
+    format: FileFormat,
+    slot_dictionary: Option<u8>,
+    front_coding: bool,
+    steps: &mut Steps,
+) -> Result<Encoding> {
+    if front_coding {
+        if format != FileFormat::Delimited {
+            bail!("--front-coding requires the delimited format");
+        }
+        return Ok(Encoding::FrontCoding);
+    }
+    let Some(size) = slot_dictionary else {
+        return Ok(Encoding::None);
+    };
+    if format != FileFormat::Delimited {
+        bail!("--slot-dictionary requires the delimited format");
+    }
+    steps.next("Choosing storage slot prefix dictionary");
+    Ok(Encoding::SlotDictionary(cmds::slot_dictionary(
+        source,
+        size as usize,
+    )?))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generate_cmd(
+    source: &impl StateSource,
+    path: &str,
+    format: FileFormat,
+    target: TargetState,
+    max_memory: Option<usize>,
+    resume: bool,
+    slot_dictionary: Option<u8>,
+    front_coding: bool,
+    compressor: Option<Compressor>,
+    chunk_size: Option<u64>,
+    code: CodeArgs,
+    order: OrderArgs,
+) -> Result<()> {
+    let order = order.order()?;
+    let header = target.header(order);
+    if chunk_size.is_some() && (format != FileFormat::Delimited || path == STDIO_PATH) {
+        bail!("--chunk-size requires the delimited format and an output file");
+    }
+    if code.include_code && format != FileFormat::Delimited {
+        bail!("--include-code requires the delimited format");
+    }
+    // Checkpoints are only recorded for single uncompressed files, which can be truncated at any offset.
+    let resumable = compressor.is_none() && chunk_size.is_none() && path != STDIO_PATH;
+    if resume && !resumable {
+        bail!("--resume requires an uncompressed output file");
+    }
+    let (checkpointer, checkpoint) = match resumable {
+        true => {
+            let (checkpointer, checkpoint) = checkpoints(
+                format!("{}.checkpoint", path),
+                header.clone(),
+                Some(format),
+                target.source_block_number,
+                resume,
+            )?;
+            (Some(checkpointer), checkpoint)
+        }
+        false => (None, None),
+    };
+
+    // A resumed file keeps its encoding.
+    let slot_dictionary = slot_dictionary.filter(|_| checkpoint.is_none());
+    let front_coding = front_coding && checkpoint.is_none();
+    let mut steps =
+        Steps::new(1 + (order == Order::Eip7748) as usize + slot_dictionary.is_some() as usize);
+    let encoding = encoding(source, format, slot_dictionary, front_coding, &mut steps)?;
+    let code = code.items(&target, checkpoint.is_some())?;
+    // The EIP-7748 iterator prefetches the storage slots from a thread of this scope.
+    let summary = thread::scope(|scope| -> Result<_> {
+        let it = if order == Order::Plain {
+            steps.next("Generating preimage file");
+            source_iter(scope, source, order, max_memory, code, checkpoint.as_ref())?
+        } else {
+            steps.next("Ordering account addresses by hash");
+            let it = source_iter(scope, source, order, max_memory, code, checkpoint.as_ref())?;
+            steps.next("Generating preimage file");
+            it
+        };
+        let it = target.iter(it, order, checkpoint.as_ref());
+        let pb = AddressProgressBar::new(order == Order::Eip7748);
+        let summary = match chunk_size {
+            Some(chunk_size) => {
+                let (manifest, summary) =
+                    cmds::generate_chunks(path, &header, encoding, compressor, chunk_size, it, pb)?;
+                eprintln!(
+                    "Wrote {} chunks, listed in {}",
+                    manifest.chunks.len(),
+                    chunks::manifest_path(path)
+                );
+                summary
+            }
+            None => cmds::generate(
+                path,
+                format,
+                &header,
+                encoding,
+                compressor,
+                it,
+                pb,
+                checkpointer,
+                checkpoint.as_ref(),
+            )?,
+        };
+        Ok(summary)
+    })?;
+    summary.print();
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generate_sharded_cmd(
+    source: &impl StateSource,
+    path: &str,
+    format: FileFormat,
+    target: TargetState,
+    slot_dictionary: Option<u8>,
+    front_coding: bool,
+    order: OrderArgs,
+    count: usize,
+    args: ShardArgs,
+) -> Result<()> {
+    if count == 0 {
+        bail!("--shards must be at least 1");
+    }
+    if path == STDIO_PATH {
+        bail!("--shards requires an output file");
+    }
+    let shards: Vec<_> = match args.shard {
+        Some(index) if index >= count => {
+            bail!("Shard {} doesn't exist, there are {} shards", index, count)
+        }
+        Some(index) => vec![Shard { index, count }],
+        None => Shard::all(count).collect(),
+    };
+
+    let order = order.order()?;
+    let mut steps =
+        Steps::new(2 + (order == Order::Eip7748) as usize + slot_dictionary.is_some() as usize);
+    let encoding = encoding(source, format, slot_dictionary, front_coding, &mut steps)?;
+    let sorted_addresses;
+    let accounts = match order {
+        Order::Plain => ShardedAccounts::Plain,
+        Order::Eip7748 => {
+            steps.next("Ordering account addresses by hash");
+            let mut pb = AddressProgressBar::new(false);
+            sorted_addresses =
+                Eip7748Iterator::sorted_addresses(source, Some(|addr| pb.progress(addr)))?;
+            ShardedAccounts::Eip7748(&sorted_addresses)
+        }
+    };
+    let header = target.header(order);
+    steps.next(&format!("Generating {} shard files", shards.len()));
+    shards::generate(
+        source,
+        path,
+        format,
+        &header,
+        &encoding,
+        accounts,
+        target.rewind.as_ref(),
+        &shards,
+    )?;
+
+    let missing = shards::missing(path, count);
+    if !missing.is_empty() {
+        eprintln!(
+            "[{}/{}] Shards {:?} are missing, generate them with --shard to concatenate the file",
+            steps.total, steps.total, missing
+        );
+        return Ok(());
+    }
+    steps.next("Concatenating shard files");
+    if let Some(summary) =
+        shards::concatenate(path, format, &header, &encoding, count, args.keep_shards)?
+    {
+        summary.print();
     }
     Ok(())
 }
 
-fn verify_cmd(tx: &Tx<RO>, path: &str, order: OrderArgs) -> Result<()> {
-    if order.plain {
-        println!("[1/2] Verifying provided preimage file...");
+#[allow(clippy::too_many_arguments)]
+fn verify_cmd(
+    source: &impl StateSource,
+    path: &str,
+    full_scan: bool,
+    max_errors: Option<usize>,
+    target: TargetState,
+    max_memory: Option<usize>,
+    resume: bool,
+    chunk: bool,
+    code: CodeArgs,
+    order: OrderArgs,
+) -> Result<()> {
+    let order = order.order()?;
+    // Checkpoints are positions in a single file.
+    let resumable = !chunks::is_manifest(path);
+    if (resume || chunk) && !resumable {
+        bail!("--resume and --chunk require a single preimage file, not a manifest");
+    }
+    let (checkpointer, checkpoint) = match resumable {
+        true => {
+            let (checkpointer, checkpoint) = checkpoints(
+                format!("{}.verify-checkpoint", path),
+                target.header(order),
+                None,
+                target.source_block_number,
+                resume,
+            )?;
+            (Some(checkpointer), checkpoint)
+        }
+        false => (None, None),
+    };
+
+    let mut steps = Steps::new(if order == Order::Plain { 2 } else { 3 });
+    let code = code.items(&target, checkpoint.is_some())?;
+    // The EIP-7748 iterator prefetches the storage slots from a thread of this scope.
+    thread::scope(|scope| -> Result<()> {
+        if order == Order::Eip7748 {
+            steps.next("Ordering account addresses by hash");
+        }
+        let it = source_iter(scope, source, order, max_memory, code, checkpoint.as_ref())?;
+        steps.next("Verifying provided preimage file");
         cmds::verify(
             path,
-            PlainIterator::new(tx)?,
-            AddressProgressBar::new(false),
-        )?;
-        println!("[2/2] The preimage file is valid!");
-    } else if order.eip7748 {
-        println!("[1/3] Ordering account addresses by hash...");
-        let mut pb = AddressProgressBar::new(false);
-        let it = Eip7748Iterator::new(tx, Some(|addr| pb.progress(addr)))?;
-        println!("[2/3] Verifying provided preimage file...");
-        cmds::verify(path, it, AddressProgressBar::new(true))?;
-        println!("[3/3] The preimage file is valid!");
-    } else {
-        return Err(anyhow!("No ordering specified"));
-    }
+            order,
+            target.chain_id,
+            target.iter(it, order, checkpoint.as_ref()),
+            full_scan,
+            max_errors,
+            AddressProgressBar::new(order == Order::Eip7748),
+            checkpointer,
+            checkpoint.as_ref(),
+            chunk,
+        )
+    })?;
+    eprintln!(
+        "[{}/{}] The preimage file is valid!",
+        steps.total, steps.total
+    );
+    Ok(())
+}
+
+/// Verifies an EIP-7748 preimage file against the hashed state tables of the database tip.
+fn verify_hashed_cmd(
+    source: &RethStateSource,
+    path: &str,
+    full_scan: bool,
+    max_errors: Option<usize>,
+) -> Result<()> {
+    let mut steps = Steps::new(2);
+    steps.next("Verifying provided preimage file against the hashed state");
+    cmds::verify_hashed(
+        path,
+        source.chain_id(),
+        hashed::hashed_state(source.tx())?,
+        full_scan,
+        max_errors,
+        AddressProgressBar::new(true),
+    )?;
+    eprintln!(
+        "[{}/{}] The preimage file is valid!",
+        steps.total, steps.total
+    );
     Ok(())
 }
@@ -0,0 +1,232 @@
+//! Structured report of the problems found while verifying a preimage file.
+
+use alloy_primitives::{Address, B256};
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    MissingAccount(Address),
+    ExtraAccount(Address),
+    DuplicateAccount(Address),
+    UnorderedAccount(Address),
+    MissingStorageSlot(Address, B256),
+    ExtraStorageSlot(Address, B256),
+    DuplicateStorageSlot(Address, B256),
+    UnorderedStorageSlot(Address, B256),
+    /// Hashed account of the database without a preimage in the file.
+    MissingAccountPreimage(B256),
+    /// Hashed storage slot of the database without a preimage in the file.
+    MissingStorageSlotPreimage(Address, B256),
+    MissingCode(Address, B256),
+    ExtraCode(Address, B256),
+    TrailingBytes,
+    Malformed(String),
+    ManifestMismatch(String),
+}
+
+impl Problem {
+    fn kind(&self) -> &'static str {
+        match self {
+            Problem::MissingAccount(_) => "missing accounts",
+            Problem::ExtraAccount(_) => "extra accounts",
+            Problem::DuplicateAccount(_) => "duplicate accounts",
+            Problem::UnorderedAccount(_) => "unordered accounts",
+            Problem::MissingStorageSlot(..) => "missing storage slots",
+            Problem::ExtraStorageSlot(..) => "extra storage slots",
+            Problem::DuplicateStorageSlot(..) => "duplicate storage slots",
+            Problem::UnorderedStorageSlot(..) => "unordered storage slots",
+            Problem::MissingAccountPreimage(_) => "missing account preimages",
+            Problem::MissingStorageSlotPreimage(..) => "missing storage slot preimages",
+            Problem::MissingCode(..) => "missing code hashes",
+            Problem::ExtraCode(..) => "extra code hashes",
+            Problem::TrailingBytes => "trailing bytes",
+            Problem::Malformed(_) => "malformed records",
+            Problem::ManifestMismatch(_) => "manifest mismatches",
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MissingAccount(address) => write!(f, "missing account {}", address),
+            Problem::ExtraAccount(address) => write!(f, "extra account {}", address),
+            Problem::DuplicateAccount(address) => write!(f, "duplicate account {}", address),
+            Problem::UnorderedAccount(address) => write!(f, "account {} out of order", address),
+            Problem::MissingStorageSlot(address, ss) => {
+                write!(f, "missing storage slot {} (address: {})", ss, address)
+            }
+            Problem::ExtraStorageSlot(address, ss) => {
+                write!(f, "extra storage slot {} (address: {})", ss, address)
+            }
+            Problem::DuplicateStorageSlot(address, ss) => {
+                write!(f, "duplicate storage slot {} (address: {})", ss, address)
+            }
+            Problem::UnorderedStorageSlot(address, ss) => {
+                write!(f, "storage slot {} out of order (address: {})", ss, address)
+            }
+            Problem::MissingAccountPreimage(hash) => {
+                write!(f, "missing preimage of hashed account {}", hash)
+            }
+            Problem::MissingStorageSlotPreimage(address, hash) => write!(
+                f,
+                "missing preimage of hashed storage slot {} (address: {})",
+                hash, address
+            ),
+            Problem::MissingCode(address, code_hash) => {
+                write!(f, "missing code hash {} (address: {})", code_hash, address)
+            }
+            Problem::ExtraCode(address, code_hash) => {
+                write!(f, "extra code hash {} (address: {})", code_hash, address)
+            }
+            Problem::TrailingBytes => write!(f, "trailing bytes after the end of the file"),
+            Problem::Malformed(err) => write!(f, "malformed record: {}", err),
+            Problem::ManifestMismatch(err) => write!(f, "manifest mismatch: {}", err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    /// Chunk file of a chunked preimage file the problem is in.
+    pub file: Option<String>,
+    /// Offset in the preimage file, if the problem is located in it.
+    pub offset: Option<u64>,
+    pub problem: Problem,
+}
+
+pub struct Report {
+    issues: Vec<Issue>,
+    max_issues: Option<usize>,
+    /// Chunk file being scanned, for the issues added.
+    pub file: Option<String>,
+
+    pub accounts: u64,
+    pub storage_slots: u64,
+    pub codes: u64,
+}
+
+impl Report {
+    pub fn new(max_issues: Option<usize>) -> Self {
+        Self {
+            issues: Vec::new(),
+            max_issues,
+            file: None,
+            accounts: 0,
+            storage_slots: 0,
+            codes: 0,
+        }
+    }
+
+    pub fn add(&mut self, offset: u64, problem: Problem) {
+        self.push(Issue {
+            file: self.file.clone(),
+            offset: Some(offset),
+            problem,
+        });
+    }
+
+    /// Adds a problem that isn't located in the preimage file, e.g: a preimage missing from it.
+    pub fn add_unlocated(&mut self, problem: Problem) {
+        self.push(Issue {
+            file: self.file.clone(),
+            offset: None,
+            problem,
+        });
+    }
+
+    fn push(&mut self, issue: Issue) {
+        if !self.is_full() {
+            self.issues.push(issue);
+        }
+    }
+
+    /// Returns `true` if the maximum number of issues was reached and the scan should stop.
+    pub fn is_full(&self) -> bool {
+        self.max_issues.is_some_and(|max| self.issues.len() >= max)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+
+    pub fn print(&self) {
+        for issue in &self.issues {
+            match (&issue.file, issue.offset) {
+                (Some(file), Some(offset)) => {
+                    println!("[{} offset {}] {}", file, offset, issue.problem)
+                }
+                (Some(file), None) => println!("[{}] {}", file, issue.problem),
+                (None, Some(offset)) => println!("[offset {}] {}", offset, issue.problem),
+                (None, None) => println!("{}", issue.problem),
+            }
+        }
+
+        let mut counts = BTreeMap::<&str, usize>::new();
+        for issue in &self.issues {
+            *counts.entry(issue.problem.kind()).or_default() += 1;
+        }
+        println!(
+            "Scanned {} accounts and {} storage slots{}: {} problems found{}",
+            self.accounts,
+            self.storage_slots,
+            match self.codes {
+                0 => String::new(),
+                codes => format!(" with {} code hashes", codes),
+            },
+            self.issues.len(),
+            if self.is_full() {
+                " (stopped at the maximum number of errors)"
+            } else {
+                ""
+            }
+        );
+        for (kind, count) in counts {
+            println!("  {}: {}", kind, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_at_the_maximum_number_of_issues() {
+        let mut report = Report::new(Some(2));
+        assert!(report.is_ok() && !report.is_full());
+        report.add(10, Problem::MissingAccount(Address::with_last_byte(1)));
+        report.file = Some("preimages.bin.chunk-00001".to_string());
+        report.add_unlocated(Problem::MissingAccountPreimage(B256::ZERO));
+        assert!(!report.is_ok() && report.is_full());
+        report.add(20, Problem::TrailingBytes);
+
+        let issues: Vec<_> = report
+            .issues
+            .iter()
+            .map(|issue| (issue.file.as_deref(), issue.offset, issue.problem.kind()))
+            .collect();
+        assert_eq!(
+            issues,
+            vec![
+                (None, Some(10), "missing accounts"),
+                (
+                    Some("preimages.bin.chunk-00001"),
+                    None,
+                    "missing account preimages"
+                ),
+            ]
+        );
+
+        let mut report = Report::new(None);
+        for i in 0..100 {
+            report.add(i, Problem::TrailingBytes);
+        }
+        assert_eq!(report.issues.len(), 100);
+        assert!(!report.is_full());
+    }
+}
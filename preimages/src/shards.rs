@@ -0,0 +1,278 @@
+//! Sharded preimage generation.
+//!
+//! The key space of the ordering, i.e: addresses in the plain ordering and address hashes in the EIP-7748
+//! one, is split in ranges of the same size by the first 8 bytes of the key. Each shard is generated to
+//! its own file by its own thread, reading from its own view of the state (e.g: its own database
+//! transaction). The shard files are then concatenated in order: raw and geth RLP shard files are copied as
+//! is, while delimited ones are decoded and their preimages re-encoded into a single file, with a single
+//! header and footer. Since the ranges are contiguous, the result is byte-identical to generating the file
+//! at once.
+//!
+//! Delimited shard files are complete preimage files with the header of the whole file, so they are
+//! validated (header, footer totals and checksum) while decoding them. A shard that failed can be
+//! regenerated alone and then concatenated with the other shard files.
+
+use alloy_primitives::{keccak256, Address};
+use anyhow::{bail, Context, Result};
+use eth_stateless::{progress::AddressProgressBar, StateSource};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    thread,
+};
+
+use crate::cmds;
+use crate::delta::StateDelta;
+use crate::format::{Encoding, FileFormat, Header, Order, PreimageWriter, Summary};
+use crate::iterators::{
+    delta::DeltaIterator, eip7748::Eip7748Iterator, file::PreimageFileReader, plain::PlainIterator,
+    AccountStorageItem, PreimageIterator,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    pub fn all(count: usize) -> impl Iterator<Item = Shard> {
+        (0..count).map(move |index| Shard { index, count })
+    }
+
+    /// Returns the path of the shard file of the preimage file at `path`.
+    pub fn path(&self, path: &str) -> String {
+        format!("{}.shard-{}-of-{}", path, self.index, self.count)
+    }
+
+    /// Returns whether the account with `address` sorts within the shard in `order`.
+    pub fn contains(&self, order: Order, address: Address) -> bool {
+        let prefix = key_prefix(order, address);
+        prefix >= self.start() && self.end().is_none_or(|end| prefix < end)
+    }
+
+    fn start(&self) -> u64 {
+        boundary(self.index, self.count)
+    }
+
+    fn end(&self) -> Option<u64> {
+        (self.index + 1 < self.count).then(|| boundary(self.index + 1, self.count))
+    }
+}
+
+fn boundary(index: usize, count: usize) -> u64 {
+    (((index as u128) << 64) / count as u128) as u64
+}
+
+fn key_prefix(order: Order, address: Address) -> u64 {
+    let prefix = match order {
+        Order::Plain => address[..8].try_into(),
+        Order::Eip7748 => keccak256(address)[..8].try_into(),
+    };
+    u64::from_be_bytes(prefix.expect("8 bytes prefix"))
+}
+
+fn prefix_address(prefix: u64) -> Address {
+    let mut address = [0; 20];
+    address[..8].copy_from_slice(&prefix.to_be_bytes());
+    Address::from(address)
+}
+
+/// Accounts the shards are generated from.
+#[derive(Clone, Copy)]
+pub enum ShardedAccounts<'a> {
+    /// Accounts read from the state by address range.
+    Plain,
+    /// Addresses sorted by hash, see [`Eip7748Iterator::sorted_addresses`].
+    Eip7748(&'a [Address]),
+}
+
+/// Generates the files of `shards` in parallel, one thread per shard, with the same `encoding`. If
+/// `rewind` is set, it's applied to the accounts of each shard.
+#[allow(clippy::too_many_arguments)]
+pub fn generate(
+    source: &impl StateSource,
+    path: &str,
+    format: FileFormat,
+    header: &Header,
+    encoding: &Encoding,
+    accounts: ShardedAccounts<'_>,
+    rewind: Option<&StateDelta>,
+    shards: &[Shard],
+) -> Result<()> {
+    let results: Vec<_> = thread::scope(|s| {
+        let handles: Vec<_> = shards
+            .iter()
+            .map(|shard| {
+                s.spawn(move || {
+                    generate_shard(
+                        s, source, path, format, header, encoding, accounts, rewind, *shard,
+                    )
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("shard thread panicked"))
+            .collect()
+    });
+
+    let mut failed = Vec::new();
+    for (shard, result) in shards.iter().zip(results) {
+        match result {
+            Ok(()) => eprintln!("Shard {} of {} generated", shard.index, shard.count),
+            Err(e) => {
+                eprintln!("Shard {} of {} failed: {:#}", shard.index, shard.count, e);
+                failed.push(shard.index);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        bail!(
+            "Shards {:?} failed, regenerate them with --shard after fixing the problem",
+            failed
+        );
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generate_shard<'scope, 'env>(
+    scope: &'scope thread::Scope<'scope, 'env>,
+    source: &'env impl StateSource,
+    path: &str,
+    format: FileFormat,
+    header: &Header,
+    encoding: &Encoding,
+    accounts: ShardedAccounts<'env>,
+    rewind: Option<&StateDelta>,
+    shard: Shard,
+) -> Result<()> {
+    let view = source.view()?;
+    let it: Box<dyn PreimageIterator + '_> = match accounts {
+        ShardedAccounts::Plain => Box::new(PlainIterator::range(
+            &*view,
+            prefix_address(shard.start()),
+            shard.end().map(prefix_address),
+        )?),
+        ShardedAccounts::Eip7748(addresses) => {
+            let start =
+                addresses.partition_point(|a| key_prefix(Order::Eip7748, *a) < shard.start());
+            let end = match shard.end() {
+                Some(end) => addresses.partition_point(|a| key_prefix(Order::Eip7748, *a) < end),
+                None => addresses.len(),
+            };
+            Box::new(Eip7748Iterator::with_sorted_addresses(
+                scope,
+                source,
+                &addresses[start..end],
+            ))
+        }
+    };
+    let it: Box<dyn PreimageIterator + '_> = match rewind {
+        Some(delta) => {
+            // Only the accounts of the shard are cloned, so the shards hold a single copy of the delta.
+            let delta = StateDelta {
+                accounts: delta
+                    .accounts
+                    .iter()
+                    .filter(|(address, _)| shard.contains(header.order, **address))
+                    .map(|(address, account)| (*address, account.clone()))
+                    .collect(),
+                ..*delta
+            };
+            Box::new(DeltaIterator::new(it, delta, header.order))
+        }
+        None => it,
+    };
+    // Written to a temporary path first, so a failed shard doesn't leave a partial shard file behind.
+    let shard_path = shard.path(path);
+    let tmp_path = format!("{}.tmp", shard_path);
+    cmds::generate(
+        &tmp_path,
+        format,
+        header,
+        encoding.clone(),
+        None,
+        it,
+        AddressProgressBar::hidden(),
+        None,
+        None,
+    )?;
+    fs::rename(&tmp_path, &shard_path)?;
+    Ok(())
+}
+
+/// Returns the shards out of `count` whose file is missing.
+pub fn missing(path: &str, count: usize) -> Vec<usize> {
+    Shard::all(count)
+        .filter(|shard| !Path::new(&shard.path(path)).exists())
+        .map(|shard| shard.index)
+        .collect()
+}
+
+/// Concatenates the `count` shard files of `path` into it, removing them unless `keep` is set. Returns
+/// the summary of the file if it's delimited, the other formats being copied as is.
+pub fn concatenate(
+    path: &str,
+    format: FileFormat,
+    header: &Header,
+    encoding: &Encoding,
+    count: usize,
+    keep: bool,
+) -> Result<Option<Summary>> {
+    let missing = missing(path, count);
+    if !missing.is_empty() {
+        bail!("Missing shards {:?}, generate them with --shard", missing);
+    }
+
+    let w = BufWriter::new(File::create(path)?);
+    let summary = match format {
+        FileFormat::Raw | FileFormat::GethRlp => {
+            let mut w = w;
+            for shard in Shard::all(count) {
+                let shard_path = shard.path(path);
+                let mut r = File::open(&shard_path)?;
+                io::copy(&mut r, &mut w).with_context(|| format!("copying {}", shard_path))?;
+            }
+            w.flush()?;
+            None
+        }
+        FileFormat::Delimited => {
+            let mut w = PreimageWriter::with_encoding(w, format, header, encoding.clone())?;
+            for shard in Shard::all(count) {
+                let shard_path = shard.path(path);
+                let reader = PreimageFileReader::open(&shard_path)
+                    .with_context(|| format!("opening {}", shard_path))?;
+                if reader.header() != header || reader.encoding() != encoding {
+                    bail!(
+                        "Shard file {} was generated for another ordering, state or encoding",
+                        shard_path
+                    );
+                }
+                for item in reader {
+                    match item.with_context(|| format!("reading {}", shard_path))? {
+                        AccountStorageItem::Account(address) => {
+                            if !shard.contains(header.order, address) {
+                                bail!("Account {} is out of the range of {}", address, shard_path);
+                            }
+                            w.write_account(address)?;
+                        }
+                        AccountStorageItem::StorageSlot(_, slot) => w.write_storage_slot(slot)?,
+                        AccountStorageItem::Code(_, code_hash) => w.write_code(code_hash)?,
+                    }
+                }
+            }
+            let (_, summary) = w.finish()?;
+            Some(summary)
+        }
+    };
+
+    if !keep {
+        for shard in Shard::all(count) {
+            fs::remove_file(shard.path(path))?;
+        }
+    }
+    Ok(summary)
+}